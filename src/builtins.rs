@@ -0,0 +1,1230 @@
+use crate::interpreter::VirtualMachine;
+use crate::types::compiler::{HeapObject, MapKey, ObjectMap, Value};
+use crate::types::constants::MAX_COLLECTION_LENGTH;
+use std::cmp::Ordering;
+use std::fs;
+use std::io::Write;
+
+pub type NativeFunction = fn(&mut VirtualMachine, Vec<Value>) -> Result<Value, String>;
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    pub function: NativeFunction,
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "curry",
+        min_args: 2,
+        max_args: 2,
+        function: curry,
+    },
+    Builtin {
+        name: "memoize",
+        min_args: 1,
+        max_args: 1,
+        function: memoize,
+    },
+    Builtin {
+        name: "sort",
+        min_args: 1,
+        max_args: 2,
+        function: sort,
+    },
+    Builtin {
+        name: "zip",
+        min_args: 2,
+        max_args: 2,
+        function: zip,
+    },
+    Builtin {
+        name: "enumerate",
+        min_args: 1,
+        max_args: 1,
+        function: enumerate,
+    },
+    Builtin {
+        name: "range",
+        min_args: 2,
+        max_args: 2,
+        function: range,
+    },
+    Builtin {
+        name: "flatten",
+        min_args: 1,
+        max_args: 1,
+        function: flatten,
+    },
+    Builtin {
+        name: "flat_map",
+        min_args: 2,
+        max_args: 2,
+        function: flat_map,
+    },
+    Builtin {
+        name: "find",
+        min_args: 2,
+        max_args: 2,
+        function: find,
+    },
+    Builtin {
+        name: "any",
+        min_args: 2,
+        max_args: 2,
+        function: any,
+    },
+    Builtin {
+        name: "all",
+        min_args: 2,
+        max_args: 2,
+        function: all,
+    },
+    Builtin {
+        name: "contains",
+        min_args: 2,
+        max_args: 2,
+        function: contains,
+    },
+    Builtin {
+        name: "index_of",
+        min_args: 2,
+        max_args: 2,
+        function: index_of,
+    },
+    Builtin {
+        name: "unique",
+        min_args: 1,
+        max_args: 1,
+        function: unique,
+    },
+    Builtin {
+        name: "group_by",
+        min_args: 2,
+        max_args: 2,
+        function: group_by,
+    },
+    Builtin {
+        name: "fill",
+        min_args: 2,
+        max_args: 2,
+        function: fill,
+    },
+    Builtin {
+        name: "repeat",
+        min_args: 2,
+        max_args: 2,
+        function: repeat,
+    },
+    Builtin {
+        name: "slice",
+        min_args: 2,
+        max_args: 3,
+        function: slice,
+    },
+    Builtin {
+        name: "chunk",
+        min_args: 2,
+        max_args: 2,
+        function: chunk,
+    },
+    Builtin {
+        name: "windows",
+        min_args: 2,
+        max_args: 2,
+        function: windows,
+    },
+    Builtin {
+        name: "reduce",
+        min_args: 3,
+        max_args: 3,
+        function: reduce,
+    },
+    Builtin {
+        name: "reduce_right",
+        min_args: 3,
+        max_args: 3,
+        function: reduce_right,
+    },
+    Builtin {
+        name: "scan",
+        min_args: 3,
+        max_args: 3,
+        function: scan,
+    },
+    Builtin {
+        name: "to_number",
+        min_args: 1,
+        max_args: 2,
+        function: to_number,
+    },
+    Builtin {
+        name: "format",
+        min_args: 1,
+        max_args: usize::MAX,
+        function: format,
+    },
+    Builtin {
+        name: "join",
+        min_args: 2,
+        max_args: 2,
+        function: join,
+    },
+    Builtin {
+        name: "chars",
+        min_args: 1,
+        max_args: 1,
+        function: chars,
+    },
+    Builtin {
+        name: "print",
+        min_args: 0,
+        max_args: usize::MAX,
+        function: print,
+    },
+    Builtin {
+        name: "len",
+        min_args: 1,
+        max_args: 1,
+        function: len,
+    },
+    Builtin {
+        name: "typeof",
+        min_args: 1,
+        max_args: 1,
+        function: type_of,
+    },
+    Builtin {
+        name: "assert",
+        min_args: 1,
+        max_args: 2,
+        function: assert,
+    },
+    Builtin {
+        name: "assert_eq",
+        min_args: 2,
+        max_args: 2,
+        function: assert_eq,
+    },
+    Builtin {
+        name: "Map.merge",
+        min_args: 2,
+        max_args: 2,
+        function: map_merge,
+    },
+    Builtin {
+        name: "Map.remove",
+        min_args: 2,
+        max_args: 2,
+        function: map_remove,
+    },
+    Builtin {
+        name: "Map.has",
+        min_args: 2,
+        max_args: 2,
+        function: map_has,
+    },
+    Builtin {
+        name: "Map.entries",
+        min_args: 1,
+        max_args: 1,
+        function: map_entries,
+    },
+    Builtin {
+        name: "IO.lines",
+        min_args: 1,
+        max_args: 1,
+        function: io_lines,
+    },
+    Builtin {
+        name: "IO.append",
+        min_args: 2,
+        max_args: 2,
+        function: io_append,
+    },
+    Builtin {
+        name: "IO.readline",
+        min_args: 0,
+        max_args: 0,
+        function: io_readline,
+    },
+    Builtin {
+        name: "Env.get",
+        min_args: 1,
+        max_args: 1,
+        function: env_get,
+    },
+    Builtin {
+        name: "Env.args",
+        min_args: 0,
+        max_args: 0,
+        function: env_args,
+    },
+    Builtin {
+        name: "Math.clamp",
+        min_args: 3,
+        max_args: 3,
+        function: math_clamp,
+    },
+    Builtin {
+        name: "Math.sign",
+        min_args: 1,
+        max_args: 1,
+        function: math_sign,
+    },
+    Builtin {
+        name: "Math.hypot",
+        min_args: 2,
+        max_args: 2,
+        function: math_hypot,
+    },
+    Builtin {
+        name: "Math.sin",
+        min_args: 1,
+        max_args: 1,
+        function: math_sin,
+    },
+    Builtin {
+        name: "Math.cos",
+        min_args: 1,
+        max_args: 1,
+        function: math_cos,
+    },
+    Builtin {
+        name: "Math.tan",
+        min_args: 1,
+        max_args: 1,
+        function: math_tan,
+    },
+    Builtin {
+        name: "Math.asin",
+        min_args: 1,
+        max_args: 1,
+        function: math_asin,
+    },
+    Builtin {
+        name: "Math.acos",
+        min_args: 1,
+        max_args: 1,
+        function: math_acos,
+    },
+    Builtin {
+        name: "Math.atan",
+        min_args: 1,
+        max_args: 1,
+        function: math_atan,
+    },
+    Builtin {
+        name: "Math.atan2",
+        min_args: 2,
+        max_args: 2,
+        function: math_atan2,
+    },
+    Builtin {
+        name: "Math.log",
+        min_args: 1,
+        max_args: 1,
+        function: math_log,
+    },
+    Builtin {
+        name: "Math.log2",
+        min_args: 1,
+        max_args: 1,
+        function: math_log2,
+    },
+    Builtin {
+        name: "Math.log10",
+        min_args: 1,
+        max_args: 1,
+        function: math_log10,
+    },
+    Builtin {
+        name: "Math.exp",
+        min_args: 1,
+        max_args: 1,
+        function: math_exp,
+    },
+    Builtin {
+        name: "Number.is_nan",
+        min_args: 1,
+        max_args: 1,
+        function: number_is_nan,
+    },
+    Builtin {
+        name: "Number.is_int",
+        min_args: 1,
+        max_args: 1,
+        function: number_is_int,
+    },
+    Builtin {
+        name: "Number.parse",
+        min_args: 1,
+        max_args: 1,
+        function: number_parse,
+    },
+    Builtin {
+        name: "Set.new",
+        min_args: 0,
+        max_args: 1,
+        function: set_new,
+    },
+    Builtin {
+        name: "Set.add",
+        min_args: 2,
+        max_args: 2,
+        function: set_add,
+    },
+    Builtin {
+        name: "Set.contains",
+        min_args: 2,
+        max_args: 2,
+        function: set_contains,
+    },
+    Builtin {
+        name: "Set.union",
+        min_args: 2,
+        max_args: 2,
+        function: set_union,
+    },
+    Builtin {
+        name: "Set.intersection",
+        min_args: 2,
+        max_args: 2,
+        function: set_intersection,
+    },
+];
+
+/// Numbers a module exposes as fields rather than functions, like `Math.pi`.
+pub const CONSTANTS: &[(&str, f64)] = &[
+    ("Math.pi", std::f64::consts::PI),
+    ("Math.e", std::f64::consts::E),
+];
+
+pub fn constant(name: &str) -> Option<f64> {
+    CONSTANTS
+        .iter()
+        .find(|(constant, _)| *constant == name)
+        .map(|(_, value)| *value)
+}
+
+pub fn lookup(name: &str) -> Option<usize> {
+    BUILTINS.iter().position(|builtin| builtin.name == name)
+}
+
+/// Whether `name` is a module such as `Set` whose functions are called as `Set.new()`.
+pub fn is_module(name: &str) -> bool {
+    BUILTINS.iter().any(|builtin| {
+        builtin
+            .name
+            .split_once('.')
+            .is_some_and(|(module, _)| module == name)
+    })
+}
+
+/// Whether `name` is a prelude builtin, callable without an import.
+pub fn is_prelude(name: &str) -> bool {
+    !name.contains('.') && lookup(name).is_some()
+}
+
+fn arity(function: &Value) -> Option<usize> {
+    match function {
+        Value::Function { params, .. } => Some(params.len()),
+        Value::Partial { function, args } => arity(function).map(|n| n - args.len()),
+        _ => None,
+    }
+}
+
+fn curry(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let [function, count] = <[Value; 2]>::try_from(args).unwrap();
+    let Value::Number(count) = count else {
+        return Err(format!(
+            "curry expects a number of arguments, got {}",
+            vm.type_name(&count)
+        ));
+    };
+    let Some(expected) = arity(&function) else {
+        return Err(format!(
+            "curry expects a function, got {}",
+            vm.type_name(&function)
+        ));
+    };
+    if count != expected as f64 {
+        return Err(format!(
+            "curry: function takes {} arguments, not {}",
+            expected, count
+        ));
+    }
+
+    // Partial application already collects arguments until the arity is met,
+    // so a curried function is a partial application with nothing bound yet
+    Ok(Value::Partial {
+        function: Box::new(function),
+        args: Vec::new(),
+    })
+}
+
+fn memoize(vm: &mut VirtualMachine, mut args: Vec<Value>) -> Result<Value, String> {
+    vm.memoize(args.remove(0))
+}
+
+fn sort(vm: &mut VirtualMachine, mut args: Vec<Value>) -> Result<Value, String> {
+    let comparator = if args.len() == 2 { args.pop() } else { None };
+    let mut elements = vm.array_elements(&args[0], "sort")?;
+
+    match comparator {
+        None => {
+            let all_numbers = elements.iter().all(|e| matches!(e, HeapObject::Number(_)));
+            let all_strings = elements.iter().all(|e| matches!(e, HeapObject::String(_)));
+            if !all_numbers && !all_strings {
+                return Err(
+                    "sort without a comparator requires only numbers or only strings".to_string(),
+                );
+            }
+            // `sort_by` is stable, so equal elements keep their original order
+            elements.sort_by(|a, b| match (a, b) {
+                (HeapObject::Number(a), HeapObject::Number(b)) => a.total_cmp(b),
+                (HeapObject::String(a), HeapObject::String(b)) => a.cmp(b),
+                _ => Ordering::Equal,
+            });
+        }
+        Some(comparator) => {
+            // A merge sort of our own, since `sort_by` may panic on a comparator
+            // that isn't a total order and can't stop at one that fails
+            elements = merge_sort(elements, &mut |a, b| {
+                let a = vm.heap_object_to_value(a.clone());
+                let b = vm.heap_object_to_value(b.clone());
+                match vm.call_value(comparator.clone(), vec![a, b])? {
+                    Value::Number(n) => Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+                    other => Err(format!(
+                        "sort comparator must return a number, got {}",
+                        vm.type_name(&other)
+                    )),
+                }
+            })?;
+        }
+    }
+
+    Ok(vm.alloc(HeapObject::Array(elements)))
+}
+
+/// Stable merge sort that gives up on the first error from `compare`. Any
+/// comparator, consistent or not, yields a permutation of `elements`.
+fn merge_sort(
+    mut elements: Vec<HeapObject>,
+    compare: &mut impl FnMut(&HeapObject, &HeapObject) -> Result<Ordering, String>,
+) -> Result<Vec<HeapObject>, String> {
+    if elements.len() <= 1 {
+        return Ok(elements);
+    }
+    let right = elements.split_off(elements.len() / 2);
+    let left = merge_sort(elements, compare)?;
+    let right = merge_sort(right, compare)?;
+
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let mut left = left.into_iter().peekable();
+    let mut right = right.into_iter().peekable();
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        // Ties take the left element, which keeps equal elements in order
+        let next = if compare(a, b)? == Ordering::Greater {
+            right.next()
+        } else {
+            left.next()
+        };
+        merged.extend(next);
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+fn zip(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let left = vm.array_elements(&args[0], "zip")?;
+    let right = vm.array_elements(&args[1], "zip")?;
+    // Pairs stop at the end of the shorter array
+    let pairs = left
+        .into_iter()
+        .zip(right)
+        .map(|(a, b)| HeapObject::Array(vec![a, b]))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(pairs)))
+}
+
+fn enumerate(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "enumerate")?;
+    let pairs = elements
+        .into_iter()
+        .enumerate()
+        .map(|(i, element)| HeapObject::Array(vec![HeapObject::Number(i as f64), element]))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(pairs)))
+}
+
+fn range(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let (Value::Number(start), Value::Number(end)) = (&args[0], &args[1]) else {
+        return Err(format!(
+            "range expects two numbers, got {} and {}",
+            vm.type_name(&args[0]),
+            vm.type_name(&args[1])
+        ));
+    };
+    // Past 2^53 adding 1 no longer gives the next number
+    const EXACT: f64 = 9007199254740992.0;
+    for bound in [start, end] {
+        if !(-EXACT..=EXACT).contains(bound) {
+            return Err(format!(
+                "range bounds must be finite numbers between -2^53 and 2^53, got {}",
+                bound
+            ));
+        }
+    }
+    let length = (end - start).ceil().max(0.0) as usize;
+    if length > MAX_COLLECTION_LENGTH {
+        return Err(format!(
+            "range would have {} elements, more than the limit of {}",
+            length, MAX_COLLECTION_LENGTH
+        ));
+    }
+    let elements = (0..length)
+        .map(|i| HeapObject::Number(start + i as f64))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(elements)))
+}
+
+/// Appends `element` to `flattened`, splicing in its contents when it is an
+/// array. Non-array elements pass through unchanged.
+fn flatten_into(flattened: &mut Vec<HeapObject>, element: HeapObject) {
+    match element {
+        HeapObject::Array(inner) => flattened.extend(inner),
+        other => flattened.push(other),
+    }
+}
+
+fn flatten(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "flatten")?;
+    let mut flattened = Vec::with_capacity(elements.len());
+    for element in elements {
+        flatten_into(&mut flattened, element);
+    }
+    Ok(vm.alloc(HeapObject::Array(flattened)))
+}
+
+fn flat_map(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "flat_map")?;
+    let mut flattened = Vec::with_capacity(elements.len());
+    for element in elements {
+        let element = vm.heap_object_to_value(element);
+        let mapped = vm.call_value(args[1].clone(), vec![element])?;
+        flatten_into(&mut flattened, vm.value_to_heap_object(mapped));
+    }
+    Ok(vm.alloc(HeapObject::Array(flattened)))
+}
+
+fn find(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "find")?;
+    Ok(first_match(vm, elements, &args[1], true)?.unwrap_or(Value::Nil))
+}
+
+fn any(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "any")?;
+    Ok(Value::Boolean(
+        first_match(vm, elements, &args[1], true)?.is_some(),
+    ))
+}
+
+fn all(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "all")?;
+    Ok(Value::Boolean(
+        first_match(vm, elements, &args[1], false)?.is_none(),
+    ))
+}
+
+/// The first element `predicate` gives `wanted` for, the elements after it are
+/// never passed to `predicate`.
+fn first_match(
+    vm: &mut VirtualMachine,
+    elements: Vec<HeapObject>,
+    predicate: &Value,
+    wanted: bool,
+) -> Result<Option<Value>, String> {
+    for element in elements {
+        let element = vm.heap_object_to_value(element);
+        let result = vm.call_value(predicate.clone(), vec![element.clone()])?;
+        if vm.as_bool(&result)? == wanted {
+            return Ok(Some(element));
+        }
+    }
+    Ok(None)
+}
+
+fn contains(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "contains")?;
+    let element = vm.value_to_heap_object(args[1].clone());
+    Ok(Value::Boolean(elements.contains(&element)))
+}
+
+fn index_of(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "index_of")?;
+    let element = vm.value_to_heap_object(args[1].clone());
+    let index = elements.iter().position(|candidate| *candidate == element);
+    Ok(Value::Number(index.map_or(-1.0, |index| index as f64)))
+}
+
+fn unique(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    // Elements may be collections, which can't be keyed, so they are compared one by one
+    let mut unique = Vec::new();
+    for element in vm.array_elements(&args[0], "unique")? {
+        if !unique.contains(&element) {
+            unique.push(element);
+        }
+    }
+    Ok(vm.alloc(HeapObject::Array(unique)))
+}
+
+fn group_by(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut groups: Vec<(MapKey, Vec<HeapObject>)> = Vec::new();
+    for element in vm.array_elements(&args[0], "group_by")? {
+        let value = vm.heap_object_to_value(element.clone());
+        let key = vm.call_value(args[1].clone(), vec![value])?;
+        let key = vm.map_key(&key)?;
+        match groups.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, group)) => group.push(element),
+            None => groups.push((key, vec![element])),
+        }
+    }
+    let map = groups
+        .into_iter()
+        .map(|(key, group)| (key, HeapObject::Array(group)))
+        .collect();
+    Ok(vm.alloc(HeapObject::Object(map)))
+}
+
+fn fill(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let count = repeat_count(vm, &args[0], "fill")?;
+    let element = vm.value_to_heap_object(args[1].clone());
+    let mut filled = repeat_buffer(count, 1, "fill")?;
+    filled.resize(count, element);
+    Ok(vm.alloc(HeapObject::Array(filled)))
+}
+
+fn repeat(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "repeat")?;
+    let count = repeat_count(vm, &args[1], "repeat")?;
+    let mut repeated = repeat_buffer(count, elements.len(), "repeat")?;
+    if !elements.is_empty() {
+        for _ in 0..count {
+            repeated.extend(elements.iter().cloned());
+        }
+    }
+    Ok(vm.alloc(HeapObject::Array(repeated)))
+}
+
+fn repeat_count(vm: &VirtualMachine, value: &Value, function: &str) -> Result<usize, String> {
+    let count = vm.as_number(value)?;
+    if count.fract() != 0.0 || count < 0.0 {
+        return Err(format!(
+            "{} count must be a non-negative integer, got {}",
+            function, count
+        ));
+    }
+    Ok(count as usize)
+}
+
+/// Room for `count` copies of `width` elements, an error instead of a crash when
+/// that is past `MAX_COLLECTION_LENGTH` or more than can be allocated.
+fn repeat_buffer(count: usize, width: usize, function: &str) -> Result<Vec<HeapObject>, String> {
+    let too_long = |length: String| {
+        format!(
+            "{} would create {} elements, more than the limit of {}",
+            function, length, MAX_COLLECTION_LENGTH
+        )
+    };
+    // Nothing to hold, however many copies of it there are
+    if width == 0 {
+        return Ok(Vec::new());
+    }
+    let length = count
+        .checked_mul(width)
+        .ok_or_else(|| too_long(format!("{} * {}", count, width)))?;
+    if length > MAX_COLLECTION_LENGTH {
+        return Err(too_long(length.to_string()));
+    }
+    let mut buffer = Vec::new();
+    buffer
+        .try_reserve_exact(length)
+        .map_err(|_| format!("{} could not allocate {} elements", function, length))?;
+    Ok(buffer)
+}
+
+fn slice(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "slice")?;
+    let start = slice_bound(vm, &args[1], elements.len())?;
+    let end = match args.get(2) {
+        Some(end) => slice_bound(vm, end, elements.len())?,
+        None => elements.len(),
+    };
+    let sliced = elements.get(start..end).unwrap_or_default().to_vec();
+    Ok(vm.alloc(HeapObject::Array(sliced)))
+}
+
+/// Position in a list of `len` elements that a slice bound refers to, counting
+/// from the end when negative and clamped to the list.
+fn slice_bound(vm: &VirtualMachine, value: &Value, len: usize) -> Result<usize, String> {
+    let bound = vm.as_number(value)?;
+    if bound.fract() != 0.0 {
+        return Err(format!("slice bounds must be integers, got {}", bound));
+    }
+    let bound = if bound < 0.0 {
+        len as f64 + bound
+    } else {
+        bound
+    };
+    Ok(bound.clamp(0.0, len as f64) as usize)
+}
+
+fn chunk(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "chunk")?;
+    let size = group_size(vm, &args[1], "chunk")?;
+    let chunks = elements
+        .chunks(size)
+        .map(|chunk| HeapObject::Array(chunk.to_vec()))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(chunks)))
+}
+
+fn windows(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "windows")?;
+    let size = group_size(vm, &args[1], "windows")?;
+    let windows = elements
+        .windows(size)
+        .map(|window| HeapObject::Array(window.to_vec()))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(windows)))
+}
+
+fn group_size(vm: &VirtualMachine, value: &Value, function: &str) -> Result<usize, String> {
+    let size = vm.as_number(value)?;
+    if size.fract() != 0.0 || size < 1.0 {
+        return Err(format!(
+            "{} size must be a positive integer, got {}",
+            function, size
+        ));
+    }
+    Ok(size as usize)
+}
+
+fn reduce(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "reduce")?;
+    fold(vm, elements, &args[1], args[2].clone())
+}
+
+fn reduce_right(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut elements = vm.array_elements(&args[0], "reduce_right")?;
+    elements.reverse();
+    fold(vm, elements, &args[1], args[2].clone())
+}
+
+fn fold(
+    vm: &mut VirtualMachine,
+    elements: Vec<HeapObject>,
+    function: &Value,
+    initial: Value,
+) -> Result<Value, String> {
+    let mut accumulator = initial;
+    for element in elements {
+        let element = vm.heap_object_to_value(element);
+        accumulator = vm.call_value(function.clone(), vec![accumulator, element])?;
+    }
+    Ok(accumulator)
+}
+
+fn scan(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "scan")?;
+    let mut accumulator = args[2].clone();
+    let mut steps = Vec::with_capacity(elements.len());
+    for element in elements {
+        let element = vm.heap_object_to_value(element);
+        accumulator = vm.call_value(args[1].clone(), vec![accumulator, element])?;
+        steps.push(vm.value_to_heap_object(accumulator.clone()));
+    }
+    Ok(vm.alloc(HeapObject::Array(steps)))
+}
+
+/// Adds `element` unless an equal one is already there, which keeps the first.
+fn set_insert(set: &mut ObjectMap, element: HeapObject) -> Result<(), String> {
+    let key = set_key(&element)?;
+    if !set.contains_key(&key) {
+        set.insert(key, element);
+    }
+    Ok(())
+}
+
+/// Sets are keyed like maps, so they hold the values maps accept as keys.
+fn set_key(element: &HeapObject) -> Result<MapKey, String> {
+    match element {
+        HeapObject::Number(n) if n.is_nan() => Err("NaN cannot be a set element".to_string()),
+        HeapObject::String(_) | HeapObject::Number(_) | HeapObject::Boolean(_) => {
+            MapKey::from_heap_object(element)
+        }
+        other => Err(format!(
+            "Set elements must be strings, numbers or booleans, got {}",
+            other.type_name()
+        )),
+    }
+}
+
+fn to_number(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let radix = match args.get(1) {
+        Some(radix) => {
+            let radix = vm.as_number(radix)?;
+            if radix.fract() != 0.0 || !(2.0..=36.0).contains(&radix) {
+                return Err(format!(
+                    "to_number radix must be an integer from 2 to 36, got {}",
+                    radix
+                ));
+            }
+            Some(radix as u32)
+        }
+        None => None,
+    };
+
+    let text = match vm.to_host(&args[0])? {
+        HeapObject::Number(n) if radix.is_none() => return Ok(Value::Number(n)),
+        HeapObject::String(s) => s,
+        _ => {
+            return Err(format!(
+                "to_number expects a string, got {}",
+                vm.type_name(&args[0])
+            ));
+        }
+    };
+
+    // Text that isn't a valid number in the requested base yields nil
+    let parsed = match radix {
+        Some(radix) => i64::from_str_radix(text.trim(), radix)
+            .ok()
+            .map(|n| n as f64),
+        None => text.trim().parse::<f64>().ok(),
+    };
+    Ok(parsed.map_or(Value::Nil, Value::Number))
+}
+
+fn format(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let template = vm.as_string(&args[0])?;
+    let values = &args[1..];
+
+    // Split the template at each `{}`, while `{{` and `}}` stand for literal braces
+    let mut pieces = vec![String::new()];
+    let mut chars = template.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match (ch, chars.peek()) {
+            ('{', Some('{')) | ('}', Some('}')) => {
+                chars.next();
+                pieces.last_mut().unwrap().push(ch);
+            }
+            ('{', Some('}')) => {
+                chars.next();
+                pieces.push(String::new());
+            }
+            _ => pieces.last_mut().unwrap().push(ch),
+        }
+    }
+
+    let placeholders = pieces.len() - 1;
+    if placeholders != values.len() {
+        return Err(format!(
+            "format string has {} placeholders but got {} arguments",
+            placeholders,
+            values.len()
+        ));
+    }
+
+    let mut output = pieces[0].clone();
+    for (value, piece) in values.iter().zip(&pieces[1..]) {
+        output.push_str(&vm.format_value(value));
+        output.push_str(piece);
+    }
+    Ok(Value::String(output))
+}
+
+fn join(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let elements = vm.array_elements(&args[0], "join")?;
+    let separator = vm.as_string(&args[1])?;
+    let parts: Vec<String> = elements
+        .iter()
+        .map(|element| vm.format_heap_object(element, false))
+        .collect();
+    Ok(Value::String(parts.join(&separator)))
+}
+
+fn chars(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let text = vm.as_string(&args[0])?;
+    let chars = text
+        .chars()
+        .map(|ch| HeapObject::String(ch.to_string()))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(chars)))
+}
+
+fn print(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let parts: Vec<String> = args.iter().map(|arg| vm.format_value(arg)).collect();
+    vm.write_line(&parts.join(" "));
+    Ok(Value::Nil)
+}
+
+fn len(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let length = match vm.to_host(&args[0]).ok() {
+        Some(HeapObject::String(s)) => s.chars().count(),
+        Some(HeapObject::Array(elements)) => elements.len(),
+        Some(HeapObject::Set(set)) => set.len(),
+        Some(HeapObject::Object(map)) => map.len(),
+        _ => {
+            return Err(format!(
+                "len expects a string, array, set or object, got {}",
+                vm.type_name(&args[0])
+            ));
+        }
+    };
+    Ok(Value::Number(length as f64))
+}
+
+fn type_of(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::String(vm.type_name(&args[0]).to_string()))
+}
+
+fn assert(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    if vm.as_bool(&args[0])? {
+        return Ok(Value::Nil);
+    }
+    match args.get(1) {
+        Some(message) => Err(format!("Assertion failed: {}", vm.format_value(message))),
+        None => Err("Assertion failed".to_string()),
+    }
+}
+
+fn assert_eq(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    if vm.values_equal(&args[0], &args[1]) {
+        return Ok(Value::Nil);
+    }
+    // Strings are quoted so that `"1"` and `1` read differently
+    let [actual, expected] = [&args[0], &args[1]]
+        .map(|value| vm.format_heap_object(&vm.value_to_heap_object(value.clone()), true));
+    Err(format!(
+        "Assertion failed: expected {}, got {}",
+        expected, actual
+    ))
+}
+
+fn map_merge(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut merged = vm.as_map(&args[0])?;
+    for (key, value) in vm.as_map(&args[1])?.iter() {
+        merged.insert(key.clone(), value.clone());
+    }
+    Ok(vm.alloc(HeapObject::Object(merged)))
+}
+
+fn map_remove(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut map = vm.as_map(&args[0])?;
+    map.remove(&vm.map_key(&args[1])?);
+    Ok(vm.alloc(HeapObject::Object(map)))
+}
+
+fn map_has(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let map = vm.as_map(&args[0])?;
+    Ok(Value::Boolean(map.get(&vm.map_key(&args[1])?).is_some()))
+}
+
+fn map_entries(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let map = vm.as_map(&args[0])?;
+    let pairs = map
+        .iter()
+        .map(|(key, value)| HeapObject::Array(vec![key.to_heap_object(), value.clone()]))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(pairs)))
+}
+
+fn io_lines(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let path = vm.as_string(&args[0])?;
+    let contents =
+        fs::read_to_string(&path).map_err(|e| format!("Cannot read '{}': {}", path, e))?;
+    let lines = contents
+        .lines()
+        .map(|line| HeapObject::String(line.to_string()))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(lines)))
+}
+
+fn io_append(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let path = vm.as_string(&args[0])?;
+    let text = vm.as_string(&args[1])?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|e| format!("Cannot append to '{}': {}", path, e))?;
+    Ok(Value::Nil)
+}
+
+fn io_readline(vm: &mut VirtualMachine, _args: Vec<Value>) -> Result<Value, String> {
+    Ok(vm.read_line()?.map_or(Value::Nil, Value::String))
+}
+
+fn env_get(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let name = vm.as_string(&args[0])?;
+    Ok(vm.env_var(&name).map_or(Value::Nil, Value::String))
+}
+
+fn env_args(vm: &mut VirtualMachine, _args: Vec<Value>) -> Result<Value, String> {
+    let args = vm
+        .args()
+        .iter()
+        .map(|arg| HeapObject::String(arg.clone()))
+        .collect();
+    Ok(vm.alloc(HeapObject::Array(args)))
+}
+
+fn math_clamp(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let x = vm.as_number(&args[0])?;
+    let lo = vm.as_number(&args[1])?;
+    let hi = vm.as_number(&args[2])?;
+    if lo > hi {
+        return Err(format!(
+            "Math.clamp lower bound {} is greater than upper bound {}",
+            vm.format_number(lo),
+            vm.format_number(hi)
+        ));
+    }
+    Ok(Value::Number(x.max(lo).min(hi)))
+}
+
+fn math_sign(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let x = vm.as_number(&args[0])?;
+    let sign = if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else if x == 0.0 {
+        0.0
+    } else {
+        f64::NAN
+    };
+    Ok(Value::Number(sign))
+}
+
+fn math_hypot(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let a = vm.as_number(&args[0])?;
+    let b = vm.as_number(&args[1])?;
+    Ok(Value::Number(a.hypot(b)))
+}
+
+/// Applies `f` to the single number argument. Inputs outside the function's
+/// domain, like `Math.log(-1)`, give NaN rather than an error.
+fn math_unary(vm: &VirtualMachine, args: &[Value], f: fn(f64) -> f64) -> Result<Value, String> {
+    Ok(Value::Number(f(vm.as_number(&args[0])?)))
+}
+
+fn math_sin(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::sin)
+}
+
+fn math_cos(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::cos)
+}
+
+fn math_tan(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::tan)
+}
+
+fn math_asin(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::asin)
+}
+
+fn math_acos(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::acos)
+}
+
+fn math_atan(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::atan)
+}
+
+fn math_log2(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::log2)
+}
+
+fn math_log10(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::log10)
+}
+
+fn math_exp(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::exp)
+}
+
+fn math_log(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    math_unary(vm, &args, f64::ln)
+}
+
+fn math_atan2(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let y = vm.as_number(&args[0])?;
+    let x = vm.as_number(&args[1])?;
+    Ok(Value::Number(y.atan2(x)))
+}
+
+fn number_is_nan(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    Ok(Value::Boolean(vm.as_number(&args[0])?.is_nan()))
+}
+
+fn number_is_int(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let n = vm.as_number(&args[0])?;
+    Ok(Value::Boolean(n.is_finite() && n.fract() == 0.0))
+}
+
+fn number_parse(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let text = vm.as_string(&args[0])?;
+    Ok(text.trim().parse::<f64>().map_or(Value::Nil, Value::Number))
+}
+
+fn set_new(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut set = ObjectMap::new();
+    if let Some(initial) = args.first() {
+        for element in vm.array_elements(initial, "Set.new")? {
+            set_insert(&mut set, element)?;
+        }
+    }
+    Ok(vm.alloc(HeapObject::Set(set)))
+}
+
+fn set_add(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut set = vm.as_set(&args[0], "Set.add")?;
+    set_insert(&mut set, vm.value_to_heap_object(args[1].clone()))?;
+    Ok(vm.alloc(HeapObject::Set(set)))
+}
+
+fn set_contains(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let set = vm.as_set(&args[0], "Set.contains")?;
+    let element = vm.value_to_heap_object(args[1].clone());
+    // Nothing that can't be a set element is in the set
+    let found = set_key(&element).is_ok_and(|key| set.contains_key(&key));
+    Ok(Value::Boolean(found))
+}
+
+fn set_union(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let mut set = vm.as_set(&args[0], "Set.union")?;
+    for (key, element) in vm.as_set(&args[1], "Set.union")?.iter() {
+        if !set.contains_key(key) {
+            set.insert(key.clone(), element.clone());
+        }
+    }
+    Ok(vm.alloc(HeapObject::Set(set)))
+}
+
+fn set_intersection(vm: &mut VirtualMachine, args: Vec<Value>) -> Result<Value, String> {
+    let left = vm.as_set(&args[0], "Set.intersection")?;
+    let right = vm.as_set(&args[1], "Set.intersection")?;
+    let set = left
+        .iter()
+        .filter(|(key, _)| right.contains_key(key))
+        .cloned()
+        .collect();
+    Ok(vm.alloc(HeapObject::Set(set)))
+}
@@ -1,43 +1,117 @@
+use crate::builtins;
+use crate::parser::Parser;
+use crate::resolver::{FileResolver, ModuleResolver};
 use crate::types::ast::*;
+use crate::types::diagnostic::{Diagnostic, Severity};
 use std::collections::HashMap;
 use std::fmt;
+use std::ops::Range;
 
 use crate::types::compiler::*;
 
 pub struct Compiler {
     pub constants: Vec<Value>,
     pub functions: HashMap<String, usize>,
+    pub imports: Vec<String>,
     pub function_table: Vec<Value>,
     pub variables: Vec<HashMap<String, usize>>,
     pub instructions: Vec<Instruction>,
     pub instruction_lines: Vec<usize>,
+    pub instruction_columns: Vec<usize>,
     pub current_function: Option<String>,
     pub depth: usize,
     pub in_new_function: bool,
+    pub warnings: Vec<Diagnostic>,
+    options: CompileOptions,
+    line: usize,   // Source line of the statement being compiled
+    column: usize, // Column of the interpolated expression being compiled, 0 elsewhere
+    resolver: Box<dyn ModuleResolver>,
+    loaded_modules: Vec<String>, // Paths of source modules already linked into the program
+    private_names: HashMap<String, Vec<String>>, // Top-level names a module doesn't export, by path
+    captures: HashMap<usize, Vec<String>>, // Enclosing variables a nested function takes first
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Compiler {
     fn resolve_function_index(&self, name: &str) -> Result<usize, String> {
-        self.functions
-            .get(name)
-            .cloned()
+        self.find_function(name)
             .ok_or_else(|| format!("Undefined function '{}'", name))
     }
+
+    /// The function `name` refers to where code is being compiled, looking through
+    /// the enclosing functions outwards before the top level.
+    fn find_function(&self, name: &str) -> Option<usize> {
+        let mut scope = self.current_function.as_deref();
+        while let Some(path) = scope {
+            if let Some(index) = self.functions.get(&format!("{}.{}", path, name)) {
+                return Some(*index);
+            }
+            scope = path.rsplit_once('.').map(|(outer, _)| outer);
+        }
+        self.functions.get(name).cloned()
+    }
+
+    /// Functions are keyed by the path of the functions declaring them, so a helper
+    /// nested in `outer` is `outer.helper` and can't clash with one declared elsewhere.
+    fn function_key(&self, name: &str) -> String {
+        match &self.current_function {
+            Some(scope) => format!("{}.{}", scope, name),
+            None => name.to_string(),
+        }
+    }
+
     pub fn new() -> Self {
         Self {
             constants: Vec::new(),
             functions: HashMap::new(),
+            imports: Vec::new(),
             function_table: Vec::new(),
             variables: Vec::new(),
             depth: 0,
             instructions: Vec::new(),
             instruction_lines: Vec::new(),
+            instruction_columns: Vec::new(),
             current_function: None,
             in_new_function: false,
+            warnings: Vec::new(),
+            options: CompileOptions::default(),
+            line: 1,
+            column: 0,
+            resolver: Box::new(FileResolver::default()),
+            loaded_modules: Vec::new(),
+            private_names: HashMap::new(),
+            captures: HashMap::new(),
         }
     }
 
-    fn insert_variable(&mut self, name: &str) -> usize {
+    pub fn with_options(options: CompileOptions) -> Self {
+        Self {
+            options,
+            ..Self::new()
+        }
+    }
+
+    /// Loads the source of imported modules through `resolver` instead of
+    /// reading them from files.
+    pub fn with_resolver(mut self, resolver: impl ModuleResolver + 'static) -> Self {
+        self.resolver = Box::new(resolver);
+        self
+    }
+
+    /// Starts the constant table from `constants`, usually those of modules
+    /// compiled earlier, so constants the modules share are pooled once and
+    /// keep the indices they already have.
+    pub fn with_constants(mut self, constants: Vec<Value>) -> Self {
+        self.constants = constants;
+        self
+    }
+
+    fn current_scope(&mut self) -> &mut HashMap<String, usize> {
         while self.variables.len() <= self.depth {
             self.variables.push(HashMap::new());
         }
@@ -47,7 +121,11 @@ impl Compiler {
             self.in_new_function = false;
         }
 
-        let current_scope = &mut self.variables[self.depth];
+        &mut self.variables[self.depth]
+    }
+
+    fn insert_variable(&mut self, name: &str) -> usize {
+        let current_scope = self.current_scope();
         let local_index = current_scope.len(); // Next available index in this scope
         current_scope.insert(name.to_string(), local_index);
 
@@ -55,129 +133,707 @@ impl Compiler {
     }
 
     fn get_variable(&self, name: &str) -> Option<(usize, usize)> {
-        let mut result = None;
-        for (depth, scope) in self.variables.iter().enumerate() {
-            if depth > self.depth {
-                break;
-            }
-            if let Some(index) = scope.get(name) {
-                result = Some((*index, depth));
+        // The VM only reaches globals and the running function's frame, the
+        // variables of enclosing functions are passed in as captures instead
+        for depth in [self.depth, 0] {
+            if let Some(index) = self.variables.get(depth).and_then(|scope| scope.get(name)) {
+                return Some((*index, depth));
             }
         }
-        result
+        None
     }
 
-    pub fn compile(&mut self, program: &Program) -> Result<ByteCode, String> {
-        self.collect_pass(&program.statements);
-        self.generate_instructions(&program.statements)?;
+    pub fn compile(&mut self, program: &Program) -> Result<ByteCode, Diagnostic> {
+        self.link_modules(&program.statements, "")
+            .and_then(|statements| {
+                self.check_let_cycles(&statements)?;
+                self.collect_pass(&statements)?;
+                self.generate_instructions(&statements)
+            })
+            .map_err(|message| Diagnostic::error(message, self.line))?;
         self.instructions.push(Instruction::Halt);
         self.instruction_lines.push(self.current_line());
+        self.instruction_columns.push(self.column);
 
         Ok(ByteCode {
             constants: self.constants.clone(),
             functions: self.function_table.clone(),
             instructions: self.instructions.clone(),
             instruction_lines: self.instruction_lines.clone(),
+            instruction_columns: self.instruction_columns.clone(),
         })
     }
 
-    fn collect_pass(&mut self, statements: &[Stmt]) {
+    /// Places the statements of every imported source module, and of the modules
+    /// it imports in turn, ahead of the import. Builtin modules need no source and
+    /// each module is linked once, so import cycles end where they started.
+    /// `directory` is where the file holding `statements` lives, relative to the
+    /// resolver's root, the paths it imports are relative to it.
+    fn link_modules(&mut self, statements: &[Stmt], directory: &str) -> Result<Vec<Stmt>, String> {
+        let mut linked = Vec::new();
+        for stmt in statements {
+            if let Stmt::Import { modules, line } = stmt {
+                for module in modules {
+                    if builtins::is_module(module) {
+                        continue;
+                    }
+                    let path = module_path(directory, module);
+                    if self.loaded_modules.contains(&path) {
+                        continue;
+                    }
+                    self.line = *line;
+                    self.loaded_modules.push(path.clone());
+                    let source = self.resolver.resolve(&path)?;
+                    let mut program = Parser::from_source(&source)
+                        .parse()
+                        .map_err(|e| format!("In module '{}': {}", module, e))?;
+                    let private = private_names(&program.statements);
+                    mangle_names(&mut program.statements, &path, &private);
+                    self.private_names.insert(path.clone(), private);
+                    let inner = path.rsplit_once('/').map_or("", |(dir, _)| dir);
+                    linked.extend(self.link_modules(&program.statements, inner)?);
+                }
+            }
+            linked.push(stmt.clone());
+        }
+        self.check_exports(statements, directory)?;
+        Ok(linked)
+    }
+
+    /// Rejects uses of names that a module imported by `statements` keeps private.
+    /// The file's own top-level declarations and local bindings take precedence.
+    fn check_exports(&mut self, statements: &[Stmt], directory: &str) -> Result<(), String> {
+        let own = declared_names(statements);
+        for stmt in statements {
+            let Stmt::Import { modules, .. } = stmt else {
+                continue;
+            };
+            for module in modules {
+                let Some(private) = self.private_names.get(&module_path(directory, module)) else {
+                    continue;
+                };
+                for stmt in statements {
+                    let mut names = Vec::new();
+                    free_names(std::slice::from_ref(stmt), &mut Vec::new(), &mut names);
+                    if let Some(name) = names
+                        .iter()
+                        .find(|name| private.contains(name) && !own.contains(name))
+                    {
+                        self.line = statement_line(stmt);
+                        return Err(format!("'{}' is not exported by module '{}'", name, module));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rejects top-level `let`s whose values depend on each other in a cycle,
+    /// like `let a = b` followed by `let b = a`, naming the bindings involved.
+    /// Only the values themselves are followed, not the bodies of functions.
+    fn check_let_cycles(&mut self, statements: &[Stmt]) -> Result<(), String> {
+        let lets: Vec<(&String, usize, Vec<String>)> = statements
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Let {
+                    name, value, line, ..
+                } => {
+                    let mut names = Vec::new();
+                    referenced_names(value, &mut Vec::new(), &mut names);
+                    Some((name, *line, names))
+                }
+                _ => None,
+            })
+            .collect();
+        let dependencies: Vec<Vec<usize>> = lets
+            .iter()
+            .map(|(_, _, names)| {
+                names
+                    .iter()
+                    .filter_map(|name| lets.iter().position(|(let_name, ..)| *let_name == name))
+                    .collect()
+            })
+            .collect();
+
+        let mut done = vec![false; lets.len()];
+        for node in 0..lets.len() {
+            if let Some(cycle) = find_cycle(node, &dependencies, &mut Vec::new(), &mut done) {
+                self.line = lets[cycle[0]].1;
+                let names: Vec<&str> = cycle.iter().map(|&n| lets[n].0.as_str()).collect();
+                return Err(format!("Circular definition: {}", names.join(" -> ")));
+            }
+        }
+        Ok(())
+    }
+
+    fn collect_pass(&mut self, statements: &[Stmt]) -> Result<(), String> {
         for stmt in statements {
             match stmt {
                 Stmt::Func {
-                    name, params, body, ..
+                    name,
+                    params,
+                    body,
+                    line,
+                    ..
                 } => {
+                    let key = self.function_key(name);
+                    if self.functions.contains_key(&key) {
+                        self.line = *line;
+                        return Err(format!("Function '{}' is already defined", name));
+                    }
                     let function_index = self.function_table.len();
-                    self.functions.insert(name.clone(), function_index);
+                    self.functions.insert(key.clone(), function_index);
 
                     let function_value = Value::Function {
-                        params: params.clone(),
+                        params: params.iter().map(Param::name).collect(),
                         offset: 0,
                     };
                     self.function_table.push(function_value);
-                    self.collect_pass(body);
+                    let outer = self.current_function.replace(key);
+                    let collected = self.collect_pass(body);
+                    self.current_function = outer;
+                    collected?;
                 }
                 Stmt::Let { value, .. } => {
-                    self.collect_constants_from_expr(value);
+                    self.collect_constants_from_expr(value)?;
+                }
+                Stmt::Import { modules, .. } => {
+                    for module in modules {
+                        if !self.imports.contains(module) {
+                            self.imports.push(module.clone());
+                        }
+                    }
                 }
                 Stmt::Expr(expr, _) => {
-                    self.collect_constants_from_expr(expr);
+                    self.collect_constants_from_expr(expr)?;
                 }
             }
         }
+        Ok(())
     }
 
-    fn collect_constants_from_expr(&mut self, expr: &Expr) {
-        match expr {
-            Expr::Boolean(b) => {
-                let value = Value::Boolean(*b);
-                if !self.constants.iter().any(
-                    |c| matches!((c, &value), (Value::Boolean(a), Value::Boolean(b)) if a == b),
-                ) {
-                    self.constants.push(value);
+    fn collect_pattern_constants(&mut self, pattern: &Pattern) -> Result<(), String> {
+        match pattern {
+            Pattern::Literal(literal) => self.collect_constants_from_expr(literal),
+            Pattern::Array(elements) => {
+                for element in elements {
+                    self.collect_pattern_constants(element)?;
                 }
+                Ok(())
             }
-            Expr::Number(n) => {
-                let value = Value::Number(*n);
-                if !self
-                    .constants
-                    .iter()
-                    .any(|c| matches!((c, &value), (Value::Number(a), Value::Number(b)) if a == b))
-                {
-                    self.constants.push(value);
+            Pattern::Object(fields) => {
+                for (_, pattern) in fields {
+                    self.collect_pattern_constants(pattern)?;
                 }
+                Ok(())
             }
-            Expr::String(s) => {
-                let value = Value::String(s.clone());
-                if !self
-                    .constants
-                    .iter()
-                    .any(|c| matches!((c, &value), (Value::String(a), Value::String(b)) if a == b))
-                {
-                    self.constants.push(value);
+            Pattern::Or(alternatives) => {
+                for alternative in alternatives {
+                    self.collect_pattern_constants(alternative)?;
                 }
+                Ok(())
             }
+            Pattern::Wildcard | Pattern::Binding(_) | Pattern::Range { .. } => Ok(()),
+        }
+    }
+
+    fn collect_constants_from_expr(&mut self, expr: &Expr) -> Result<(), String> {
+        match expr {
+            Expr::Boolean(b) => self.add_constant(Value::Boolean(*b)),
+            Expr::Number(n) => self.add_constant(Value::Number(*n)),
+            Expr::String(s) => self.add_constant(Value::String(s.clone())),
             Expr::Binary { left, right, .. } => {
-                self.collect_constants_from_expr(left);
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(left)?;
+                self.collect_constants_from_expr(right)?;
             }
             Expr::Call { func, args } => {
-                self.collect_constants_from_expr(func);
+                self.collect_constants_from_expr(func)?;
                 for arg in args {
-                    self.collect_constants_from_expr(arg);
+                    self.collect_constants_from_expr(arg)?;
                 }
             }
+            Expr::Member { object, name } => {
+                self.collect_constants_from_expr(object)?;
+                self.collect_constants_from_expr(&Expr::String(name.clone()))?;
+            }
             Expr::Pipeline { left, right } => {
-                self.collect_constants_from_expr(left);
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(left)?;
+                self.collect_constants_from_expr(right)?;
             }
             Expr::Unary { right, .. } => {
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(right)?;
             }
             Expr::Update { left, right } => {
-                self.collect_constants_from_expr(left);
-                self.collect_constants_from_expr(right);
+                self.collect_constants_from_expr(left)?;
+                self.collect_constants_from_expr(right)?;
             }
             Expr::Array { elements } => {
                 for element in elements {
-                    self.collect_constants_from_expr(element);
+                    self.collect_constants_from_expr(element)?;
+                }
+            }
+            Expr::Map { entries } => {
+                for (key, value) in entries {
+                    self.collect_constants_from_expr(key)?;
+                    self.collect_constants_from_expr(value)?;
+                }
+            }
+            Expr::Index { object, index } => {
+                self.collect_constants_from_expr(object)?;
+                self.collect_constants_from_expr(index)?;
+            }
+            Expr::Match { value, arms } => {
+                self.collect_constants_from_expr(value)?;
+                for arm in arms {
+                    self.collect_pattern_constants(&arm.pattern)?;
+                    self.collect_pass(&arm.body)?;
+                }
+            }
+            Expr::For { iterable, body, .. } => {
+                self.collect_constants_from_expr(iterable)?;
+                self.collect_pass(body)?;
+            }
+            Expr::Yield { value } => {
+                self.collect_constants_from_expr(value)?;
+            }
+            Expr::Interpolation { parts } => {
+                for part in parts {
+                    if let InterpPart::Expr(expr, _) = part {
+                        self.collect_constants_from_expr(expr)?;
+                    }
                 }
             }
             Expr::Identifier(_) => {}
         }
+        Ok(())
+    }
+
+    /// Compiles `body` so that it leaves its value on the stack, nil when empty.
+    fn compile_block(&mut self, body: &[Stmt]) -> Result<(), String> {
+        let line = self.line;
+        self.check_unused_lets(body)?;
+        if body.is_empty() {
+            self.push(Instruction::Push(Value::Nil));
+        }
+        for (i, stmt) in body.iter().enumerate() {
+            self.compile_statement(stmt, i == body.len() - 1)?;
+        }
+        self.line = line;
+        Ok(())
+    }
+
+    /// Records a diagnostic at `line`, as a warning or, when `severity` is
+    /// `Error`, by failing the compilation.
+    fn report(&mut self, severity: Severity, message: String, line: usize) -> Result<(), String> {
+        match severity {
+            Severity::Warning => {
+                self.warnings.push(Diagnostic::warning(message, line));
+                Ok(())
+            }
+            Severity::Error => {
+                self.line = line;
+                Err(message)
+            }
+        }
+    }
+
+    /// Reports `let`s in a block that nothing after them reads. Names starting
+    /// with `_` are exempt.
+    fn check_unused_lets(&mut self, body: &[Stmt]) -> Result<(), String> {
+        for (i, stmt) in body.iter().enumerate() {
+            let Stmt::Let { name, line, .. } = stmt else {
+                continue;
+            };
+            let mut names = Vec::new();
+            free_names(&body[i + 1..], &mut Vec::new(), &mut names);
+            if !name.starts_with('_') && !names.contains(name) {
+                let message = format!("Unused binding '{}'", name);
+                self.report(self.options.unused_bindings, message, *line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports names bound by a match arm's pattern that its body never reads.
+    fn check_unused_pattern_names(&mut self, arm: &MatchArm) -> Result<(), String> {
+        let mut names = Vec::new();
+        free_names(&arm.body, &mut Vec::new(), &mut names);
+        for name in arm.pattern.bindings() {
+            if !name.starts_with('_') && !names.contains(&name) {
+                let message = format!("Unused binding '{}'", name);
+                self.report(self.options.unused_bindings, message, arm.line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports arms that follow a catch-all arm or repeat an earlier literal,
+    /// neither can ever be selected.
+    fn check_unreachable_arms(&mut self, arms: &[MatchArm]) -> Result<(), String> {
+        let mut seen: Vec<&Expr> = Vec::new();
+        let mut catch_all = false;
+        for arm in arms {
+            let reason = match &arm.pattern {
+                // An `if let` whose pattern can't fail still has its `else`
+                _ if arm.fallback => None,
+                _ if catch_all => Some("an earlier arm matches every value"),
+                Pattern::Literal(literal) if seen.contains(&literal) => {
+                    Some("an earlier arm has the same pattern")
+                }
+                Pattern::Literal(literal) => {
+                    seen.push(literal);
+                    None
+                }
+                Pattern::Wildcard | Pattern::Binding(_) => {
+                    catch_all = true;
+                    None
+                }
+                Pattern::Or(alternatives) => {
+                    catch_all = alternatives
+                        .iter()
+                        .any(|pattern| matches!(pattern, Pattern::Wildcard | Pattern::Binding(_)));
+                    None
+                }
+                Pattern::Range { .. } | Pattern::Array(_) | Pattern::Object(_) => None,
+            };
+            if let Some(reason) = reason {
+                let message = format!("Unreachable match arm, {}", reason);
+                self.report(self.options.unreachable_arms, message, arm.line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tests the part of the scrutinee reached by indexing it with `path` against
+    /// `pattern`, binding its names. Each failed test jumps to the next arm, the
+    /// jumps are added to `next_arm` to be patched.
+    fn compile_pattern(
+        &mut self,
+        pattern: &Pattern,
+        scrutinee: usize,
+        path: &mut Vec<Value>,
+        next_arm: &mut Vec<usize>,
+    ) -> Result<(), String> {
+        match pattern {
+            Pattern::Wildcard => {}
+            Pattern::Literal(literal) => {
+                self.load_path(scrutinee, path);
+                self.compile_expression(literal)?;
+                self.push(Instruction::Equal);
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+            }
+            Pattern::Range {
+                start,
+                end,
+                inclusive,
+            } => {
+                // typeof(value) == "number", so other values fall through instead of failing to compare
+                let typeof_index = builtins::lookup("typeof").expect("typeof is a builtin");
+                self.load_path(scrutinee, path);
+                self.push(Instruction::CallGlobal(typeof_index, 1));
+                self.push(Instruction::Push(Value::String("number".to_string())));
+                self.push(Instruction::Equal);
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+
+                // start <= value
+                self.load_path(scrutinee, path);
+                self.push(Instruction::Push(Value::Number(*start)));
+                self.push(Instruction::GreaterEqual);
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+
+                // value < end, or value <= end when inclusive
+                self.load_path(scrutinee, path);
+                self.push(Instruction::Push(Value::Number(*end)));
+                if *inclusive {
+                    self.push(Instruction::LessEqual);
+                } else {
+                    self.push(Instruction::Less);
+                }
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+            }
+            Pattern::Binding(name) => {
+                self.load_path(scrutinee, path);
+                let index = self.insert_variable(name);
+                self.push(Instruction::StoreVar(self.depth, index));
+            }
+            Pattern::Array(elements) => {
+                // typeof(value) == "array", then len(value) == elements.len()
+                let typeof_index = builtins::lookup("typeof").expect("typeof is a builtin");
+                self.load_path(scrutinee, path);
+                self.push(Instruction::CallGlobal(typeof_index, 1));
+                self.push(Instruction::Push(Value::String("array".to_string())));
+                self.push(Instruction::Equal);
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+
+                let len_index = builtins::lookup("len").expect("len is a builtin");
+                self.load_path(scrutinee, path);
+                self.push(Instruction::CallGlobal(len_index, 1));
+                self.push(Instruction::Push(Value::Number(elements.len() as f64)));
+                self.push(Instruction::Equal);
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+
+                for (i, element) in elements.iter().enumerate() {
+                    path.push(Value::Number(i as f64));
+                    self.compile_pattern(element, scrutinee, path, next_arm)?;
+                    path.pop();
+                }
+            }
+            Pattern::Object(fields) => {
+                // typeof(value) == "object", then Map.has(value, field) for every field
+                let typeof_index = builtins::lookup("typeof").expect("typeof is a builtin");
+                self.load_path(scrutinee, path);
+                self.push(Instruction::CallGlobal(typeof_index, 1));
+                self.push(Instruction::Push(Value::String("object".to_string())));
+                self.push(Instruction::Equal);
+                next_arm.push(self.instructions.len());
+                self.push(Instruction::JumpIfFalse(0));
+
+                let has_index = builtins::lookup("Map.has").expect("Map.has is a builtin");
+                for (field, pattern) in fields {
+                    let key = Value::String(field.clone());
+                    self.load_path(scrutinee, path);
+                    self.push(Instruction::Push(key.clone()));
+                    self.push(Instruction::CallGlobal(has_index, 2));
+                    next_arm.push(self.instructions.len());
+                    self.push(Instruction::JumpIfFalse(0));
+
+                    path.push(key);
+                    self.compile_pattern(pattern, scrutinee, path, next_arm)?;
+                    path.pop();
+                }
+            }
+            Pattern::Or(alternatives) => {
+                let mut names = alternatives[0].bindings();
+                names.sort();
+                for alternative in &alternatives[1..] {
+                    let mut other = alternative.bindings();
+                    other.sort();
+                    if let Some(name) = names
+                        .iter()
+                        .find(|name| !other.contains(name))
+                        .or_else(|| other.iter().find(|name| !names.contains(name)))
+                    {
+                        return Err(format!(
+                            "Every alternative of a '|' pattern must bind the same names, '{}' is only bound by some",
+                            name
+                        ));
+                    }
+                }
+
+                // Each alternative that fails moves on to the next, the last one to the next arm
+                let mut matched = Vec::new();
+                let mut slots: Vec<(String, usize)> = Vec::new();
+                for (i, alternative) in alternatives.iter().enumerate() {
+                    let last = i + 1 == alternatives.len();
+                    let mut next_alternative = Vec::new();
+                    let failed = if last {
+                        &mut *next_arm
+                    } else {
+                        &mut next_alternative
+                    };
+                    self.compile_pattern(alternative, scrutinee, path, failed)?;
+
+                    // The body reads the slots of the first alternative, later ones copy theirs over
+                    if i == 0 {
+                        for name in &names {
+                            let slot = self.current_scope()[name];
+                            slots.push((name.clone(), slot));
+                        }
+                    } else {
+                        for (name, slot) in &slots {
+                            let index = self.current_scope()[name];
+                            self.push(Instruction::LoadVar(self.depth, index));
+                            self.push(Instruction::StoreVar(self.depth, *slot));
+                            self.current_scope().insert(name.clone(), *slot);
+                        }
+                    }
+                    if !last {
+                        matched.push(self.instructions.len());
+                        self.push(Instruction::Jump(0));
+                        for jump in next_alternative {
+                            self.instructions[jump] =
+                                Instruction::JumpIfFalse(self.instructions.len());
+                        }
+                    }
+                }
+                let end = self.instructions.len();
+                for jump in matched {
+                    self.instructions[jump] = Instruction::Jump(end);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_path(&mut self, scrutinee: usize, path: &[Value]) {
+        self.push(Instruction::LoadVar(self.depth, scrutinee));
+        for key in path {
+            self.push(Instruction::Push(key.clone()));
+            self.push(Instruction::Index);
+        }
+    }
+
+    /// Reports a definition that hides a prelude builtin, calls then reach the definition.
+    fn check_prelude_shadowing(&mut self, name: &str) -> Result<(), String> {
+        if builtins::is_prelude(name) {
+            let message = format!("'{}' shadows the prelude builtin of the same name", name);
+            self.report(self.options.shadowing, message, self.line)?;
+        }
+        Ok(())
+    }
+
+    /// Works out which variables of the function being compiled each function nested
+    /// in `body` uses. The VM can't reach an enclosing frame, so they become leading
+    /// parameters that callers fill in. A helper calling a sibling takes the sibling's
+    /// captures too, to pass them on.
+    fn collect_captures(&mut self, body: &[Stmt]) {
+        let mut locals: Vec<String> = self.current_scope().keys().cloned().collect();
+        locals.extend(body.iter().filter_map(|stmt| match stmt {
+            Stmt::Let { name, .. } => Some(name.clone()),
+            _ => None,
+        }));
+        let nested: Vec<(&String, usize, Vec<String>)> = body
+            .iter()
+            .filter_map(|stmt| match stmt {
+                Stmt::Func { name, .. } => {
+                    let mut names = Vec::new();
+                    free_names(std::slice::from_ref(stmt), &mut Vec::new(), &mut names);
+                    Some((name, self.find_function(name)?, names))
+                }
+                _ => None,
+            })
+            .collect();
+
+        let mut captures: Vec<Vec<String>> = nested
+            .iter()
+            .map(|(_, _, names)| {
+                let mut captured: Vec<String> = Vec::new();
+                for name in names {
+                    if locals.contains(name) && !captured.contains(name) {
+                        captured.push(name.clone());
+                    }
+                }
+                captured
+            })
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for caller in 0..nested.len() {
+                for callee in 0..nested.len() {
+                    if caller == callee || !nested[caller].2.contains(nested[callee].0) {
+                        continue;
+                    }
+                    for name in captures[callee].clone() {
+                        if !captures[caller].contains(&name) {
+                            captures[caller].push(name);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        for ((_, function_index, _), captured) in nested.iter().zip(captures) {
+            if captured.is_empty() {
+                continue;
+            }
+            if let Some(Value::Function { params, .. }) =
+                self.function_table.get_mut(*function_index)
+            {
+                params.splice(0..0, captured.iter().cloned());
+            }
+            self.captures.insert(*function_index, captured);
+        }
+    }
+
+    /// Pushes the variables a nested function captures ahead of the arguments of a
+    /// call to it, giving how many there were.
+    fn push_captures(&mut self, func: &Expr) -> Result<usize, String> {
+        let Expr::Identifier(name) = func else {
+            return Ok(0);
+        };
+        if self.get_variable(name).is_some() {
+            return Ok(0);
+        }
+        let Some(captures) = self
+            .find_function(name)
+            .and_then(|index| self.captures.get(&index))
+            .cloned()
+        else {
+            return Ok(0);
+        };
+        for capture in captures.iter() {
+            let (index, depth) = self
+                .get_variable(capture)
+                .ok_or_else(|| format!("'{}' uses '{}' before it is defined", name, capture))?;
+            self.push(Instruction::LoadVar(depth, index));
+        }
+        Ok(captures.len())
+    }
+
+    /// Turns calls whose result is returned straight away into tail calls, which
+    /// reuse the caller's frame instead of growing the call stack.
+    fn mark_tail_calls(&mut self, body: Range<usize>) {
+        for pc in body {
+            if let Instruction::Call(index, arg_count) = self.instructions[pc]
+                && self.is_tail_position(pc + 1)
+            {
+                self.instructions[pc] = Instruction::TailCall(index, arg_count);
+            }
+        }
+    }
+
+    /// Whether execution continuing at `pc` returns without doing anything else,
+    /// following forward jumps such as the ones ending a match arm.
+    pub fn is_tail_position(&self, mut pc: usize) -> bool {
+        loop {
+            match self.instructions.get(pc) {
+                Some(Instruction::Return) => return true,
+                Some(Instruction::Jump(target)) if *target > pc => pc = *target,
+                _ => return false,
+            }
+        }
     }
 
     fn generate_instructions(&mut self, statements: &[Stmt]) -> Result<(), String> {
-        for stmt in statements {
-            self.compile_statement(stmt, false)?;
+        // The last top-level expression is kept on the stack as the program's value
+        let result = statements
+            .iter()
+            .rposition(|stmt| matches!(stmt, Stmt::Expr(..)));
+        for (i, stmt) in statements.iter().enumerate() {
+            self.compile_statement(stmt, Some(i) == result)?;
         }
         Ok(())
     }
 
     fn compile_statement(&mut self, stmt: &Stmt, last: bool) -> Result<(), String> {
+        self.line = match stmt {
+            Stmt::Let { line, .. }
+            | Stmt::Func { line, .. }
+            | Stmt::Import { line, .. }
+            | Stmt::Expr(_, line) => *line,
+        };
         match stmt {
-            Stmt::Let { name, value, line } => {
+            Stmt::Let {
+                name,
+                value,
+                line,
+                annotation,
+                ..
+            } => {
+                self.check_prelude_shadowing(name)?;
                 self.compile_expression(value)?;
+                if let Some(annotation) = annotation {
+                    let subject = format!("'{}'", name);
+                    self.push_with_line(Instruction::CheckType(annotation.clone(), subject), *line);
+                }
                 let var_index = match self.get_or_create_variable_index(name) {
                     VarOutput::Created { index, .. } => index,
                     VarOutput::GotCurrentScope { .. } => {
@@ -191,7 +847,8 @@ impl Compiler {
 
                 self.push_with_line(Instruction::StoreVar(self.depth, var_index), *line);
                 if last {
-                    self.push_with_line(Instruction::Push(Value::Number(0.0)), *line); // TEMP MEASURE, REPLACE THIS ONCE ENUMS ARE IMPLEMENTED PLEASE !!!
+                    // A declaration has no value, so a block ending in one gives nil
+                    self.push_with_line(Instruction::Push(Value::Nil), *line);
                 }
             }
             Stmt::Func {
@@ -199,47 +856,107 @@ impl Compiler {
                 params,
                 body,
                 line,
+                returns,
+                ..
             } => {
+                self.check_prelude_shadowing(name)?;
+                let key = self.function_key(name);
+                let function_index = self.resolve_function_index(name)?;
+                let captures = self
+                    .captures
+                    .get(&function_index)
+                    .cloned()
+                    .unwrap_or_default();
                 let jump_over_function = self.instructions.len();
                 self.push_with_line(Instruction::Jump(0), *line);
+                let function_start = self.instructions.len();
                 self.depth += 1;
                 self.in_new_function = true;
-                if let Some(function_index) = self.functions.get(name).cloned() {
-                    if let Some(Value::Function { params, .. }) =
-                        self.function_table.get_mut(function_index)
-                    {
-                        let param_count = params.len();
-                        let params = params.clone();
-                        self.function_table[function_index] = Value::Function {
-                            params,
-                            offset: self.instructions.len(),
-                        };
-
-                        if param_count > 0 {
-                            self.push_with_line(Instruction::LoadArg(param_count), *line);
-                        }
+                if let Some(Value::Function { params, .. }) =
+                    self.function_table.get_mut(function_index)
+                {
+                    let param_count = params.len();
+                    let params = params.clone();
+                    self.function_table[function_index] = Value::Function {
+                        params,
+                        offset: self.instructions.len(),
+                    };
+
+                    if param_count > 0 {
+                        self.push_with_line(Instruction::LoadArg(param_count), *line);
                     }
                 }
+                if body_yields(body) {
+                    // Calls hand back the suspended frame, the first resume's nil is dropped
+                    self.push_with_line(Instruction::MakeGenerator, *line);
+                    self.push_with_line(Instruction::Pop, *line);
+                }
 
                 let old_function = self.current_function.clone();
 
-                self.current_function = Some(name.clone());
+                self.current_function = Some(key);
 
-                for param_name in params.iter() {
-                    let _ = self.get_or_create_variable_index(param_name);
+                // Start from an empty scope so names left over from an earlier
+                // function can't resolve to its slots
+                self.current_scope();
+                for capture in captures.iter() {
+                    self.insert_variable(capture);
                 }
-
-                for (i, body_stmt) in body.iter().enumerate() {
-                    let last = i == body.len() - 1;
-                    self.compile_statement(body_stmt, last)?;
+                for param in params.iter() {
+                    self.insert_variable(&param.name());
+                }
+                // Annotated arguments are checked on entry, before the body can use them
+                for (slot, param) in params.iter().enumerate() {
+                    let slot = slot + captures.len();
+                    if let Param::Typed(param, annotation) = param {
+                        let subject = format!("parameter '{}' of '{}'", param, name);
+                        self.push_with_line(Instruction::LoadVar(self.depth, slot), *line);
+                        self.push_with_line(
+                            Instruction::CheckType(annotation.clone(), subject),
+                            *line,
+                        );
+                        self.push_with_line(Instruction::Pop, *line);
+                    }
                 }
+                // Destructured parameters bind their fields on entry, a missing field is an error
+                for (slot, param) in params.iter().enumerate() {
+                    let slot = slot + captures.len();
+                    if let Param::Fields(fields) = param {
+                        for field in fields {
+                            self.push_with_line(Instruction::LoadVar(self.depth, slot), *line);
+                            self.push_with_line(
+                                Instruction::Push(Value::String(field.clone())),
+                                *line,
+                            );
+                            self.push_with_line(Instruction::Index, *line);
+                            let index = self.insert_variable(field);
+                            self.push_with_line(Instruction::StoreVar(self.depth, index), *line);
+                        }
+                    }
+                }
+                self.collect_captures(body);
+
+                self.compile_block(body)?;
                 self.depth -= 1;
 
+                if let Some(returns) = returns {
+                    let subject = format!("the result of '{}'", name);
+                    self.push_with_line(Instruction::CheckType(returns.clone(), subject), *line);
+                }
                 self.push_with_line(Instruction::Return, *line);
+                if self.options.tail_calls {
+                    self.mark_tail_calls(function_start..self.instructions.len());
+                }
                 self.current_function = old_function;
 
                 let after_function = self.instructions.len();
                 self.instructions[jump_over_function] = Instruction::Jump(after_function);
+                if last {
+                    self.push_with_line(Instruction::Push(Value::Nil), *line);
+                }
+            }
+            Stmt::Import { .. } => {
+                // Imports are resolved entirely during the collect pass
             }
             Stmt::Expr(expr, line) => {
                 self.compile_expression(expr)?;
@@ -252,6 +969,13 @@ impl Compiler {
     }
 
     fn compile_expression(&mut self, expr: &Expr) -> Result<(), String> {
+        if self.options.constant_folding
+            && matches!(expr, Expr::Binary { .. } | Expr::Unary { .. })
+            && let Some(n) = fold_number(expr)
+        {
+            self.push(Instruction::Push(Value::Number(n)));
+            return Ok(());
+        }
         match expr {
             Expr::Boolean(b) => {
                 let const_index = self.get_constant_index(&Value::Boolean(*b));
@@ -265,6 +989,28 @@ impl Compiler {
                 let const_index = self.get_constant_index(&Value::String(s.clone()));
                 self.push(Instruction::LoadConst(const_index));
             }
+            Expr::Identifier(name)
+                if self.get_variable(name).is_none() && self.find_function(name).is_some() =>
+            {
+                let function_index = self.resolve_function_index(name)?;
+                let captured = self.push_captures(expr)?;
+                if captured > 0 {
+                    if let Some(Value::Function { params, .. }) =
+                        self.function_table.get(function_index)
+                        && params.len() == captured
+                    {
+                        return Err(format!(
+                            "'{}' takes no arguments of its own, so it can only be called",
+                            name
+                        ));
+                    }
+                    // Binding the captures leaves a partial taking the function's own arguments
+                    self.push(Instruction::LoadFunction(function_index));
+                    self.push(Instruction::CallValue(captured));
+                } else {
+                    self.push(Instruction::LoadFunction(function_index));
+                }
+            }
             Expr::Identifier(name) => {
                 let (var_index, fetch_depth) = match self.get_or_create_variable_index(name) {
                     VarOutput::Created { index, depth } => (index, depth),
@@ -273,6 +1019,30 @@ impl Compiler {
                 };
                 self.push(Instruction::LoadVar(fetch_depth, var_index));
             }
+            Expr::Binary {
+                left,
+                op: op @ (BinaryOp::And | BinaryOp::Or),
+                right,
+            } => {
+                // The left operand decides the result on its own when it is `false` for
+                // `&&` or `true` for `||`, so the right operand is jumped over unevaluated
+                self.compile_expression(left)?;
+                self.push(Instruction::Dup);
+                let jump = self.instructions.len();
+                self.push(if *op == BinaryOp::And {
+                    Instruction::JumpIfFalse(0)
+                } else {
+                    Instruction::JumpIfTrue(0)
+                });
+                self.push(Instruction::Pop);
+                self.compile_expression(right)?;
+                let end = self.instructions.len();
+                self.instructions[jump] = if *op == BinaryOp::And {
+                    Instruction::JumpIfFalse(end)
+                } else {
+                    Instruction::JumpIfTrue(end)
+                };
+            }
             Expr::Binary { left, op, right } => {
                 self.compile_expression(left)?;
                 self.compile_expression(right)?;
@@ -281,53 +1051,60 @@ impl Compiler {
                     BinaryOp::Sub => self.push(Instruction::Sub),
                     BinaryOp::Mul => self.push(Instruction::Mul),
                     BinaryOp::Div => self.push(Instruction::Div),
+                    BinaryOp::Pow => self.push(Instruction::Pow),
                     BinaryOp::Eq => self.push(Instruction::Equal),
                     BinaryOp::Lt => self.push(Instruction::Less),
                     BinaryOp::Gt => self.push(Instruction::Greater),
                     BinaryOp::Ne => {
                         self.push(Instruction::Equal);
+                        self.push(Instruction::Not);
                     }
-                    BinaryOp::Le => {
-                        self.push(Instruction::Greater);
-                    }
-                    BinaryOp::Ge => {
-                        self.push(Instruction::Less);
-                    }
+                    BinaryOp::Le => self.push(Instruction::LessEqual),
+                    BinaryOp::Ge => self.push(Instruction::GreaterEqual),
+                    BinaryOp::And | BinaryOp::Or => unreachable!("compiled with jumps above"),
                 }
             }
             Expr::Call { func, args } => {
-                for arg in args.iter().rev() {
+                let captured = self.push_captures(func)?;
+                for arg in args.iter() {
                     self.compile_expression(arg)?;
                 }
-
-                if let Expr::Identifier(func_name) = func.as_ref() {
-                    let function_index = self.resolve_function_index(func_name)?;
-                    self.push(Instruction::Call(function_index));
-                } else {
-                    self.compile_expression(func)?;
+                self.compile_call(func, captured + args.len())?;
+            }
+            Expr::Member { object, name } => {
+                if let Expr::Identifier(module) = object.as_ref()
+                    && builtins::is_module(module)
+                {
+                    let qualified = format!("{}.{}", module, name);
+                    if let Some(value) = builtins::constant(&qualified) {
+                        self.require_import(module)?;
+                        self.push(Instruction::Push(Value::Number(value)));
+                        return Ok(());
+                    }
+                    return Err(format!("Module function '{}' must be called", qualified));
                 }
+                // Field access is indexing with the field name as a string key
+                self.compile_expression(object)?;
+                let const_index = self.get_constant_index(&Value::String(name.clone()));
+                self.push(Instruction::GetField(const_index));
             }
             Expr::Pipeline { left, right } => {
+                let callee = match right.as_ref() {
+                    Expr::Call { func, .. } => func,
+                    _ => right,
+                };
+                let captured = self.push_captures(callee)?;
                 self.compile_expression(left)?;
 
+                // The piped value becomes the first argument of the right hand side
                 match right.as_ref() {
                     Expr::Call { func, args } => {
-                        for arg in args.iter().rev() {
+                        for arg in args.iter() {
                             self.compile_expression(arg)?;
                         }
-                        if let Expr::Identifier(func_name) = func.as_ref() {
-                            let function_index = self.resolve_function_index(func_name)?;
-                            self.push(Instruction::Call(function_index));
-                        }
-                    }
-                    Expr::Identifier(func_name) => {
-                        let function_index = self.resolve_function_index(func_name)?;
-                        self.push(Instruction::Call(function_index));
-                    }
-                    _ => {
-                        println!("right: {:?}", right);
-                        self.compile_expression(right)?;
+                        self.compile_call(func, captured + args.len() + 1)?;
                     }
+                    _ => self.compile_call(right, captured + 1)?,
                 }
             }
             Expr::Unary { op, right } => match op {
@@ -353,20 +1130,202 @@ impl Compiler {
                 }
                 self.push(Instruction::CreateArray(elements.len()));
             }
+            Expr::Map { entries } => {
+                for (key, value) in entries.iter() {
+                    self.compile_expression(key)?;
+                    self.compile_expression(value)?;
+                }
+                self.push(Instruction::CreateMap(entries.len()));
+            }
+            Expr::Index { object, index } => {
+                self.compile_expression(object)?;
+                if let Expr::String(name) = index.as_ref() {
+                    // A literal key is known while compiling, so it is looked up like a field
+                    let const_index = self.get_constant_index(&Value::String(name.clone()));
+                    self.push(Instruction::GetField(const_index));
+                } else {
+                    self.compile_expression(index)?;
+                    self.push(Instruction::Index);
+                }
+            }
+            Expr::Match { value, arms } => {
+                self.check_unreachable_arms(arms)?;
+                self.compile_expression(value)?;
+                let scope = self.current_scope().clone();
+
+                // The scrutinee is evaluated once and kept in a hidden variable for the arms to test
+                let scrutinee = self.insert_variable(&format!("$match{}", self.instructions.len()));
+                self.push(Instruction::StoreVar(self.depth, scrutinee));
+                let arm_scope = self.current_scope().clone();
+
+                let mut end_jumps = Vec::new();
+                for arm in arms {
+                    let mut next_arm = Vec::new();
+                    self.check_unused_pattern_names(arm)?;
+                    self.compile_pattern(&arm.pattern, scrutinee, &mut Vec::new(), &mut next_arm)?;
+
+                    self.compile_block(&arm.body)?;
+                    end_jumps.push(self.instructions.len());
+                    self.push(Instruction::Jump(0));
+
+                    for jump in next_arm {
+                        self.instructions[jump] = Instruction::JumpIfFalse(self.instructions.len());
+                    }
+                    // Bindings are only visible inside their own arm
+                    *self.current_scope() = arm_scope.clone();
+                }
+
+                // Reached when no arm matched
+                self.push(Instruction::LoadVar(self.depth, scrutinee));
+                self.push(Instruction::NoMatch);
+                let end = self.instructions.len();
+                for jump in end_jumps {
+                    self.instructions[jump] = Instruction::Jump(end);
+                }
+                *self.current_scope() = scope;
+            }
+            Expr::For {
+                name,
+                iterable,
+                body,
+            } => {
+                self.compile_expression(iterable)?;
+                let scope = self.current_scope().clone();
+
+                // The iterable, the position in it and the body values collected so far
+                let hidden = format!("$for{}", self.instructions.len());
+                let source = self.insert_variable(&hidden);
+                self.push(Instruction::StoreVar(self.depth, source));
+                let position = self.insert_variable(&format!("{}_position", hidden));
+                self.push(Instruction::Push(Value::Number(0.0)));
+                self.push(Instruction::StoreVar(self.depth, position));
+                let results = self.insert_variable(&format!("{}_results", hidden));
+                self.push(Instruction::CreateArray(0));
+                self.push(Instruction::StoreVar(self.depth, results));
+
+                let loop_start = self.instructions.len();
+                self.push(Instruction::LoadVar(self.depth, source));
+                self.push(Instruction::LoadVar(self.depth, position));
+                let next = self.instructions.len();
+                self.push(Instruction::IterNext(0));
+                let element = self.insert_variable(name);
+                self.push(Instruction::StoreVar(self.depth, element));
+
+                self.push(Instruction::LoadVar(self.depth, results));
+                self.compile_block(body)?;
+                self.push(Instruction::CreateArray(1));
+                self.push(Instruction::ConcatArray);
+                self.push(Instruction::StoreVar(self.depth, results));
+
+                self.push(Instruction::LoadVar(self.depth, position));
+                self.push(Instruction::Push(Value::Number(1.0)));
+                self.push(Instruction::Add);
+                self.push(Instruction::StoreVar(self.depth, position));
+                self.push(Instruction::Jump(loop_start));
+
+                self.instructions[next] = Instruction::IterNext(self.instructions.len());
+                self.push(Instruction::LoadVar(self.depth, results));
+                *self.current_scope() = scope;
+            }
+            Expr::Yield { value } => {
+                if self.current_function.is_none() {
+                    return Err("yield is only allowed inside a function".to_string());
+                }
+                self.compile_expression(value)?;
+                self.push(Instruction::Yield);
+            }
+            Expr::Interpolation { parts } => {
+                // Formatted like `format`, with a `{}` where each expression goes
+                let mut template = String::new();
+                let mut argc = 1;
+                for part in parts {
+                    match part {
+                        InterpPart::Literal(text) => {
+                            template.push_str(&text.replace('{', "{{").replace('}', "}}"))
+                        }
+                        InterpPart::Expr(..) => {
+                            template.push_str("{}");
+                            argc += 1;
+                        }
+                    }
+                }
+                self.push(Instruction::Push(Value::String(template)));
+                for part in parts {
+                    if let InterpPart::Expr(expr, span) = part {
+                        // Errors in the expression point at it inside the string
+                        let outer = (self.line, self.column);
+                        if span.line > 0 {
+                            (self.line, self.column) = (span.line, span.column);
+                        }
+                        self.compile_expression(expr)?;
+                        (self.line, self.column) = outer;
+                    }
+                }
+                let format = builtins::lookup("format").expect("format is a builtin");
+                self.push(Instruction::CallGlobal(format, argc));
+            }
+        }
+        Ok(())
+    }
+
+    fn compile_call(&mut self, func: &Expr, argc: usize) -> Result<(), String> {
+        // Named functions are called directly unless a variable shadows the name
+        if let Expr::Identifier(func_name) = func
+            && self.get_variable(func_name).is_none()
+        {
+            if self.find_function(func_name).is_none()
+                && let Some(builtin_index) = builtins::lookup(func_name)
+            {
+                self.push(Instruction::CallGlobal(builtin_index, argc));
+                return Ok(());
+            }
+            let function_index = self.resolve_function_index(func_name)?;
+            self.push(Instruction::Call(function_index, argc));
+        } else if let Expr::Member { object, name } = func
+            && let Expr::Identifier(module) = object.as_ref()
+            && self.get_variable(module).is_none()
+            && builtins::is_module(module)
+        {
+            self.require_import(module)?;
+            let qualified = format!("{}.{}", module, name);
+            let builtin_index = builtins::lookup(&qualified)
+                .ok_or_else(|| format!("Undefined function '{}'", qualified))?;
+            self.push(Instruction::CallGlobal(builtin_index, argc));
+        } else {
+            self.compile_expression(func)?;
+            self.push(Instruction::CallValue(argc));
+        }
+        Ok(())
+    }
+
+    fn require_import(&self, module: &str) -> Result<(), String> {
+        if !self.imports.iter().any(|import| import == module) {
+            return Err(format!(
+                "Module '{}' must be imported before use: import \"{}\"",
+                module, module
+            ));
         }
         Ok(())
     }
 
     fn get_constant_index(&self, value: &Value) -> usize {
-        self.constants
-            .iter()
-            .position(|c| match (c, value) {
-                (Value::Number(a), Value::Number(b)) => a == b,
-                (Value::String(a), Value::String(b)) => a == b,
-                (Value::Boolean(a), Value::Boolean(b)) => a == b,
-                _ => false,
-            })
-            .unwrap_or(0)
+        self.find_constant(value).unwrap_or(0)
+    }
+
+    fn find_constant(&self, value: &Value) -> Option<usize> {
+        self.constants.iter().position(|c| match (c, value) {
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            _ => false,
+        })
+    }
+
+    /// Adds `value` to the constant table unless an equal constant is already there.
+    fn add_constant(&mut self, value: Value) {
+        if self.find_constant(&value).is_none() {
+            self.constants.push(value);
+        }
     }
 
     fn get_or_create_variable_index(&mut self, name: &str) -> VarOutput {
@@ -388,19 +1347,22 @@ impl Compiler {
 
 impl Compiler {
     fn current_line(&self) -> usize {
-        *self.instruction_lines.last().unwrap_or(&1)
+        self.line
     }
 
     fn push(&mut self, instr: Instruction) {
-        // We do not really care about line number in expressional contexts.
+        // Expressions take the line of the statement they belong to, those embedded
+        // in an interpolated string the position of their code
         let line = self.current_line();
         self.instructions.push(instr);
         self.instruction_lines.push(line);
+        self.instruction_columns.push(self.column);
     }
 
     fn push_with_line(&mut self, instr: Instruction, line: usize) {
         self.instructions.push(instr);
         self.instruction_lines.push(line);
+        self.instruction_columns.push(self.column);
     }
 }
 
@@ -411,7 +1373,13 @@ impl fmt::Display for Instruction {
             Instruction::StoreVar(scope, idx) => write!(f, "STORE_VAR {} {}", scope, idx),
             Instruction::LoadVar(scope, idx) => write!(f, "LOAD_VAR {} {}", scope, idx),
             Instruction::LoadArg(idx) => write!(f, "LOAD_ARG {}", idx),
-            Instruction::Call(idx) => write!(f, "CALL {}", idx),
+            Instruction::LoadFunction(idx) => write!(f, "LOAD_FUNCTION {}", idx),
+            Instruction::Call(idx, argc) => write!(f, "CALL {} {}", idx, argc),
+            Instruction::TailCall(idx, argc) => write!(f, "TAIL_CALL {} {}", idx, argc),
+            Instruction::MakeGenerator => write!(f, "MAKE_GENERATOR"),
+            Instruction::Yield => write!(f, "YIELD"),
+            Instruction::CallValue(argc) => write!(f, "CALL_VALUE {}", argc),
+            Instruction::CallGlobal(idx, argc) => write!(f, "CALL_GLOBAL {} {}", idx, argc),
             Instruction::Return => write!(f, "RETURN"),
             Instruction::LoadConst(idx) => write!(f, "LOAD_CONST {}", idx),
             Instruction::Add => write!(f, "ADD"),
@@ -421,12 +1389,23 @@ impl fmt::Display for Instruction {
             Instruction::Equal => write!(f, "EQUAL"),
             Instruction::Less => write!(f, "LESS"),
             Instruction::Greater => write!(f, "GREATER"),
+            Instruction::LessEqual => write!(f, "LESS_EQUAL"),
+            Instruction::GreaterEqual => write!(f, "GREATER_EQUAL"),
             Instruction::Not => write!(f, "NOT"),
             Instruction::CreateArray(size) => write!(f, "CREATE_ARRAY {}", size),
             Instruction::ConcatArray => write!(f, "CONCAT_ARRAY"),
+            Instruction::CreateMap(size) => write!(f, "CREATE_MAP {}", size),
+            Instruction::Index => write!(f, "INDEX"),
+            Instruction::CheckType(expected, subject) => {
+                write!(f, "CHECK_TYPE {} {}", expected, subject)
+            }
+            Instruction::NoMatch => write!(f, "NO_MATCH"),
+            Instruction::GetField(idx) => write!(f, "GET_FIELD {}", idx),
+            Instruction::Pow => write!(f, "POW"),
             Instruction::Jump(addr) => write!(f, "JUMP {}", addr),
             Instruction::JumpIfFalse(addr) => write!(f, "JUMP_IF_FALSE {}", addr),
             Instruction::JumpIfTrue(addr) => write!(f, "JUMP_IF_TRUE {}", addr),
+            Instruction::IterNext(addr) => write!(f, "ITER_NEXT {}", addr),
             Instruction::Pop => write!(f, "POP"),
             Instruction::Dup => write!(f, "DUP"),
             Instruction::Halt => write!(f, "HALT"),
@@ -434,20 +1413,524 @@ impl fmt::Display for Instruction {
     }
 }
 
+/// Whether a function body yields, which makes the function a generator.
+/// Nested functions are generators of their own and are not looked into.
+fn body_yields(body: &[Stmt]) -> bool {
+    body.iter().any(|stmt| match stmt {
+        Stmt::Let { value, .. } => expr_yields(value),
+        Stmt::Expr(expr, _) => expr_yields(expr),
+        Stmt::Func { .. } | Stmt::Import { .. } => false,
+    })
+}
+
+fn expr_yields(expr: &Expr) -> bool {
+    match expr {
+        Expr::Yield { .. } => true,
+        Expr::Identifier(_) | Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => false,
+        Expr::Update { left, right }
+        | Expr::Binary { left, right, .. }
+        | Expr::Pipeline { left, right } => expr_yields(left) || expr_yields(right),
+        Expr::Index { object, index } => expr_yields(object) || expr_yields(index),
+        Expr::Unary { right, .. } => expr_yields(right),
+        Expr::Member { object, .. } => expr_yields(object),
+        Expr::Call { func, args } => expr_yields(func) || args.iter().any(expr_yields),
+        Expr::Array { elements } => elements.iter().any(expr_yields),
+        Expr::Map { entries } => entries
+            .iter()
+            .any(|(key, value)| expr_yields(key) || expr_yields(value)),
+        Expr::Match { value, arms } => {
+            expr_yields(value) || arms.iter().any(|arm| body_yields(&arm.body))
+        }
+        Expr::For { iterable, body, .. } => expr_yields(iterable) || body_yields(body),
+        Expr::Interpolation { parts } => parts
+            .iter()
+            .any(|part| matches!(part, InterpPart::Expr(expr, _) if expr_yields(expr))),
+    }
+}
+
+/// Depth first search for a cycle through `node`, `path` holds the nodes being
+/// visited and `done` those known not to lead into a cycle.
+fn find_cycle(
+    node: usize,
+    dependencies: &[Vec<usize>],
+    path: &mut Vec<usize>,
+    done: &mut [bool],
+) -> Option<Vec<usize>> {
+    if let Some(start) = path.iter().position(|&n| n == node) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(node);
+        return Some(cycle);
+    }
+    if done[node] {
+        return None;
+    }
+    path.push(node);
+    for &next in &dependencies[node] {
+        if let Some(cycle) = find_cycle(next, dependencies, path, done) {
+            return Some(cycle);
+        }
+    }
+    path.pop();
+    done[node] = true;
+    None
+}
+
+/// Collects the variables `expr` reads that aren't bound inside it, nested
+/// function bodies aside since they only run when called.
+fn referenced_names(expr: &Expr, bound: &mut Vec<String>, names: &mut Vec<String>) {
+    let mut visit = |expr: &Expr, bound: &mut Vec<String>| referenced_names(expr, bound, names);
+    match expr {
+        Expr::Identifier(name) => {
+            if !bound.contains(name) && !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => {}
+        Expr::Update { left, right }
+        | Expr::Binary { left, right, .. }
+        | Expr::Pipeline { left, right } => {
+            visit(left, bound);
+            visit(right, bound);
+        }
+        Expr::Index { object, index } => {
+            visit(object, bound);
+            visit(index, bound);
+        }
+        Expr::Unary { right, .. } => visit(right, bound),
+        Expr::Member { object, .. } => visit(object, bound),
+        Expr::Yield { value } => visit(value, bound),
+        Expr::Call { func, args } => {
+            visit(func, bound);
+            for arg in args {
+                visit(arg, bound);
+            }
+        }
+        Expr::Array { elements } => {
+            for element in elements {
+                visit(element, bound);
+            }
+        }
+        Expr::Map { entries } => {
+            for (key, value) in entries {
+                visit(key, bound);
+                visit(value, bound);
+            }
+        }
+        Expr::Interpolation { parts } => {
+            for part in parts {
+                if let InterpPart::Expr(expr, _) = part {
+                    visit(expr, bound);
+                }
+            }
+        }
+        Expr::Match { value, arms } => {
+            visit(value, bound);
+            for arm in arms {
+                let outer = bound.len();
+                bound.extend(arm.pattern.bindings());
+                block_names(&arm.body, bound, names);
+                bound.truncate(outer);
+            }
+        }
+        Expr::For {
+            name,
+            iterable,
+            body,
+        } => {
+            visit(iterable, bound);
+            let outer = bound.len();
+            bound.push(name.clone());
+            block_names(body, bound, names);
+            bound.truncate(outer);
+        }
+    }
+}
+
+fn block_names(body: &[Stmt], bound: &mut Vec<String>, names: &mut Vec<String>) {
+    let outer = bound.len();
+    for stmt in body {
+        match stmt {
+            Stmt::Let { name, value, .. } => {
+                referenced_names(value, bound, names);
+                bound.push(name.clone());
+            }
+            Stmt::Expr(expr, _) => referenced_names(expr, bound, names),
+            Stmt::Func { .. } | Stmt::Import { .. } => {}
+        }
+    }
+    bound.truncate(outer);
+}
+
+/// The path of `module` imported from a file in `directory`, both relative to the
+/// resolver's root, with `.` and `..` components resolved.
+fn module_path(directory: &str, module: &str) -> String {
+    let mut components: Vec<&str> = Vec::new();
+    let start = if module.starts_with('/') {
+        ""
+    } else {
+        directory
+    };
+    for component in start.split('/').chain(module.split('/')) {
+        match component {
+            "" | "." => {}
+            ".." if components.last().is_some_and(|last| *last != "..") => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    let path = components.join("/");
+    if module.starts_with('/') {
+        format!("/{}", path)
+    } else {
+        path
+    }
+}
+
+/// Names declared at the top level of `statements`.
+fn declared_names(statements: &[Stmt]) -> Vec<String> {
+    statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Let { name, .. } | Stmt::Func { name, .. } => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The top-level names of a module that importers can't use. A module without any
+/// `export` shares everything.
+fn private_names(statements: &[Stmt]) -> Vec<String> {
+    let exports_any = statements.iter().any(|stmt| {
+        matches!(
+            stmt,
+            Stmt::Let { exported: true, .. } | Stmt::Func { exported: true, .. }
+        )
+    });
+    if !exports_any {
+        return Vec::new();
+    }
+    statements
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Let {
+                name,
+                exported: false,
+                ..
+            }
+            | Stmt::Func {
+                name,
+                exported: false,
+                ..
+            } => Some(name.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Renames the top-level `names` of the module at `path` throughout its
+/// `statements` to `path::name`, which no source identifier can spell, so they
+/// can't collide with the importer's own names once linked into one program.
+fn mangle_names(statements: &mut [Stmt], path: &str, names: &[String]) {
+    let renames: HashMap<String, String> = names
+        .iter()
+        .map(|name| (name.clone(), format!("{}::{}", path, name)))
+        .collect();
+    for stmt in statements.iter_mut() {
+        if let Stmt::Let { name, .. } | Stmt::Func { name, .. } = stmt
+            && let Some(renamed) = renames.get(name)
+        {
+            *name = renamed.clone();
+        }
+    }
+    rename_in_block(statements, &renames, &mut Vec::new());
+}
+
+/// Applies `renames` to every identifier in `body` that isn't one of the `bound`
+/// names or declared locally, following the bodies of nested functions.
+fn rename_in_block(body: &mut [Stmt], renames: &HashMap<String, String>, bound: &mut Vec<String>) {
+    let outer = bound.len();
+    bound.extend(body.iter().filter_map(|stmt| match stmt {
+        Stmt::Func { name, .. } => Some(name.clone()),
+        _ => None,
+    }));
+    for stmt in body {
+        match stmt {
+            Stmt::Let { name, value, .. } => {
+                rename_in_expr(value, renames, bound);
+                bound.push(name.clone());
+            }
+            Stmt::Func { params, body, .. } => {
+                let scope = bound.len();
+                for param in params.iter() {
+                    match param {
+                        Param::Name(name) | Param::Typed(name, _) => bound.push(name.clone()),
+                        Param::Fields(fields) => bound.extend(fields.iter().cloned()),
+                    }
+                }
+                rename_in_block(body, renames, bound);
+                bound.truncate(scope);
+            }
+            Stmt::Expr(expr, _) => rename_in_expr(expr, renames, bound),
+            Stmt::Import { .. } => {}
+        }
+    }
+    bound.truncate(outer);
+}
+
+fn rename_in_expr(expr: &mut Expr, renames: &HashMap<String, String>, bound: &mut Vec<String>) {
+    match expr {
+        Expr::Identifier(name) => {
+            if !bound.contains(name)
+                && let Some(renamed) = renames.get(name)
+            {
+                *name = renamed.clone();
+            }
+        }
+        Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) => {}
+        Expr::Update { left, right }
+        | Expr::Binary { left, right, .. }
+        | Expr::Pipeline { left, right } => {
+            rename_in_expr(left, renames, bound);
+            rename_in_expr(right, renames, bound);
+        }
+        Expr::Index { object, index } => {
+            rename_in_expr(object, renames, bound);
+            rename_in_expr(index, renames, bound);
+        }
+        Expr::Unary { right, .. } => rename_in_expr(right, renames, bound),
+        Expr::Member { object, .. } => rename_in_expr(object, renames, bound),
+        Expr::Yield { value } => rename_in_expr(value, renames, bound),
+        Expr::Call { func, args } => {
+            rename_in_expr(func, renames, bound);
+            for arg in args {
+                rename_in_expr(arg, renames, bound);
+            }
+        }
+        Expr::Array { elements } => {
+            for element in elements {
+                rename_in_expr(element, renames, bound);
+            }
+        }
+        Expr::Map { entries } => {
+            for (key, value) in entries {
+                rename_in_expr(key, renames, bound);
+                rename_in_expr(value, renames, bound);
+            }
+        }
+        Expr::Interpolation { parts } => {
+            for part in parts {
+                if let InterpPart::Expr(expr, _) = part {
+                    rename_in_expr(expr, renames, bound);
+                }
+            }
+        }
+        Expr::Match { value, arms } => {
+            rename_in_expr(value, renames, bound);
+            for arm in arms {
+                let outer = bound.len();
+                bound.extend(arm.pattern.bindings());
+                rename_in_block(&mut arm.body, renames, bound);
+                bound.truncate(outer);
+            }
+        }
+        Expr::For {
+            name,
+            iterable,
+            body,
+        } => {
+            rename_in_expr(iterable, renames, bound);
+            let outer = bound.len();
+            bound.push(name.clone());
+            rename_in_block(body, renames, bound);
+            bound.truncate(outer);
+        }
+    }
+}
+
+/// Like `block_names`, but also follows the bodies of nested functions.
+fn free_names(body: &[Stmt], bound: &mut Vec<String>, names: &mut Vec<String>) {
+    let outer = bound.len();
+    // Functions are visible throughout the body declaring them
+    bound.extend(body.iter().filter_map(|stmt| match stmt {
+        Stmt::Func { name, .. } => Some(name.clone()),
+        _ => None,
+    }));
+    for stmt in body {
+        match stmt {
+            Stmt::Let { name, value, .. } => {
+                referenced_names(value, bound, names);
+                bound.push(name.clone());
+            }
+            Stmt::Func { params, body, .. } => {
+                let scope = bound.len();
+                for param in params {
+                    match param {
+                        Param::Name(name) | Param::Typed(name, _) => bound.push(name.clone()),
+                        Param::Fields(fields) => bound.extend(fields.iter().cloned()),
+                    }
+                }
+                free_names(body, bound, names);
+                bound.truncate(scope);
+            }
+            Stmt::Expr(expr, _) => referenced_names(expr, bound, names),
+            Stmt::Import { .. } => {}
+        }
+    }
+    bound.truncate(outer);
+}
+
+fn statement_line(stmt: &Stmt) -> usize {
+    match stmt {
+        Stmt::Let { line, .. }
+        | Stmt::Func { line, .. }
+        | Stmt::Import { line, .. }
+        | Stmt::Expr(_, line) => *line,
+    }
+}
+
+/// Evaluates arithmetic made only of number literals the way the VM would,
+/// `None` when it needs runtime values or would fail at runtime.
+fn fold_number(expr: &Expr) -> Option<f64> {
+    match expr {
+        Expr::Number(n) => Some(*n),
+        Expr::Unary {
+            op: UnaryOp::Neg,
+            right,
+        } => Some(0.0 - fold_number(right)?),
+        Expr::Binary { left, op, right } => {
+            let (a, b) = (fold_number(left)?, fold_number(right)?);
+            match op {
+                BinaryOp::Add => Some(a + b),
+                BinaryOp::Sub => Some(a - b),
+                BinaryOp::Mul => Some(a * b),
+                BinaryOp::Div if b != 0.0 => Some(a / b),
+                BinaryOp::Pow => Some(a.powf(b)),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Formats `n` the same way on every platform, with the fewest digits that read
+/// back as `n`, written out in full rather than with an exponent, and `-0` as `0`.
+/// Integral numbers end in `.0` only when `decimal_point` is set.
+pub fn format_float(n: f64, decimal_point: bool) -> String {
+    // `Display` for `f64` gives the shortest round-trip digits and, unlike `%g`
+    // in C, never switches to exponent notation for very large or small numbers
+    let text = if n == 0.0 { 0.0 } else { n }.to_string();
+    if decimal_point && n.is_finite() && !text.contains('.') {
+        format!("{}.0", text)
+    } else {
+        text
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::Number(n) => write!(f, "{}", n),
+            Value::Number(n) => write!(f, "{}", format_float(*n, false)),
             Value::String(s) => write!(f, "\"{}\"", s),
             Value::Boolean(b) => write!(f, "{}", b),
+            Value::Nil => write!(f, "nil"),
             Value::Function { params, offset } => {
                 write!(f, "fn({}) @{}", params.join(", "), offset)
             }
+            Value::Partial { function, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{} [{}]", function, args.join(", "))
+            }
+            Value::Memoized(idx) => write!(f, "memoized #{}", idx),
+            Value::Generator(idx) => write!(f, "generator #{}", idx),
             Value::HeapPointer(idx) => write!(f, "HEAP_POINTER {}", idx),
         }
     }
 }
 
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapKey::String(s) => write!(f, "{}", s),
+            MapKey::Number(bits) => write!(f, "{}", format_float(f64::from_bits(*bits), false)),
+            MapKey::Boolean(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+impl ByteCode {
+    /// Disassembly that only changes when the program's behavior does, for golden
+    /// tests. Constants are listed sorted by value and inlined where they are
+    /// loaded, builtins are named, and jumps and functions point at labels
+    /// instead of instruction offsets.
+    pub fn canonical(&self) -> String {
+        let mut targets: Vec<usize> = self
+            .instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                Instruction::Jump(addr)
+                | Instruction::JumpIfFalse(addr)
+                | Instruction::JumpIfTrue(addr)
+                | Instruction::IterNext(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        targets.sort_unstable();
+        targets.dedup();
+        let label = |addr: &usize| format!("L{}", targets.binary_search(addr).unwrap_or(0));
+
+        let mut output = String::from("constants:\n");
+        let mut constants: Vec<(String, usize)> = self
+            .constants
+            .iter()
+            .enumerate()
+            .map(|(i, constant)| (constant.to_string(), i))
+            .collect();
+        constants.sort();
+        for (constant, i) in constants {
+            output.push_str(&format!("  [{}] {}\n", i, constant));
+        }
+
+        output.push_str("code:\n");
+        for (i, instruction) in self.instructions.iter().enumerate() {
+            for (index, function) in self.functions.iter().enumerate() {
+                if let Value::Function { params, offset } = function
+                    && *offset == i
+                {
+                    output.push_str(&format!("F{}({}):\n", index, params.join(", ")));
+                }
+            }
+            if targets.binary_search(&i).is_ok() {
+                output.push_str(&format!("{}:\n", label(&i)));
+            }
+            let text = match instruction {
+                Instruction::LoadConst(idx) => match self.constants.get(*idx) {
+                    Some(constant) => format!("LOAD_CONST {}", constant),
+                    None => instruction.to_string(),
+                },
+                Instruction::GetField(idx) => match self.constants.get(*idx) {
+                    Some(constant) => format!("GET_FIELD {}", constant),
+                    None => instruction.to_string(),
+                },
+                Instruction::CallGlobal(idx, argc) => match builtins::BUILTINS.get(*idx) {
+                    Some(builtin) => format!("CALL_GLOBAL {} {}", builtin.name, argc),
+                    None => instruction.to_string(),
+                },
+                Instruction::Call(idx, argc) => format!("CALL F{} {}", idx, argc),
+                Instruction::TailCall(idx, argc) => format!("TAIL_CALL F{} {}", idx, argc),
+                Instruction::LoadFunction(idx) => format!("LOAD_FUNCTION F{}", idx),
+                Instruction::Jump(addr) => format!("JUMP {}", label(addr)),
+                Instruction::JumpIfFalse(addr) => format!("JUMP_IF_FALSE {}", label(addr)),
+                Instruction::JumpIfTrue(addr) => format!("JUMP_IF_TRUE {}", label(addr)),
+                Instruction::IterNext(addr) => format!("ITER_NEXT {}", label(addr)),
+                _ => instruction.to_string(),
+            };
+            output.push_str(&format!("  {}\n", text));
+        }
+        output
+    }
+}
+
 impl fmt::Display for ByteCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "=== BYTECODE ===")?;
@@ -15,6 +15,7 @@ pub fn print_token_summary(tokens: &[Token]) {
         let token_type: &str = match token {
             Token::Identifier(_) => "Identifier",
             Token::String(_) => "String",
+            Token::Interpolated(_) => "Interpolated",
             Token::Number(_) => "Number",
             Token::True => "True",
             Token::False => "False",
@@ -24,15 +25,20 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::Fn => "Fn",
             Token::Match => "Match",
             Token::Import => "Import",
+            Token::Export => "Export",
             Token::Enum => "Enum",
             Token::If => "If",
             Token::Else => "Else",
             Token::Return => "Return",
             Token::Async => "Async",
             Token::Await => "Await",
+            Token::For => "For",
+            Token::In => "In",
+            Token::Yield => "Yield",
             Token::Plus => "Plus",
             Token::Minus => "Minus",
             Token::Multiply => "Multiply",
+            Token::Power => "Power",
             Token::Divide => "Divide",
             Token::Modulo => "Modulo",
             Token::Equal => "Equal",
@@ -46,7 +52,9 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::Or => "Or",
             Token::Not => "Not",
             Token::Pipeline => "Pipeline",
+            Token::Bar => "Bar",
             Token::Update => "Update",
+            Token::Colon => "Colon",
             Token::DoubleColon => "DoubleColon",
             Token::LeftParen => "LeftParen",
             Token::RightParen => "RightParen",
@@ -56,9 +64,14 @@ pub fn print_token_summary(tokens: &[Token]) {
             Token::RightBracket => "RightBracket",
             Token::Comma => "Comma",
             Token::Dot => "Dot",
+            Token::DotDot => "DotDot",
+            Token::DocComment(_) => "DocComment",
+            Token::ModuleDoc(_) => "ModuleDoc",
+            Token::DotDotEqual => "DotDotEqual",
             Token::Arrow => "Arrow",
             Token::FatArrow => "FatArrow",
             Token::Hash => "Hash",
+            Token::Error(_) => "Error",
             Token::Newline => "Newline",
             Token::Eof => "Eof",
         };
@@ -0,0 +1,57 @@
+use crate::types::ast::{Param, Program, Stmt};
+
+/// Renders a markdown overview of the top-level declarations in `program`,
+/// each with its signature and `///` comment, after the file's `//!` comment.
+pub fn render(program: &Program) -> String {
+    let mut sections = Vec::new();
+    if let Some(doc) = &program.doc {
+        sections.push(doc.clone());
+    }
+
+    for stmt in &program.statements {
+        let (signature, doc) = match stmt {
+            Stmt::Func {
+                name,
+                params,
+                doc,
+                returns,
+                ..
+            } => {
+                let params: Vec<String> = params
+                    .iter()
+                    .map(|param| match param {
+                        Param::Typed(name, annotation) => format!("{}: {}", name, annotation),
+                        _ => param.name(),
+                    })
+                    .collect();
+                let returns = returns
+                    .as_ref()
+                    .map_or(String::new(), |t| format!(" -> {}", t));
+                (
+                    format!("func {}({}){}", name, params.join(", "), returns),
+                    doc,
+                )
+            }
+            Stmt::Let {
+                name,
+                doc,
+                annotation,
+                ..
+            } => {
+                let annotation = annotation
+                    .as_ref()
+                    .map_or(String::new(), |t| format!(": {}", t));
+                (format!("let {}{}", name, annotation), doc)
+            }
+            Stmt::Import { .. } | Stmt::Expr(..) => continue,
+        };
+        match doc {
+            Some(doc) => sections.push(format!("### {}\n\n{}", signature, doc)),
+            None => sections.push(format!("### {}", signature)),
+        }
+    }
+
+    let mut output = sections.join("\n\n");
+    output.push('\n');
+    output
+}
@@ -1,5 +1,6 @@
-use crate::compiler::Compiler;
-use crate::types::compiler::{ByteCode, HeapObject, Instruction, Value};
+use crate::builtins::BUILTINS;
+use crate::compiler::{Compiler, format_float};
+use crate::types::compiler::{ByteCode, HeapObject, Instruction, MapKey, ObjectMap, Value};
 use crate::types::constants::{
     GC_CHECK_INTERVAL, GC_HISTORY_BUFFER_SIZE, GC_THRESHOLD, HEAP_SCORE_ARRAY_BASE,
     HEAP_SCORE_ARRAY_PER_ELEMENT, HEAP_SCORE_MAP_BASE, HEAP_SCORE_MAP_PER_ELEMENT,
@@ -7,13 +8,20 @@ use crate::types::constants::{
     UNDERFLOW_ERROR,
 };
 use crate::types::traits::IntoResult;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead};
 
 #[derive(Debug, Clone)]
 pub struct StackFrame {
     variables: Vec<Value>,
 }
 
+impl Default for StackFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl StackFrame {
     pub fn new() -> Self {
         Self {
@@ -33,50 +41,187 @@ impl StackFrame {
     }
 }
 
+/// Cache behind a `memoize` wrapper, results are keyed by the call's arguments.
+struct MemoTable {
+    function: Value,
+    cache: HashMap<Vec<MapKey>, HeapObject>,
+    calls: usize, // Times the wrapped function actually ran
+}
+
+/// A generator call suspended at its last `yield`, or not started yet.
+struct GeneratorState {
+    frame: Option<StackFrame>, // None once the body has returned, or while it runs
+    pc: usize,                 // Where the body resumes
+    stack: Vec<Value>,         // Operands the body had pushed when it yielded
+}
+
+/// Callback that receives each line printed by the program.
+pub type PrintHook = Box<dyn FnMut(&str)>;
+
 pub struct VirtualMachine {
     stack: Vec<Value>,
     stack_frames: Vec<StackFrame>,
     return_addresses: Vec<usize>,
     pc: usize,
     constants: Vec<Value>,
+    field_keys: Vec<Option<MapKey>>, // Map key of each string constant, for GET_FIELD
     functions: Vec<Value>,
     instructions: Vec<Instruction>,
     instruction_lines: Vec<usize>,
+    instruction_columns: Vec<usize>,
     heap: Vec<HeapObject>,
     last_heap_score: VecDeque<usize>,
     raw_compiler: Compiler,
+    memo_tables: Vec<MemoTable>,
+    generators: Vec<GeneratorState>,
+    resuming: Vec<(usize, usize)>, // Running generators and where their operands start
+    float_precision: Option<usize>, // Significant digits when formatting numbers
+    decimal_point: bool,           // Whether integral numbers format as `3.0`
+    copy_on_assign: bool,          // Bindings and arguments get their own copy of collections
+    print_hook: Option<PrintHook>, // Receives `print` output instead of stdout
+    input: Option<Box<dyn BufRead>>, // Where `IO.readline` reads from instead of stdin
+    env: Option<HashMap<String, String>>, // Seen by `Env.get` instead of the process environment
+    args: Vec<String>,             // Command-line arguments given to the script
+    instruction_limit: Option<usize>, // Instructions a run may execute before it is aborted
+    executed: usize,               // Instructions executed so far in this run
+    next_heap_check: usize,        // Value of `executed` at which the heap is next checked
+    call_site: usize,              // Position of the instruction that entered the running function
+    heap_limit: Option<usize>,     // Most bytes, by heap score, live objects may take up
 }
 
 impl VirtualMachine {
+    /// Prepares `bytecode` to run. `compiler` is the one that produced it, its
+    /// scopes let `global` look variables up by name and name them in errors.
     pub fn new(bytecode: ByteCode, compiler: Compiler) -> Self {
-        let vm = Self {
+        Self {
             stack: Vec::new(),
             stack_frames: vec![StackFrame::new()],
             return_addresses: Vec::new(),
             pc: 0,
             raw_compiler: compiler,
+            field_keys: field_keys(&bytecode.constants),
             constants: bytecode.constants,
             functions: bytecode.functions,
             instructions: bytecode.instructions,
             instruction_lines: bytecode.instruction_lines,
+            instruction_columns: bytecode.instruction_columns,
             heap: Vec::new(),
             last_heap_score: VecDeque::new(),
-        };
-        vm
+            memo_tables: Vec::new(),
+            generators: Vec::new(),
+            resuming: Vec::new(),
+            float_precision: None,
+            decimal_point: false,
+            copy_on_assign: false,
+            print_hook: None,
+            input: None,
+            env: None,
+            args: Vec::new(),
+            instruction_limit: None,
+            executed: 0,
+            next_heap_check: 0,
+            call_site: 0,
+            heap_limit: None,
+        }
+    }
+
+    /// Gives every binding and argument its own deep copy of arrays, sets and objects
+    /// instead of sharing the caller's heap object.
+    pub fn with_copy_on_assign(mut self) -> Self {
+        self.copy_on_assign = true;
+        self
+    }
+
+    /// Sends every line the program prints to `hook` instead of stdout.
+    pub fn on_print(&mut self, hook: PrintHook) {
+        self.print_hook = Some(hook);
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str) {
+        match self.print_hook.as_mut() {
+            Some(hook) => hook(line),
+            None => println!("{}", line),
+        }
+    }
+
+    /// Makes `IO.readline` read from `input` instead of stdin.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
+    }
+
+    /// Reads the next line of input without its line ending, `None` at the end.
+    pub(crate) fn read_line(&mut self) -> Result<Option<String>, String> {
+        let mut line = String::new();
+        let read = match self.input.as_mut() {
+            Some(input) => input.read_line(&mut line),
+            None => io::stdin().lock().read_line(&mut line),
+        }
+        .map_err(|e| format!("Cannot read input: {}", e))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        let trimmed = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(trimmed);
+        Ok(Some(line))
+    }
+
+    /// Makes `Env.get` look variables up in `env` instead of the process environment.
+    pub fn set_env(&mut self, env: HashMap<String, String>) {
+        self.env = Some(env);
+    }
+
+    /// Sets the command-line arguments `Env.args` gives the script.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.args = args;
+    }
+
+    pub(crate) fn env_var(&self, name: &str) -> Option<String> {
+        match &self.env {
+            Some(env) => env.get(name).cloned(),
+            None => std::env::var(name).ok(),
+        }
+    }
+
+    pub(crate) fn args(&self) -> &[String] {
+        &self.args
+    }
+
+    /// Aborts a run with an error once it has executed `limit` instructions,
+    /// so untrusted scripts can't loop forever.
+    pub fn with_instruction_limit(mut self, limit: usize) -> Self {
+        self.instruction_limit = Some(limit);
+        self
+    }
+
+    /// Aborts a run with an out-of-memory error when its live objects take up
+    /// more than `bytes`, as estimated by the collector's heap score.
+    pub fn with_heap_limit(mut self, bytes: usize) -> Self {
+        self.heap_limit = Some(bytes);
+        self
+    }
+
+    /// Formats numbers with at most `digits` significant digits when printing them,
+    /// by default numbers print with as many digits as it takes to read them back.
+    pub fn with_float_precision(mut self, digits: usize) -> Self {
+        self.float_precision = Some(digits.max(1));
+        self
+    }
+
+    /// Formats integral numbers with a trailing `.0`, `3.0` instead of `3`, for
+    /// hosts whose output has to tell them apart from integers.
+    pub fn with_decimal_point(mut self) -> Self {
+        self.decimal_point = true;
+        self
     }
 
     fn gc(&mut self) {
-        // Mark phase: Find all live objects by tracing from stack variables
+        // Mark phase: Find all live objects by tracing from variables and operands
         let mut marked = vec![false; self.heap.len()];
-        for frame in &self.stack_frames {
-            for value in &frame.variables {
-                if let Value::HeapPointer(idx) = value {
-                    if *idx < marked.len() {
-                        marked[*idx] = true;
-                    }
-                }
+        self.for_each_pointer(|idx| {
+            if let Some(mark) = marked.get_mut(*idx) {
+                *mark = true;
             }
-        }
+        });
 
         // Sweep phase: Build new compacted heap and create index mapping
         let mut new_heap = Vec::with_capacity(self.heap.len());
@@ -89,22 +234,58 @@ impl VirtualMachine {
         }
 
         // Update phase: Fix all heap pointer references to use new indices
-        for frame in &mut self.stack_frames {
-            for value in &mut frame.variables {
-                if let Value::HeapPointer(idx) = value {
-                    if *idx < remap.len() {
-                        if let Some(new_idx) = remap[*idx] {
-                            *value = Value::HeapPointer(new_idx);
-                        }
-                    }
-                }
+        self.for_each_pointer(|idx| {
+            if let Some(Some(new_idx)) = remap.get(*idx) {
+                *idx = *new_idx;
             }
-        }
+        });
 
         // Replace old heap with compacted heap
         self.heap = new_heap;
     }
 
+    /// Visits every heap pointer the program can still reach: variables of running
+    /// and suspended calls, their operands, and values captured by partials and memos.
+    fn for_each_pointer(&mut self, mut visit: impl FnMut(&mut usize)) {
+        let variables = self
+            .stack_frames
+            .iter_mut()
+            .flat_map(|frame| frame.variables.iter_mut());
+        let suspended = self.generators.iter_mut().flat_map(|generator| {
+            let variables = generator
+                .frame
+                .iter_mut()
+                .flat_map(|f| f.variables.iter_mut());
+            variables.chain(generator.stack.iter_mut())
+        });
+        let memos = self.memo_tables.iter_mut().map(|table| &mut table.function);
+        let values = variables
+            .chain(self.stack.iter_mut())
+            .chain(suspended)
+            .chain(memos);
+        for value in values {
+            visit_pointers(value, &mut visit);
+        }
+    }
+
+    /// Runs the collector when the heap has grown past the GC threshold or the
+    /// heap limit, failing if the live objects alone are over the limit.
+    fn check_heap(&mut self) -> Result<(), String> {
+        let mut heap_score = self.heap_score();
+        let over_limit = |score: usize| self.heap_limit.is_some_and(|limit| score > limit);
+        if heap_score >= GC_THRESHOLD || over_limit(heap_score) {
+            self.gc();
+            heap_score = self.heap_score();
+        }
+        match self.heap_limit {
+            Some(limit) if heap_score > limit => Err(format!(
+                "Out of memory: the heap holds {} bytes, more than the limit of {}",
+                heap_score, limit
+            )),
+            _ => Ok(()),
+        }
+    }
+
     fn heap_score(&mut self) -> usize {
         let mut heap_score: usize = 0;
         for obj in &self.heap {
@@ -112,6 +293,9 @@ impl VirtualMachine {
                 HeapObject::Array(arr) => {
                     heap_score += HEAP_SCORE_ARRAY_BASE + arr.len() * HEAP_SCORE_ARRAY_PER_ELEMENT;
                 }
+                HeapObject::Set(set) => {
+                    heap_score += HEAP_SCORE_ARRAY_BASE + set.len() * HEAP_SCORE_ARRAY_PER_ELEMENT;
+                }
                 HeapObject::String(s) => {
                     heap_score += HEAP_SCORE_STRING_BASE + s.len();
                 }
@@ -130,20 +314,72 @@ impl VirtualMachine {
         heap_score
     }
 
+    /// Clears everything a run leaves behind, the stack, call frames, heap and
+    /// generators, so the program can run again from the start. Host settings
+    /// such as the print hook, input, environment and formatting options stay,
+    /// and the heap keeps its allocation for the next run.
+    pub fn reset(&mut self) {
+        self.stack.clear();
+        self.stack_frames.clear();
+        self.stack_frames.push(StackFrame::new());
+        self.return_addresses.clear();
+        self.pc = 0;
+        self.heap.clear();
+        self.last_heap_score.clear();
+        self.memo_tables.clear();
+        self.generators.clear();
+        self.resuming.clear();
+        self.executed = 0;
+        self.next_heap_check = 0;
+        self.call_site = 0;
+    }
+
+    /// Resets the VM and swaps in another program, for hosts that run many
+    /// scripts on one long-lived VM.
+    pub fn load(&mut self, bytecode: ByteCode, compiler: Compiler) {
+        self.reset();
+        self.raw_compiler = compiler;
+        self.field_keys = field_keys(&bytecode.constants);
+        self.constants = bytecode.constants;
+        self.functions = bytecode.functions;
+        self.instructions = bytecode.instructions;
+        self.instruction_lines = bytecode.instruction_lines;
+        self.instruction_columns = bytecode.instruction_columns;
+    }
+
+    /// Runs already compiled `bytecode` on a fresh VM, so a program compiled once
+    /// can run any number of times without going back to its source. Without the
+    /// compiler `global` can't resolve names, read the outcome from `result`.
+    pub fn run_program(bytecode: ByteCode) -> Result<Self, String> {
+        let mut vm = Self::new(bytecode, Compiler::new());
+        vm.run()?;
+        Ok(vm)
+    }
+
+    /// Executes the program from the current instruction until it halts or fails.
     pub fn run(&mut self) -> Result<(), String> {
         while self.pc < self.instructions.len() {
-            if (self.pc + 1) % GC_CHECK_INTERVAL == 0 {
-                let heap_score = self.heap_score();
-                if heap_score >= GC_THRESHOLD {
-                    self.gc();
+            // Counted in instructions run, a loop over a few positions still gets checked
+            if self.executed >= self.next_heap_check {
+                self.next_heap_check = self.executed + GC_CHECK_INTERVAL;
+                if let Err(e) = self.check_heap() {
+                    return Err(format!("[{}] {}", self.location(self.pc), e));
                 }
             }
             match &self.instructions[self.pc] {
                 Instruction::Halt => break,
                 _ => {
+                    let pc = self.pc;
                     if let Err(e) = self.execute_instruction() {
-                        let line = self.instruction_lines.get(self.pc).cloned().unwrap_or(0);
-                        return Err(format!("[line {}] {}", line, e));
+                        let location = self.location(pc);
+                        // Well-formed bytecode never underflows, so name the culprit
+                        if e == UNDERFLOW_ERROR {
+                            return Err(format!(
+                                "[{}] {} at instruction {} ({})",
+                                location, e, pc, self.instructions[pc]
+                            ));
+                        }
+                        return Err(format!("[{}] {}", location, e));
                     }
                 }
             }
@@ -151,7 +387,24 @@ impl VirtualMachine {
         Ok(())
     }
 
+    /// Where the instruction at `pc` came from, for error messages. The column is
+    /// only known for code embedded in interpolated strings.
+    fn location(&self, pc: usize) -> String {
+        let line = self.instruction_lines.get(pc).cloned().unwrap_or(0);
+        match self.instruction_columns.get(pc) {
+            Some(&column) if column > 0 => format!("line {}, column {}", line, column),
+            _ => format!("line {}", line),
+        }
+    }
+
     fn execute_instruction(&mut self) -> Result<(), String> {
+        // Counted here so calls from native code and generator resumes count too
+        self.executed += 1;
+        if let Some(limit) = self.instruction_limit
+            && self.executed > limit
+        {
+            return Err(format!("Instruction limit of {} reached", limit));
+        }
         match &self.instructions[self.pc].clone() {
             Instruction::Push(value) => {
                 self.stack.push(value.clone());
@@ -199,31 +452,27 @@ impl VirtualMachine {
                         let result = format!("{}{}", a_str, b_str);
                         self.stack.push(Value::String(result));
                     }
-                    _ => {
-                        return Err(format!(
-                            "Cannot add {} and {} - both operands must be the same type",
-                            a.type_name(&self.heap),
-                            b.type_name(&self.heap)
-                        ));
-                    }
+                    _ => return Err(self.operand_error("+", &a, &b)),
                 }
             }
 
             Instruction::Sub => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
+                let (a, b) = self.pop_numbers("-")?;
                 self.stack.push(Value::Number(a - b));
             }
 
             Instruction::Mul => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
+                let (a, b) = self.pop_numbers("*")?;
                 self.stack.push(Value::Number(a * b));
             }
 
+            Instruction::Pow => {
+                let (a, b) = self.pop_numbers("**")?;
+                self.stack.push(Value::Number(a.powf(b)));
+            }
+
             Instruction::Div => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
+                let (a, b) = self.pop_numbers("/")?;
                 if b == 0.0 {
                     return Err("Division by zero".to_string());
                 }
@@ -235,22 +484,28 @@ impl VirtualMachine {
                 let b: Value = self.stack.pop().ok_or(STACK_UNDERFLOW)?;
                 let a: Value = self.stack.pop().ok_or(STACK_UNDERFLOW)?;
                 let result = self.values_equal(&a, &b);
-                self.stack
-                    .push(Value::Boolean(if result { true } else { false }));
+                self.stack.push(Value::Boolean(result));
             }
 
             Instruction::Less => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
-                self.stack
-                    .push(Value::Boolean(if a < b { true } else { false }));
+                let (a, b) = self.pop_ordered()?;
+                self.stack.push(Value::Boolean(a < b));
             }
 
             Instruction::Greater => {
-                let b: f64 = self.pop_value()?;
-                let a: f64 = self.pop_value()?;
-                self.stack
-                    .push(Value::Boolean(if a > b { true } else { false }));
+                let (a, b) = self.pop_ordered()?;
+                self.stack.push(Value::Boolean(a > b));
+            }
+
+            // Not the negation of GREATER and LESS, which would make NaN comparisons true
+            Instruction::LessEqual => {
+                let (a, b) = self.pop_ordered()?;
+                self.stack.push(Value::Boolean(a <= b));
+            }
+
+            Instruction::GreaterEqual => {
+                let (a, b) = self.pop_ordered()?;
+                self.stack.push(Value::Boolean(a >= b));
             }
 
             Instruction::Not => {
@@ -282,6 +537,82 @@ impl VirtualMachine {
                 self.stack.push(Value::HeapPointer(heap_index));
             }
 
+            Instruction::CreateMap(size) => {
+                let mut entries = Vec::with_capacity(*size);
+                for _ in 0..*size {
+                    let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                    let key = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                    entries.push((self.map_key(&key)?, self.value_to_heap_object(value)));
+                }
+                entries.reverse();
+
+                // Later duplicates overwrite earlier entries
+                let map: ObjectMap = entries.into_iter().collect();
+                let value = self.alloc(HeapObject::Object(map));
+                self.stack.push(value);
+            }
+
+            Instruction::Index => {
+                let index = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let collection = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let element = self.index(&collection, &index)?;
+                let value = self.heap_object_to_value(element);
+                self.stack.push(value);
+            }
+
+            Instruction::GetField(id) => {
+                let collection = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let field = match (&collection, self.field_keys.get(*id)) {
+                    (Value::HeapPointer(idx), Some(Some(key))) => match self.heap.get(*idx) {
+                        Some(HeapObject::Object(map)) => map.get(key).cloned(),
+                        _ => None,
+                    },
+                    _ => None,
+                };
+                // Anything but a present field goes the slow way, for INDEX's errors
+                let element = match field {
+                    Some(element) => element,
+                    None => {
+                        let name = self.constants.get(*id).ok_or("Invalid constant index")?;
+                        self.index(&collection, &name.clone())?
+                    }
+                };
+                let value = self.heap_object_to_value(element);
+                self.stack.push(value);
+            }
+
+            Instruction::CheckType(expected, subject) => {
+                let value = self.stack.last().ok_or(UNDERFLOW_ERROR)?;
+                let actual = self.type_name(value);
+                // Annotations say `bool` and `map` where values report `boolean` and `object`
+                let matches = match expected.as_str() {
+                    "bool" => actual == "boolean",
+                    "map" => actual == "object",
+                    other => actual == other,
+                };
+                if !matches {
+                    let mut message =
+                        format!("Expected {} to be {}, got {}", subject, expected, actual);
+                    // Parameters are checked on entry, at the declaration, so also name the call
+                    if subject.starts_with("parameter ") {
+                        let line = self
+                            .instruction_lines
+                            .get(self.call_site)
+                            .cloned()
+                            .unwrap_or(0);
+                        message.push_str(&format!(", in the call at line {}", line));
+                    }
+                    return Err(message);
+                }
+            }
+
+            Instruction::NoMatch => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                // Strings are quoted so that `"1"` and `1` read differently
+                let value = self.format_heap_object(&self.value_to_heap_object(value), true);
+                return Err(format!("No arm matched value {}", value));
+            }
+
             Instruction::ConcatArray => {
                 let right = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
                 let left = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
@@ -297,8 +628,8 @@ impl VirtualMachine {
                     }
                 };
 
-                let left_arr = self.heap.get(left_idx).ok_or(INVALID_HEAP_POINTER_ERROR)?;
-                let right_arr = self.heap.get(right_idx).ok_or(INVALID_HEAP_POINTER_ERROR)?;
+                let left_arr = self.heap_object(left_idx, "CONCAT_ARRAY")?;
+                let right_arr = self.heap_object(right_idx, "CONCAT_ARRAY")?;
 
                 match (left_arr, right_arr) {
                     (HeapObject::Array(left_vec), HeapObject::Array(right_vec)) => {
@@ -322,7 +653,7 @@ impl VirtualMachine {
 
             Instruction::JumpIfFalse(addr) => {
                 let value: bool = self.pop_value()?;
-                if value == false {
+                if !value {
                     self.pc = *addr;
                     return Ok(());
                 }
@@ -330,29 +661,79 @@ impl VirtualMachine {
 
             Instruction::JumpIfTrue(addr) => {
                 let value: bool = self.pop_value()?;
-                if value == true {
+                if value {
                     self.pc = *addr;
                     return Ok(());
                 }
             }
 
-            Instruction::Call(func_index) => {
+            Instruction::LoadFunction(func_index) => {
                 let function = self
                     .functions
                     .get(*func_index)
-                    .ok_or("Invalid function index")?;
-
-                if let Value::Function { offset, .. } = function {
-                    self.return_addresses.push(self.pc + 1);
+                    .ok_or("Invalid function index")?
+                    .clone();
+                self.stack.push(function);
+            }
 
-                    let new_frame = StackFrame::new();
-                    self.stack_frames.push(new_frame);
+            Instruction::Call(func_index, arg_count) => {
+                let function = self
+                    .functions
+                    .get(*func_index)
+                    .ok_or("Invalid function index")?
+                    .clone();
+                return self.call_function(function, *arg_count);
+            }
 
-                    self.pc = *offset;
+            Instruction::TailCall(func_index, arg_count) => {
+                let function = self
+                    .functions
+                    .get(*func_index)
+                    .ok_or("Invalid function index")?
+                    .clone();
+                // A full application replaces the caller's frame, its return address is reused
+                if let Value::Function { ref params, offset } = function
+                    && *arg_count == params.len()
+                {
+                    self.stack_frames.pop();
+                    self.stack_frames.push(StackFrame::new());
+                    self.call_site = self.pc;
+                    self.pc = offset;
                     return Ok(());
-                } else {
-                    return Err("Invalid function value".to_string());
                 }
+                return self.call_function(function, *arg_count);
+            }
+
+            Instruction::CallValue(arg_count) => {
+                let function = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                return self.call_function(function, *arg_count);
+            }
+
+            Instruction::CallGlobal(builtin_index, arg_count) => {
+                let builtin = BUILTINS
+                    .get(*builtin_index)
+                    .ok_or("Invalid builtin index")?;
+                if *arg_count < builtin.min_args || *arg_count > builtin.max_args {
+                    return Err(if builtin.min_args == builtin.max_args {
+                        format!(
+                            "{} expects {} arguments, got {}",
+                            builtin.name, builtin.min_args, arg_count
+                        )
+                    } else if builtin.max_args == usize::MAX {
+                        format!(
+                            "{} expects at least {} arguments, got {}",
+                            builtin.name, builtin.min_args, arg_count
+                        )
+                    } else {
+                        format!(
+                            "{} expects {} to {} arguments, got {}",
+                            builtin.name, builtin.min_args, builtin.max_args, arg_count
+                        )
+                    });
+                }
+                let args = self.pop_args(*arg_count)?;
+                let result = (builtin.function)(self, args)?;
+                self.stack.push(result);
             }
 
             Instruction::Return => {
@@ -368,6 +749,54 @@ impl VirtualMachine {
                 }
             }
 
+            Instruction::MakeGenerator => {
+                let frame = self.stack_frames.pop().ok_or("No stack frame available")?;
+                self.generators.push(GeneratorState {
+                    frame: Some(frame),
+                    pc: self.pc + 1,
+                    stack: Vec::new(),
+                });
+                self.stack.push(Value::Generator(self.generators.len() - 1));
+                self.pc = self
+                    .return_addresses
+                    .pop()
+                    .ok_or("No return address available")?;
+                return Ok(());
+            }
+
+            Instruction::Yield => {
+                let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                let (id, base) = *self
+                    .resuming
+                    .last()
+                    .ok_or("yield outside of a running generator")?;
+                let frame = self.stack_frames.pop().ok_or("No stack frame available")?;
+                let stack = self.stack.split_off(base);
+                let generator = &mut self.generators[id];
+                generator.frame = Some(frame);
+                generator.pc = self.pc + 1;
+                generator.stack = stack;
+
+                self.stack.push(value);
+                self.pc = self
+                    .return_addresses
+                    .pop()
+                    .ok_or("No return address available")?;
+                return Ok(());
+            }
+
+            Instruction::IterNext(exit) => {
+                let position: f64 = self.pop_value()?;
+                let iterable = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+                match self.next_element(&iterable, position as usize)? {
+                    Some(element) => self.stack.push(element),
+                    None => {
+                        self.pc = *exit;
+                        return Ok(());
+                    }
+                }
+            }
+
             Instruction::Pop => {
                 self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
             }
@@ -386,11 +815,467 @@ impl VirtualMachine {
         Ok(())
     }
 
-    fn resolve_variable(&self, depth: usize, var_index: usize) -> Result<Value, String> {
-        for frame in self.stack_frames.iter().rev() {
-            if let Some(value) = frame.get_variable(var_index) {
-                return Ok(value.clone());
+    fn call_function(&mut self, function: Value, arg_count: usize) -> Result<(), String> {
+        match function {
+            Value::Function { ref params, offset } => {
+                if arg_count < params.len() {
+                    // Under-application binds the supplied arguments for a later call
+                    let args = self.pop_args(arg_count)?;
+                    self.stack.push(Value::Partial {
+                        function: Box::new(function),
+                        args,
+                    });
+                    self.pc += 1;
+                    return Ok(());
+                }
+                if arg_count > params.len() {
+                    return Err(format!(
+                        "Function expects {} arguments, got {}",
+                        params.len(),
+                        arg_count
+                    ));
+                }
+
+                self.return_addresses.push(self.pc + 1);
+                self.stack_frames.push(StackFrame::new());
+                self.call_site = self.pc;
+                self.pc = offset;
+                Ok(())
+            }
+            Value::Partial { function, args } => {
+                let new_args = self.pop_args(arg_count)?;
+                let total = args.len() + new_args.len();
+                self.stack.extend(args);
+                self.stack.extend(new_args);
+                self.call_function(*function, total)
+            }
+            Value::Memoized(table) => {
+                let args = self.pop_args(arg_count)?;
+                let result = self.call_memoized(table, args)?;
+                self.stack.push(result);
+                self.pc += 1;
+                Ok(())
+            }
+            other => Err(format!(
+                "value of type {} is not callable",
+                self.type_name(&other)
+            )),
+        }
+    }
+
+    fn pop_args(&mut self, arg_count: usize) -> Result<Vec<Value>, String> {
+        if self.stack.len() < arg_count {
+            return Err("Not enough arguments".to_string());
+        }
+        Ok(self.stack.split_off(self.stack.len() - arg_count))
+    }
+
+    /// Wraps `function` so that calls with the same arguments reuse the first result.
+    pub fn memoize(&mut self, function: Value) -> Result<Value, String> {
+        if !matches!(
+            function,
+            Value::Function { .. } | Value::Partial { .. } | Value::Memoized(_)
+        ) {
+            return Err(format!(
+                "memoize expects a function, got {}",
+                self.type_name(&function)
+            ));
+        }
+        self.memo_tables.push(MemoTable {
+            function,
+            cache: HashMap::new(),
+            calls: 0,
+        });
+        Ok(Value::Memoized(self.memo_tables.len() - 1))
+    }
+
+    /// How many times the function behind a `memoize` wrapper has actually run.
+    pub fn memoized_calls(&self, value: &Value) -> Option<usize> {
+        match value {
+            Value::Memoized(table) => self.memo_tables.get(*table).map(|memo| memo.calls),
+            _ => None,
+        }
+    }
+
+    fn call_memoized(&mut self, table: usize, args: Vec<Value>) -> Result<Value, String> {
+        let key = args
+            .iter()
+            .map(|arg| self.map_key(arg))
+            .collect::<Result<Vec<MapKey>, String>>()?;
+        let memo = self
+            .memo_tables
+            .get(table)
+            .ok_or("Invalid memoized function")?;
+        if let Some(cached) = memo.cache.get(&key).cloned() {
+            return Ok(self.heap_object_to_value(cached));
+        }
+
+        let result = self.call_value(memo.function.clone(), args)?;
+        let cacheable = !matches!(
+            result,
+            Value::Function { .. }
+                | Value::Partial { .. }
+                | Value::Memoized(_)
+                | Value::Generator(_)
+        );
+        let object = self.value_to_heap_object(result.clone());
+        let memo = &mut self.memo_tables[table];
+        memo.calls += 1;
+        if cacheable {
+            memo.cache.insert(key, object);
+        }
+        Ok(result)
+    }
+
+    /// Calls `function` with `args` from native code, running the VM until the
+    /// call returns and handing back its result.
+    pub fn call_value(&mut self, function: Value, args: Vec<Value>) -> Result<Value, String> {
+        let resume_pc = self.pc;
+        let call_depth = self.return_addresses.len();
+        let arg_count = args.len();
+        self.stack.extend(args);
+        self.call_function(function, arg_count)?;
+
+        while self.return_addresses.len() > call_depth {
+            if self.pc >= self.instructions.len() {
+                return Err("Function call ran past the end of the program".to_string());
+            }
+            self.execute_instruction()?;
+        }
+
+        self.pc = resume_pc;
+        self.stack.pop().ok_or(UNDERFLOW_ERROR.to_string())
+    }
+
+    /// Calls `function` like `call_value`, but a failure unwinds the VM to where it
+    /// was before the call, so the host can go on making calls. The error names the
+    /// line that failed.
+    pub fn try_call(&mut self, function: Value, args: Vec<Value>) -> Result<Value, String> {
+        let (pc, stack, frames) = (self.pc, self.stack.len(), self.stack_frames.len());
+        let (calls, resuming) = (self.return_addresses.len(), self.resuming.len());
+        self.call_value(function, args).map_err(|e| {
+            let location = self.location(self.pc);
+            self.pc = pc;
+            self.stack.truncate(stack);
+            self.stack_frames.truncate(frames);
+            self.return_addresses.truncate(calls);
+            self.resuming.truncate(resuming);
+            format!("[{}] {}", location, e)
+        })
+    }
+
+    /// Element `position` of an array or set, or the next value of a generator,
+    /// `None` once there are no more.
+    fn next_element(&mut self, iterable: &Value, position: usize) -> Result<Option<Value>, String> {
+        let not_iterable = || format!("type {} is not iterable", self.type_name(iterable));
+        let element = match iterable {
+            Value::Generator(id) => return self.resume_generator(*id),
+            Value::HeapPointer(idx) => match self.heap_object(*idx, "ITER_NEXT")? {
+                HeapObject::Array(elements) => elements.get(position),
+                HeapObject::Set(set) => set.get_index(position).map(|(_, element)| element),
+                _ => return Err(not_iterable()),
+            },
+            _ => return Err(not_iterable()),
+        };
+        let element = element.cloned();
+        Ok(element.map(|element| self.heap_object_to_value(element)))
+    }
+
+    /// Runs generator `id` from where it was suspended until it yields, giving the
+    /// yielded value, or until its body returns, which finishes it.
+    fn resume_generator(&mut self, id: usize) -> Result<Option<Value>, String> {
+        let generator = self.generators.get_mut(id).ok_or("Invalid generator")?;
+        let Some(frame) = generator.frame.take() else {
+            return Ok(None);
+        };
+        let pc = generator.pc;
+        let saved = std::mem::take(&mut generator.stack);
+
+        let resume_pc = self.pc;
+        let call_depth = self.return_addresses.len();
+        self.return_addresses.push(resume_pc);
+        self.stack_frames.push(frame);
+        self.resuming.push((id, self.stack.len()));
+        self.stack.extend(saved);
+        self.stack.push(Value::Nil); // Value of the `yield` being resumed
+        self.pc = pc;
+
+        while self.return_addresses.len() > call_depth {
+            if self.pc >= self.instructions.len() {
+                return Err("Generator ran past the end of the program".to_string());
+            }
+            self.execute_instruction()?;
+        }
+
+        self.resuming.pop();
+        self.pc = resume_pc;
+        let value = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+        // A yield puts the frame back, a body that returned leaves it empty
+        Ok(self.generators[id].frame.is_some().then_some(value))
+    }
+
+    pub fn type_name(&self, value: &Value) -> &'static str {
+        value.type_name(&self.heap)
+    }
+
+    pub fn alloc(&mut self, object: HeapObject) -> Value {
+        self.heap.push(object);
+        Value::HeapPointer(self.heap.len() - 1)
+    }
+
+    /// Replaces the collection `value` points to with `object` in place, so every
+    /// binding that shares it sees the change.
+    pub fn replace_object(&mut self, value: &Value, object: HeapObject) -> Result<(), String> {
+        match value {
+            Value::HeapPointer(idx) if *idx < self.heap.len() => {
+                self.heap[*idx] = object;
+                Ok(())
+            }
+            other => Err(format!(
+                "Expected a collection, got {}",
+                self.type_name(other)
+            )),
+        }
+    }
+
+    /// The object `idx` points to, an error naming the pointer and `operation`
+    /// when it points past the heap, which only a VM or compiler bug can cause.
+    pub(crate) fn heap_object(&self, idx: usize, operation: &str) -> Result<&HeapObject, String> {
+        self.heap.get(idx).ok_or_else(|| {
+            format!(
+                "{} {} during {} (heap holds {} objects)",
+                INVALID_HEAP_POINTER_ERROR,
+                idx,
+                operation,
+                self.heap.len()
+            )
+        })
+    }
+
+    pub fn array_elements(&self, value: &Value, context: &str) -> Result<Vec<HeapObject>, String> {
+        match value {
+            Value::HeapPointer(idx) => match self.heap_object(*idx, context)? {
+                HeapObject::Array(elements) => Ok(elements.clone()),
+                _ => Err(format!(
+                    "{} expects an array, got {}",
+                    context,
+                    self.type_name(value)
+                )),
+            },
+            _ => Err(format!(
+                "{} expects an array, got {}",
+                context,
+                self.type_name(value)
+            )),
+        }
+    }
+
+    pub fn as_set(&self, value: &Value, context: &str) -> Result<ObjectMap, String> {
+        match value {
+            Value::HeapPointer(idx) => match self.heap_object(*idx, context)? {
+                HeapObject::Set(set) => Ok(set.clone()),
+                _ => Err(format!(
+                    "{} expects a set, got {}",
+                    context,
+                    self.type_name(value)
+                )),
+            },
+            _ => Err(format!(
+                "{} expects a set, got {}",
+                context,
+                self.type_name(value)
+            )),
+        }
+    }
+
+    pub fn heap_object_to_value(&mut self, object: HeapObject) -> Value {
+        match object {
+            HeapObject::Number(n) => Value::Number(n),
+            HeapObject::String(s) => Value::String(s),
+            HeapObject::Boolean(b) => Value::Boolean(b),
+            HeapObject::Null => Value::Nil,
+            object => self.alloc(object),
+        }
+    }
+
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::String(s) => s.clone(),
+            Value::Number(n) => self.format_number(*n),
+            Value::HeapPointer(idx) => match self.heap_object(*idx, "formatting") {
+                Ok(object) => self.format_heap_object(object, false),
+                Err(e) => e,
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    pub fn format_number(&self, n: f64) -> String {
+        let n = match self.float_precision {
+            // Rounding through scientific notation keeps the digits significant, not decimal
+            Some(digits) if n.is_finite() => {
+                format!("{:.*e}", digits - 1, n).parse::<f64>().unwrap_or(n)
             }
+            _ => n,
+        };
+        format_float(n, self.decimal_point)
+    }
+
+    pub fn format_heap_object(&self, object: &HeapObject, nested: bool) -> String {
+        match object {
+            HeapObject::String(s) if nested => format!("\"{}\"", s),
+            HeapObject::String(s) => s.clone(),
+            HeapObject::Number(n) => self.format_number(*n),
+            HeapObject::Boolean(b) => b.to_string(),
+            HeapObject::Null => "nil".to_string(),
+            HeapObject::Array(elements) => {
+                let elements: Vec<String> = elements
+                    .iter()
+                    .map(|element| self.format_heap_object(element, true))
+                    .collect();
+                format!("[{}]", elements.join(", "))
+            }
+            HeapObject::Set(set) => {
+                let elements: Vec<String> = set
+                    .values()
+                    .map(|element| self.format_heap_object(element, true))
+                    .collect();
+                format!("Set {{ {} }}", elements.join(", "))
+            }
+            HeapObject::Object(map) => {
+                let entries: Vec<String> = map
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{} = {}", key, self.format_heap_object(value, true))
+                    })
+                    .collect();
+                format!("{{ {} }}", entries.join(", "))
+            }
+        }
+    }
+
+    /// Resolves `value` to the object it stands for, reading through heap
+    /// pointers and lifting stack scalars to their heap representation.
+    pub fn to_host(&self, value: &Value) -> Result<HeapObject, String> {
+        match value {
+            Value::HeapPointer(idx) => self
+                .heap_object(*idx, "conversion to a host value")
+                .cloned(),
+            Value::Function { .. } | Value::Partial { .. } | Value::Memoized(_) => {
+                Err("Functions cannot be converted to host values".to_string())
+            }
+            Value::Generator(_) => Err("Generators cannot be converted to host values".to_string()),
+            _ => Ok(self.value_to_heap_object(value.clone())),
+        }
+    }
+
+    pub fn as_number(&self, value: &Value) -> Result<f64, String> {
+        self.to_host(value)?
+            .as_number()
+            .ok_or_else(|| format!("Expected number, got {}", self.type_name(value)))
+    }
+
+    pub fn as_string(&self, value: &Value) -> Result<String, String> {
+        self.to_host(value)?
+            .as_string()
+            .map(str::to_string)
+            .ok_or_else(|| format!("Expected string, got {}", self.type_name(value)))
+    }
+
+    pub fn as_bool(&self, value: &Value) -> Result<bool, String> {
+        self.to_host(value)?
+            .as_bool()
+            .ok_or_else(|| format!("Expected boolean, got {}", self.type_name(value)))
+    }
+
+    pub fn as_array(&self, value: &Value) -> Result<Vec<HeapObject>, String> {
+        self.to_host(value)?
+            .as_array()
+            .map(<[HeapObject]>::to_vec)
+            .ok_or_else(|| format!("Expected array, got {}", self.type_name(value)))
+    }
+
+    pub fn as_map(&self, value: &Value) -> Result<ObjectMap, String> {
+        self.to_host(value)?
+            .as_map()
+            .cloned()
+            .ok_or_else(|| format!("Expected object, got {}", self.type_name(value)))
+    }
+
+    /// Converts `value` to its canonical map key.
+    pub fn map_key(&self, value: &Value) -> Result<MapKey, String> {
+        match value {
+            Value::Function { .. }
+            | Value::Partial { .. }
+            | Value::Memoized(_)
+            | Value::Generator(_) => Err(format!(
+                "Map keys must be strings, numbers or booleans, got {}",
+                self.type_name(value)
+            )),
+            _ => MapKey::from_heap_object(&self.to_host(value)?),
+        }
+    }
+
+    fn index(&self, collection: &Value, index: &Value) -> Result<HeapObject, String> {
+        let object = match collection {
+            Value::HeapPointer(idx) => self.heap_object(*idx, "INDEX")?,
+            _ => {
+                return Err(format!(
+                    "type {} is not indexable",
+                    self.type_name(collection)
+                ));
+            }
+        };
+
+        match object {
+            HeapObject::Array(elements) => {
+                let position = match self.to_host(index)? {
+                    HeapObject::Number(n) => n,
+                    _ => {
+                        return Err(format!(
+                            "Array index must be a number, got {}",
+                            self.type_name(index)
+                        ));
+                    }
+                };
+                if position.fract() != 0.0 || position < 0.0 || position >= elements.len() as f64 {
+                    return Err(format!(
+                        "Index {} out of bounds for array of length {}",
+                        position,
+                        elements.len()
+                    ));
+                }
+                Ok(elements[position as usize].clone())
+            }
+            HeapObject::Object(map) => {
+                let key = self.map_key(index)?;
+                map.get(&key)
+                    .cloned()
+                    .ok_or_else(|| format!("Key '{}' not found in object", key))
+            }
+            other => Err(format!("type {} is not indexable", other.type_name())),
+        }
+    }
+
+    pub fn global(&self, name: &str) -> Option<Value> {
+        let index = self.raw_compiler.variables.first()?.get(name)?;
+        self.stack_frames.first()?.get_variable(*index).cloned()
+    }
+
+    /// The program's value, left on the stack by its last top-level expression.
+    pub fn result(&self) -> Value {
+        self.stack.last().cloned().unwrap_or(Value::Nil)
+    }
+
+    fn resolve_variable(&self, depth: usize, var_index: usize) -> Result<Value, String> {
+        // Globals live in the bottom frame, anything deeper in the running function's frame
+        let frame = if depth == 0 {
+            self.stack_frames.first()
+        } else {
+            self.stack_frames.last()
+        };
+        if let Some(value) = frame.and_then(|frame| frame.get_variable(var_index)) {
+            return Ok(value.clone());
         }
         if let Some(scope) = self.raw_compiler.variables.get(depth) {
             for (name, idx) in scope.iter() {
@@ -415,7 +1300,7 @@ impl VirtualMachine {
             _ => None,
         };
 
-        heap_index.map(|index| Value::HeapPointer(index))
+        heap_index.map(Value::HeapPointer)
     }
 
     fn set_variable(&mut self, var_index: usize, value: Value) -> Result<(), String> {
@@ -423,6 +1308,14 @@ impl VirtualMachine {
             Some(heap_pointer) => heap_pointer,
             None => value,
         };
+        let final_value = match final_value {
+            // Heap objects own their elements, so cloning one copies it all the way down
+            Value::HeapPointer(idx) if self.copy_on_assign => {
+                let object = self.heap_object(idx, "STORE_VAR")?.clone();
+                self.alloc(object)
+            }
+            value => value,
+        };
 
         let current_frame = self
             .stack_frames
@@ -443,11 +1336,76 @@ impl VirtualMachine {
         }
     }
 
-    fn values_equal(&self, a: &Value, b: &Value) -> bool {
+    /// Pops the operands of the arithmetic `operator`, which only applies to numbers.
+    fn pop_numbers(&mut self, operator: &str) -> Result<(f64, f64), String> {
+        let b = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+        let a = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+        match (&a, &b) {
+            (Value::Number(a), Value::Number(b)) => Ok((*a, *b)),
+            _ => Err(self.operand_error(operator, &a, &b)),
+        }
+    }
+
+    fn operand_error(&self, operator: &str, a: &Value, b: &Value) -> String {
+        format!(
+            "Cannot apply '{}' to {} and {}",
+            operator,
+            a.type_name(&self.heap),
+            b.type_name(&self.heap)
+        )
+    }
+
+    /// Pops the operands of `<`, `>`, `<=` or `>=`. Only numbers are ordered, lists and
+    /// maps have no ordering and are rejected rather than compared by some rule.
+    fn pop_ordered(&mut self) -> Result<(f64, f64), String> {
+        let b = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+        let a = self.stack.pop().ok_or(UNDERFLOW_ERROR)?;
+        match (&a, &b) {
+            (Value::Number(a), Value::Number(b)) => Ok((*a, *b)),
+            _ => Err(format!(
+                "Cannot order {} and {} - only numbers can be compared with <, >, <= and >=",
+                a.type_name(&self.heap),
+                b.type_name(&self.heap)
+            )),
+        }
+    }
+
+    /// `==` never fails: values of different types are unequal, collections compare
+    /// by structure and functions by identity.
+    pub fn values_equal(&self, a: &Value, b: &Value) -> bool {
         match (a, b) {
-            (Value::Number(x), Value::Number(y)) => x == y,
-            (Value::String(x), Value::String(y)) => x == y,
-            _ => false,
+            (Value::Function { offset: x, .. }, Value::Function { offset: y, .. }) => x == y,
+            (
+                Value::Partial { function, args },
+                Value::Partial {
+                    function: other_function,
+                    args: other_args,
+                },
+            ) => {
+                self.values_equal(function, other_function)
+                    && args.len() == other_args.len()
+                    && args
+                        .iter()
+                        .zip(other_args)
+                        .all(|(x, y)| self.values_equal(x, y))
+            }
+            (Value::Memoized(x), Value::Memoized(y)) => x == y,
+            (Value::Generator(x), Value::Generator(y)) => x == y,
+            (
+                Value::Function { .. }
+                | Value::Partial { .. }
+                | Value::Memoized(_)
+                | Value::Generator(_),
+                _,
+            )
+            | (
+                _,
+                Value::Function { .. }
+                | Value::Partial { .. }
+                | Value::Memoized(_)
+                | Value::Generator(_),
+            ) => false,
+            _ => self.value_to_heap_object(a.clone()) == self.value_to_heap_object(b.clone()),
         }
     }
 
@@ -464,13 +1422,43 @@ impl VirtualMachine {
         println!("================");
     }
 
-    fn value_to_heap_object(&self, value: Value) -> HeapObject {
+    pub fn value_to_heap_object(&self, value: Value) -> HeapObject {
         match value {
             Value::Number(n) => HeapObject::Number(n),
             Value::String(s) => HeapObject::String(s),
             Value::Boolean(b) => HeapObject::Boolean(b),
-            Value::HeapPointer(_) => HeapObject::Null, // Could preserve references, but simplify for now
-            Value::Function { .. } => HeapObject::Null, // Functions can't go in arrays yet
+            Value::Nil => HeapObject::Null,
+            // Heap values are immutable, so nesting copies the referenced object
+            Value::HeapPointer(idx) => self.heap.get(idx).cloned().unwrap_or(HeapObject::Null),
+            Value::Function { .. }
+            | Value::Partial { .. }
+            | Value::Memoized(_)
+            | Value::Generator(_) => HeapObject::Null, // Functions can't go in arrays yet
+        }
+    }
+}
+
+/// Map keys for the string constants, so GET_FIELD finds fields without
+/// converting its name on every access.
+fn field_keys(constants: &[Value]) -> Vec<Option<MapKey>> {
+    constants
+        .iter()
+        .map(|constant| match constant {
+            Value::String(name) => Some(MapKey::String(name.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+fn visit_pointers(value: &mut Value, visit: &mut impl FnMut(&mut usize)) {
+    match value {
+        Value::HeapPointer(idx) => visit(idx),
+        Value::Partial { function, args } => {
+            visit_pointers(function, visit);
+            for arg in args {
+                visit_pointers(arg, visit);
+            }
         }
+        _ => {}
     }
 }
@@ -1,34 +1,90 @@
-use crate::types::token::Token;
+use crate::types::diagnostic::Span;
+use crate::types::token::{Segment, Token};
+use std::ops::Range;
+
+/// Lexes `source` to completion, returning every token up to and including the
+/// terminating `Eof`.
+pub fn tokenize(source: &str) -> Vec<Token> {
+    Lexer::new(source.to_string()).tokenize()
+}
+
+/// Removes the indentation shared by every non-blank line of `text`. A leading
+/// newline and a trailing whitespace-only line are dropped so block strings can
+/// open and close on their own lines.
+pub fn dedent(text: &str) -> String {
+    let text = text.strip_prefix('\n').unwrap_or(text);
+    let mut lines: Vec<&str> = text.split('\n').collect();
+    if lines.len() > 1 && lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches([' ', '\t']).len())
+        .min()
+        .unwrap_or(0);
+
+    lines
+        .iter()
+        .map(|line| {
+            let strip = indent.min(line.len() - line.trim_start_matches([' ', '\t']).len());
+            &line[strip..]
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
 
 pub struct Lexer {
-    input: String,
+    chars: Vec<char>, // The input, indexed by character position
     position: usize,
     current_char: Option<char>,
+    token_start: usize, // Position of the first character of the last token
+    disabled_keywords: Vec<String>, // Keywords lexed as plain identifiers instead
 }
 
 impl Lexer {
     pub fn new(input: String) -> Self {
-        let mut lexer = Lexer {
-            input,
+        let chars: Vec<char> = input.chars().collect();
+        Lexer {
+            current_char: chars.first().copied(),
+            chars,
             position: 0,
-            current_char: None,
-        };
-        lexer.current_char = lexer.input.chars().nth(0);
-        lexer
+            token_start: 0,
+            disabled_keywords: Vec::new(),
+        }
+    }
+
+    /// Lexes each of `keywords` as an ordinary identifier, so a program can use
+    /// names like `match` or `async` for its own variables and functions. The
+    /// syntax the keyword introduces is then unavailable in that program.
+    pub fn without_keywords(mut self, keywords: &[&str]) -> Self {
+        self.disabled_keywords
+            .extend(keywords.iter().map(|keyword| keyword.to_string()));
+        self
     }
 
     fn advance(&mut self) {
-        self.position += 1;
-        self.current_char = self.input.chars().nth(self.position);
+        self.seek(self.position + 1);
+    }
+
+    /// Moves straight to the character at `position`.
+    fn seek(&mut self, position: usize) {
+        self.position = position;
+        self.current_char = self.chars.get(position).copied();
     }
 
     fn peek(&self) -> Option<char> {
-        self.input.chars().nth(self.position + 1)
+        self.chars.get(self.position + 1).copied()
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.chars.get(self.position + 2).copied()
     }
 
     fn skip_whitespace(&mut self) {
         while let Some(ch) = self.current_char {
-            if ch.is_whitespace() && ch != '\n' {
+            if ch.is_whitespace() && ch != '\n' && !(ch == '\r' && self.peek() == Some('\n')) {
                 self.advance();
             } else {
                 break;
@@ -36,26 +92,228 @@ impl Lexer {
         }
     }
 
-    fn read_string(&mut self) -> String {
+    fn read_string(&mut self) -> Option<String> {
         let mut value = String::new();
+        let mut first_newline = None;
         self.advance(); // skip opening quote
 
         while let Some(ch) = self.current_char {
             if ch == '"' {
                 self.advance(); // skip closing quote
-                break;
+                return Some(value);
+            }
+            if ch == '\n' && first_newline.is_none() {
+                first_newline = Some(self.position);
+            }
+            // Normalize CRLF so Windows sources produce the same strings as Unix ones
+            if ch == '\r' && self.peek() == Some('\n') {
+                self.advance();
+                continue;
             }
             value.push(ch);
             self.advance();
         }
 
-        value
+        // Unterminated: resume lexing at the line after the opening quote so the
+        // rest of the file is still tokenized
+        if let Some(position) = first_newline {
+            self.seek(position);
+        }
+        None
+    }
+
+    /// Reads a `$"..."` string starting at the `$`. Each `{...}` is lexed on its own
+    /// into a code segment, `{{` and `}}` stand for literal braces.
+    fn read_interpolated(&mut self) -> Option<Vec<Segment>> {
+        let mut segments = Vec::new();
+        let mut text = String::new();
+        let mut first_newline = None;
+        self.advance(); // skip `$`
+        self.advance(); // skip opening quote
+
+        while let Some(ch) = self.current_char {
+            match ch {
+                '"' => {
+                    self.advance(); // skip closing quote
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(text));
+                    }
+                    return Some(segments);
+                }
+                '{' | '}' if self.peek() == Some(ch) => {
+                    text.push(ch);
+                    self.advance();
+                    self.advance();
+                }
+                '{' => {
+                    if !text.is_empty() {
+                        segments.push(Segment::Text(std::mem::take(&mut text)));
+                    }
+                    self.advance(); // skip `{`
+                    let start = self.position;
+                    let Some(code) = self.read_embedded_code() else {
+                        // Recover like an unterminated string, at the first line break
+                        // in the string or else the first one after the `{`
+                        let newline = first_newline.or_else(|| {
+                            self.chars[start..]
+                                .iter()
+                                .position(|ch| *ch == '\n')
+                                .map(|offset| start + offset)
+                        });
+                        if let Some(position) = newline {
+                            self.seek(position);
+                        }
+                        return None;
+                    };
+                    let mut lexer = Lexer::new(code);
+                    lexer.disabled_keywords = self.disabled_keywords.clone();
+                    let (tokens, spans) = lexer.tokenize_with_spans();
+                    // Positions are shifted into the whole source here, `lex_spans`
+                    // fills in the lines and columns
+                    let spans = spans
+                        .iter()
+                        .map(|span| Span {
+                            start: span.start + start,
+                            end: span.end + start,
+                            line: 0,
+                            column: 0,
+                        })
+                        .collect();
+                    segments.push(Segment::Code(tokens, spans));
+                }
+                _ => {
+                    if ch == '\n' && first_newline.is_none() {
+                        first_newline = Some(self.position);
+                    }
+                    if !(ch == '\r' && self.peek() == Some('\n')) {
+                        text.push(ch);
+                    }
+                    self.advance();
+                }
+            }
+        }
+
+        if let Some(position) = first_newline {
+            self.seek(position);
+        }
+        None
+    }
+
+    /// Reads the source of an embedded expression up to its closing `}`, which is
+    /// skipped. Braces in between must balance, quoted strings are taken whole.
+    fn read_embedded_code(&mut self) -> Option<String> {
+        let mut code = String::new();
+        self.copy_code(&mut code).then_some(code)
+    }
+
+    /// Copies characters into `code` until the `}` that closes the embedded
+    /// expression, false when the input ends first.
+    fn copy_code(&mut self, code: &mut String) -> bool {
+        let mut depth = 0;
+        while let Some(ch) = self.current_char {
+            match ch {
+                '"' => {
+                    if !self.copy_string(code) {
+                        return false;
+                    }
+                    continue;
+                }
+                '$' if self.peek() == Some('"') => {
+                    if !self.copy_string(code) {
+                        return false;
+                    }
+                    continue;
+                }
+                '{' => depth += 1,
+                '}' if depth == 0 => {
+                    self.advance();
+                    return true;
+                }
+                '}' => depth -= 1,
+                _ => {}
+            }
+            code.push(ch);
+            self.advance();
+        }
+        false
+    }
+
+    /// Copies a plain, block or interpolated string literal into `code`, so the
+    /// braces and quotes inside it don't count towards the enclosing expression.
+    fn copy_string(&mut self, code: &mut String) -> bool {
+        let interpolated = self.current_char == Some('$');
+        if interpolated {
+            self.copy_char(code);
+        }
+        let block = !interpolated && self.peek() == Some('"') && self.peek_second() == Some('"');
+        let quotes = if block { 3 } else { 1 };
+        for _ in 0..quotes {
+            self.copy_char(code);
+        }
+
+        while let Some(ch) = self.current_char {
+            if ch == '"'
+                && (!block || (self.peek() == Some('"') && self.peek_second() == Some('"')))
+            {
+                for _ in 0..quotes {
+                    self.copy_char(code);
+                }
+                return true;
+            }
+            if interpolated && ch == '{' && self.peek() != Some('{') {
+                self.copy_char(code);
+                if !self.copy_code(code) {
+                    return false;
+                }
+                code.push('}');
+                continue;
+            }
+            if interpolated && ch == '{' {
+                self.copy_char(code); // the first of `{{`
+            }
+            self.copy_char(code);
+        }
+        false
+    }
+
+    fn copy_char(&mut self, code: &mut String) {
+        if let Some(ch) = self.current_char {
+            code.push(ch);
+        }
+        self.advance();
+    }
+
+    fn read_block_string(&mut self) -> Option<String> {
+        let mut value = String::new();
+        for _ in 0..3 {
+            self.advance(); // skip opening quotes
+        }
+
+        while let Some(ch) = self.current_char {
+            if ch == '"' && self.peek() == Some('"') && self.peek_second() == Some('"') {
+                for _ in 0..3 {
+                    self.advance(); // skip closing quotes
+                }
+                return Some(dedent(&value));
+            }
+            if ch == '\r' && self.peek() == Some('\n') {
+                self.advance();
+                continue;
+            }
+            value.push(ch);
+            self.advance();
+        }
+
+        None
     }
 
     fn read_number(&mut self) -> f64 {
         let mut value = String::new();
 
         while let Some(ch) = self.current_char {
+            if ch == '.' && self.peek() == Some('.') {
+                break; // `1..5` is a range, not the number `1.`
+            }
             if ch.is_ascii_digit() || ch == '.' {
                 value.push(ch);
                 self.advance();
@@ -79,6 +337,13 @@ impl Lexer {
             }
         }
 
+        // A single trailing `?` or `!` names predicates and bang functions like
+        // `empty?` and `sort!`, unless it starts `!=`
+        if matches!(self.current_char, Some('?') | Some('!')) && self.peek() != Some('=') {
+            value.push(self.current_char.unwrap());
+            self.advance();
+        }
+
         value
     }
 
@@ -91,7 +356,7 @@ impl Lexer {
             self.advance(); // skip second /
 
             while let Some(ch) = self.current_char {
-                if ch == '\n' {
+                if ch == '\n' || (ch == '\r' && self.peek() == Some('\n')) {
                     break;
                 }
                 comment.push(ch);
@@ -118,9 +383,17 @@ impl Lexer {
 
     pub fn next_token(&mut self) -> Token {
         loop {
+            self.token_start = self.position;
             match self.current_char {
                 None => return Token::Eof,
 
+                // A CRLF line ending is one newline, starting where LF alone would
+                Some('\r') if self.peek() == Some('\n') => {
+                    self.advance();
+                    self.advance();
+                    return Token::Newline;
+                }
+
                 Some(ch) if ch.is_whitespace() && ch != '\n' => {
                     self.skip_whitespace();
                     continue;
@@ -131,9 +404,25 @@ impl Lexer {
                     return Token::Newline;
                 }
 
+                Some('"') if self.peek() == Some('"') && self.peek_second() == Some('"') => {
+                    return match self.read_block_string() {
+                        Some(string_value) => Token::String(string_value),
+                        None => Token::Error("Unterminated block string".to_string()),
+                    };
+                }
+
+                Some('$') if self.peek() == Some('"') => {
+                    return match self.read_interpolated() {
+                        Some(segments) => Token::Interpolated(segments),
+                        None => Token::Error("Unterminated string".to_string()),
+                    };
+                }
+
                 Some('"') => {
-                    let string_value = self.read_string();
-                    return Token::String(string_value);
+                    return match self.read_string() {
+                        Some(string_value) => Token::String(string_value),
+                        None => Token::Error("Unterminated string".to_string()),
+                    };
                 }
 
                 Some(ch) if ch.is_ascii_digit() => {
@@ -143,25 +432,26 @@ impl Lexer {
 
                 Some(ch) if ch.is_alphabetic() || ch == '_' => {
                     let identifier = self.read_identifier();
+                    if self.disabled_keywords.contains(&identifier) {
+                        return Token::Identifier(identifier);
+                    }
                     return match identifier.as_str() {
-                        "let" => {
-                            if self.current_char == Some('!') {
-                                self.advance();
-                                Token::LetBang
-                            } else {
-                                Token::Let
-                            }
-                        }
+                        "let" => Token::Let,
+                        "let!" => Token::LetBang,
                         "func" => Token::Func,
                         "fn" => Token::Fn,
                         "match" => Token::Match,
                         "import" => Token::Import,
+                        "export" => Token::Export,
                         "enum" => Token::Enum,
                         "if" => Token::If,
                         "else" => Token::Else,
                         "return" => Token::Return,
                         "async" => Token::Async,
                         "await" => Token::Await,
+                        "for" => Token::For,
+                        "in" => Token::In,
+                        "yield" => Token::Yield,
                         "true" => Token::True,
                         "false" => Token::False,
                         _ => Token::Identifier(identifier),
@@ -169,8 +459,34 @@ impl Lexer {
                 }
 
                 Some('/') if self.peek() == Some('/') || self.peek() == Some('*') => {
-                    self.read_comment();
-                    continue; // Skip comments entirely
+                    let line_comment = self.peek() == Some('/');
+                    let starts_line = self.chars[..self.position]
+                        .iter()
+                        .rev()
+                        .take_while(|ch| **ch != '\n')
+                        .all(|ch| ch.is_whitespace());
+                    let comment = self.read_comment();
+                    if line_comment && starts_line {
+                        // `///` documents the next declaration and `//!` the whole file,
+                        // `////` and longer runs of slashes, or either after code on the
+                        // same line, are plain comments
+                        let doc = |text: &str| {
+                            text.strip_prefix(' ')
+                                .unwrap_or(text)
+                                .trim_end()
+                                .to_string()
+                        };
+                        match comment.strip_prefix('/') {
+                            Some(text) if !text.starts_with('/') => {
+                                return Token::DocComment(doc(text));
+                            }
+                            _ => {}
+                        }
+                        if let Some(text) = comment.strip_prefix('!') {
+                            return Token::ModuleDoc(doc(text));
+                        }
+                    }
+                    continue; // Skip other comments entirely
                 }
 
                 Some(ch) => {
@@ -185,7 +501,13 @@ impl Lexer {
                                 return Token::Minus;
                             }
                         }
-                        '*' => return Token::Multiply,
+                        '*' => {
+                            if self.current_char == Some('*') {
+                                self.advance();
+                                return Token::Power;
+                            }
+                            return Token::Multiply;
+                        }
                         '/' => return Token::Divide,
                         '%' => return Token::Modulo,
                         '=' => {
@@ -242,7 +564,7 @@ impl Lexer {
                                 self.advance();
                                 return Token::Pipeline;
                             } else {
-                                continue; // Skip single |
+                                return Token::Bar;
                             }
                         }
                         ':' => {
@@ -250,7 +572,7 @@ impl Lexer {
                                 self.advance();
                                 return Token::DoubleColon;
                             } else {
-                                continue; // Skip single :
+                                return Token::Colon;
                             }
                         }
                         '(' => return Token::LeftParen,
@@ -260,7 +582,17 @@ impl Lexer {
                         '[' => return Token::LeftBracket,
                         ']' => return Token::RightBracket,
                         ',' => return Token::Comma,
-                        '.' => return Token::Dot,
+                        '.' => {
+                            if self.current_char == Some('.') {
+                                self.advance();
+                                if self.current_char == Some('=') {
+                                    self.advance();
+                                    return Token::DotDotEqual;
+                                }
+                                return Token::DotDot;
+                            }
+                            return Token::Dot;
+                        }
                         '#' => return Token::Hash,
                         _ => continue, // Skip unknown characters
                     }
@@ -270,18 +602,208 @@ impl Lexer {
     }
 
     pub fn tokenize(&mut self) -> Vec<Token> {
+        self.tokenize_with_spans().0
+    }
+
+    /// Lexes to completion like `tokenize`, also returning the span of every token.
+    pub fn tokenize_with_spans(&mut self) -> (Vec<Token>, Vec<Span>) {
+        self.lex_spans(1, |_, _| false)
+    }
+
+    /// Lexes from the current position, which starts `line`, until the input ends
+    /// or `stop` accepts the token just lexed.
+    fn lex_spans(
+        &mut self,
+        mut line: usize,
+        mut stop: impl FnMut(&Token, &Span) -> bool,
+    ) -> (Vec<Token>, Vec<Span>) {
         let mut tokens = Vec::new();
+        let mut spans = Vec::new();
+        let (mut column, mut scanned) = (1, self.position);
 
         loop {
             let token = self.next_token();
             let is_eof = matches!(token, Token::Eof);
+
+            // Tokens start in increasing order, so the location can be counted forward
+            for ch in &self.chars[scanned..self.token_start] {
+                if *ch == '\n' {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+            }
+            scanned = self.token_start;
+
+            let span = Span {
+                start: self.token_start,
+                end: self.position.max(self.token_start),
+                line,
+                column,
+            };
+            let token = match token {
+                Token::Interpolated(segments) => {
+                    Token::Interpolated(locate_segments(segments, &self.chars, &span))
+                }
+                token => token,
+            };
+            let stopped = stop(&token, &span);
             tokens.push(token);
+            spans.push(span);
 
-            if is_eof {
+            if is_eof || stopped {
                 break;
             }
         }
 
-        tokens
+        (tokens, spans)
+    }
+}
+
+/// Fills in the lines and columns of the tokens embedded in an interpolated
+/// string, counting forward from the string's own `span`.
+fn locate_segments(segments: Vec<Segment>, chars: &[char], span: &Span) -> Vec<Segment> {
+    let (mut line, mut column, mut scanned) = (span.line, span.column, span.start);
+    segments
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Code(tokens, spans) => {
+                let spans = spans
+                    .into_iter()
+                    .map(|inner| {
+                        for ch in &chars[scanned..inner.start] {
+                            if *ch == '\n' {
+                                line += 1;
+                                column = 1;
+                            } else {
+                                column += 1;
+                            }
+                        }
+                        scanned = inner.start;
+                        Span {
+                            line,
+                            column,
+                            ..inner
+                        }
+                    })
+                    .collect();
+                Segment::Code(tokens, spans)
+            }
+            text => text,
+        })
+        .collect()
+}
+
+/// Moves a span, and those embedded in an interpolated `token`, by `delta`
+/// characters and `line_delta` lines.
+fn shift(token: &Token, span: &Span, delta: isize, line_delta: isize) -> (Token, Span) {
+    let moved = |span: &Span| Span {
+        start: (span.start as isize + delta) as usize,
+        end: (span.end as isize + delta) as usize,
+        line: (span.line as isize + line_delta) as usize,
+        column: span.column,
+    };
+    let token = match token {
+        Token::Interpolated(segments) => Token::Interpolated(
+            segments
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Code(tokens, spans) => {
+                        Segment::Code(tokens.clone(), spans.iter().map(moved).collect())
+                    }
+                    text => text.clone(),
+                })
+                .collect(),
+        ),
+        token => token.clone(),
+    };
+    (token, moved(span))
+}
+
+/// A source buffer kept lexed across edits, for editors that re-lex on every keystroke.
+pub struct LexedSource {
+    pub source: String,
+    pub tokens: Vec<Token>,
+    pub spans: Vec<Span>,
+    disabled_keywords: Vec<String>, // Kept so edits are lexed like the original
+}
+
+impl LexedSource {
+    pub fn new(source: &str) -> Self {
+        Self::without_keywords(source, &[])
+    }
+
+    /// Like `new`, lexing each of `keywords` as an identifier the way
+    /// `Lexer::without_keywords` does, before and after every edit.
+    pub fn without_keywords(source: &str, keywords: &[&str]) -> Self {
+        let (tokens, spans) = Lexer::new(source.to_string())
+            .without_keywords(keywords)
+            .tokenize_with_spans();
+        Self {
+            source: source.to_string(),
+            tokens,
+            spans,
+            disabled_keywords: keywords.iter().map(|keyword| keyword.to_string()).collect(),
+        }
+    }
+
+    /// Replaces the characters in `range` with `text` and re-lexes only what the
+    /// edit can affect. The lexer keeps no state between tokens, so lexing restarts
+    /// after the last newline before the edit and stops at the first newline after
+    /// it that lines up with an old one, the tokens past that are shifted and reused.
+    pub fn edit(&mut self, range: Range<usize>, text: &str) {
+        let chars: Vec<char> = self.source.chars().collect();
+        let inserted = text.chars().count();
+        let delta = inserted as isize - range.len() as isize;
+        self.source = chars[..range.start]
+            .iter()
+            .chain(text.chars().collect::<Vec<char>>().iter())
+            .chain(chars[range.end..].iter())
+            .collect();
+
+        let restart = (0..self.tokens.len()).rev().find(|&i| {
+            matches!(self.tokens[i], Token::Newline) && self.spans[i].end <= range.start
+        });
+        let (position, line) = match restart {
+            Some(i) => (self.spans[i].end, self.spans[i].line + 1),
+            None => (0, 1),
+        };
+        let keep = restart.map_or(0, |i| i + 1);
+
+        let old_spans = &self.spans;
+        let edit_end = range.start + inserted;
+        let mut resume = None;
+        let mut lexer = Lexer::new(self.source.clone());
+        lexer.disabled_keywords = self.disabled_keywords.clone();
+        lexer.seek(position);
+        let (tokens, spans) = lexer.lex_spans(line, |token, span| {
+            if !matches!(token, Token::Newline) || span.start < edit_end {
+                return false;
+            }
+            let old_start = (span.start as isize - delta) as usize;
+            let i = old_spans.partition_point(|old| old.start < old_start);
+            if old_start >= range.end
+                && matches!(self.tokens.get(i), Some(Token::Newline))
+                && old_spans[i].start == old_start
+            {
+                resume = Some((i + 1, span.line as isize - old_spans[i].line as isize));
+            }
+            resume.is_some()
+        });
+
+        let mut tail = (Vec::new(), Vec::new());
+        if let Some((from, line_delta)) = resume {
+            tail = self.tokens[from..]
+                .iter()
+                .zip(&self.spans[from..])
+                .map(|(token, span)| shift(token, span, delta, line_delta))
+                .unzip();
+        }
+
+        self.tokens.truncate(keep);
+        self.spans.truncate(keep);
+        self.tokens.extend(tokens.into_iter().chain(tail.0));
+        self.spans.extend(spans.into_iter().chain(tail.1));
     }
 }
@@ -0,0 +1,312 @@
+pub mod builtins;
+pub mod compiler;
+pub mod debug;
+pub mod doc;
+pub mod interpreter;
+pub mod lexer;
+pub mod parser;
+pub mod resolver;
+pub mod types;
+
+#[cfg(test)]
+mod tests;
+
+pub mod runtime {
+    use crate::compiler::Compiler;
+    use crate::interpreter::VirtualMachine;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::resolver::FileResolver;
+    use crate::types::compiler::Value;
+    use crate::types::constants::TOKEN_CACHE_CAPACITY;
+    use crate::types::diagnostic::Span;
+    use crate::types::token::Token;
+    use std::cell::RefCell;
+    use std::collections::{HashMap, VecDeque};
+    use std::fmt;
+    use std::path::Path;
+
+    /// Tokens of sources lexed before, keyed by the source text so an edited file
+    /// misses and is lexed again. Holds at most `TOKEN_CACHE_CAPACITY` sources.
+    #[derive(Default)]
+    struct TokenCache {
+        entries: HashMap<String, (Vec<Token>, Vec<Span>)>,
+        order: VecDeque<String>, // Cached sources, oldest first
+        hits: usize,
+    }
+
+    thread_local! {
+        static TOKEN_CACHE: RefCell<TokenCache> = RefCell::new(TokenCache::default());
+    }
+
+    /// Lexes `source`, reusing the tokens of an earlier run over the same text.
+    /// Every keyword is lexed as one, like the `n` command does, hosts that
+    /// disable keywords lex with their own `Lexer` instead.
+    pub fn tokenize_cached(source: &str) -> (Vec<Token>, Vec<Span>) {
+        TOKEN_CACHE.with_borrow_mut(|cache| {
+            if let Some(entry) = cache.entries.get(source) {
+                let entry = entry.clone();
+                cache.hits += 1;
+                return entry;
+            }
+            let entry = Lexer::new(source.to_string()).tokenize_with_spans();
+            if cache.order.len() == TOKEN_CACHE_CAPACITY
+                && let Some(oldest) = cache.order.pop_front()
+            {
+                cache.entries.remove(&oldest);
+            }
+            cache.order.push_back(source.to_string());
+            cache.entries.insert(source.to_string(), entry.clone());
+            entry
+        })
+    }
+
+    /// How many times `tokenize_cached` on this thread skipped lexing.
+    pub fn token_cache_hits() -> usize {
+        TOKEN_CACHE.with_borrow(|cache| cache.hits)
+    }
+
+    /// Markdown listing of the declarations in a source file and their doc comments.
+    pub fn document(filename: &str) -> Result<String, String> {
+        let source_code = read_source(filename)?;
+        let (tokens, spans) = tokenize_cached(&source_code);
+        let program = Parser::with_spans(tokens, spans).parse().map_err(|e| {
+            format!(
+                "Parse error: {}\n{}",
+                e,
+                e.underline(&source_code).trim_end()
+            )
+        })?;
+        Ok(crate::doc::render(&program))
+    }
+
+    /// The stages `compile_and_run_with_debug` prints, as one JSON object for
+    /// tools: the source, its tokens with their spans, the AST and the bytecode.
+    /// The program isn't run.
+    pub fn debug_json(filename: &str) -> Result<String, String> {
+        let source_code = read_source(filename)?;
+        let (tokens, spans) = tokenize_cached(&source_code);
+        let located: Vec<serde_json::Value> = tokens
+            .iter()
+            .zip(&spans)
+            .map(|(token, span)| serde_json::json!({ "token": token, "span": span }))
+            .collect();
+        let program = Parser::with_spans(tokens, spans).parse().map_err(|e| {
+            format!(
+                "Parse error: {}\n{}",
+                e,
+                e.underline(&source_code).trim_end()
+            )
+        })?;
+        let directory = Path::new(filename).parent().unwrap_or(Path::new("."));
+        let mut compiler = Compiler::new().with_resolver(FileResolver::new(directory));
+        let bytecode = compiler
+            .compile(&program)
+            .map_err(|e| format!("Compile error: {}", e))?;
+        for warning in &compiler.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+
+        let stages = serde_json::json!({
+            "source": source_code,
+            "tokens": located,
+            "ast": program,
+            "bytecode": bytecode,
+        });
+        serde_json::to_string_pretty(&stages).map_err(|e| e.to_string())
+    }
+
+    /// Outcome of `run_tests`, each test with the error it failed on if it did, in
+    /// the order the file declares them.
+    #[derive(Debug, Default)]
+    pub struct TestReport {
+        pub results: Vec<(String, Result<(), String>)>,
+    }
+
+    impl TestReport {
+        pub fn passed(&self) -> usize {
+            self.results
+                .iter()
+                .filter(|(_, result)| result.is_ok())
+                .count()
+        }
+
+        pub fn failed(&self) -> usize {
+            self.results.len() - self.passed()
+        }
+
+        pub fn success(&self) -> bool {
+            self.failed() == 0
+        }
+    }
+
+    impl fmt::Display for TestReport {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            for (name, result) in &self.results {
+                match result {
+                    Ok(()) => writeln!(f, "test {} ... ok", name)?,
+                    Err(error) => writeln!(f, "test {} ... FAILED: {}", name, error)?,
+                }
+            }
+            writeln!(f, "\n{} passed, {} failed", self.passed(), self.failed())
+        }
+    }
+
+    /// Runs a source file, then each of its top-level functions named `test_*`.
+    /// A test passes when it returns, and one that fails, say on an `assert`,
+    /// doesn't stop the tests after it.
+    pub fn run_tests(filename: &str) -> Result<TestReport, String> {
+        let source_code = read_source(filename)?;
+        let (tokens, spans) = tokenize_cached(&source_code);
+        let program = Parser::with_spans(tokens, spans).parse().map_err(|e| {
+            format!(
+                "Parse error: {}\n{}",
+                e,
+                e.underline(&source_code).trim_end()
+            )
+        })?;
+        let directory = Path::new(filename).parent().unwrap_or(Path::new("."));
+        let mut compiler = Compiler::new().with_resolver(FileResolver::new(directory));
+        let bytecode = compiler
+            .compile(&program)
+            .map_err(|e| format!("Compile error: {}", e))?;
+        for warning in &compiler.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+
+        // Nested helpers are keyed `outer.name`, only top-level functions are tests
+        let mut tests: Vec<(&String, usize)> = compiler
+            .functions
+            .iter()
+            .filter(|(name, _)| name.starts_with("test_") && !name.contains('.'))
+            .map(|(name, index)| (name, *index))
+            .collect();
+        tests.sort_by_key(|(_, index)| *index);
+        let tests: Vec<(String, Value)> = tests
+            .into_iter()
+            .map(|(name, index)| (name.clone(), bytecode.functions[index].clone()))
+            .collect();
+
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.run().map_err(|e| format!("Runtime error: {}", e))?;
+
+        let mut report = TestReport::default();
+        for (name, function) in tests {
+            let result = match &function {
+                Value::Function { params, .. } if !params.is_empty() => {
+                    Err("Test functions take no arguments".to_string())
+                }
+                _ => vm.try_call(function, Vec::new()).map(|_| ()),
+            };
+            report.results.push((name, result));
+        }
+        Ok(report)
+    }
+
+    fn read_source(filename: &str) -> Result<String, String> {
+        // Check if file ends with .n extension
+        if !filename.ends_with(".n") {
+            return Err("Error: File must have .n extension".to_string());
+        }
+
+        std::fs::read_to_string(filename)
+            .map_err(|err| format!("Error reading file '{}': {}", filename, err))
+    }
+
+    pub fn compile_and_run(filename: &str) -> Result<String, String> {
+        compile_and_run_with_debug(filename, false)
+    }
+
+    pub fn compile_and_run_with_debug(filename: &str, debug: bool) -> Result<String, String> {
+        compile_and_run_with_args(filename, debug, Vec::new())
+    }
+
+    /// Runs the file like `compile_and_run_with_debug`, passing `args` to the script.
+    pub fn compile_and_run_with_args(
+        filename: &str,
+        debug: bool,
+        args: Vec<String>,
+    ) -> Result<String, String> {
+        let source_code = read_source(filename)?;
+
+        if debug {
+            println!("--- Source Code ---\n{}", source_code);
+        }
+
+        let (tokens, spans) = tokenize_cached(&source_code);
+
+        if debug {
+            println!("--- Tokens ---");
+            for token in &tokens {
+                println!("{:?}", token);
+            }
+        }
+
+        let mut parser = Parser::with_spans(tokens, spans);
+        let ast = match parser.parse() {
+            Ok(ast) => ast,
+            Err(e) => {
+                return Err(format!(
+                    "Parse error: {}\n{}",
+                    e,
+                    e.underline(&source_code).trim_end()
+                ));
+            }
+        };
+
+        if debug {
+            println!("--- AST ---");
+            // Assuming AST implements Debug
+            println!("{:#?}", ast);
+        }
+
+        // Imports are found next to the file that imports them
+        let directory = Path::new(filename).parent().unwrap_or(Path::new("."));
+        let mut compiler = Compiler::new().with_resolver(FileResolver::new(directory));
+        let bytecode = match compiler.compile(&ast) {
+            Ok(bc) => bc,
+            Err(e) => return Err(format!("Compile error: {}", e)),
+        };
+        for warning in &compiler.warnings {
+            eprintln!("Warning: {}", warning);
+        }
+
+        if debug {
+            println!("--- Bytecode ---\n");
+            if !bytecode.functions.is_empty() {
+                println!("--- Functions ---");
+                for function in bytecode.functions.iter() {
+                    println!("{}", function);
+                }
+            }
+            if !bytecode.constants.is_empty() {
+                println!("--- Constants ---");
+                for constant in bytecode.constants.iter() {
+                    println!("{}", constant);
+                }
+            }
+            println!("--- Instructions ---");
+            for instruction in bytecode.instructions.iter() {
+                println!("{}", instruction);
+            }
+        }
+
+        let mut vm = VirtualMachine::new(bytecode, compiler);
+        vm.set_args(args);
+
+        if debug {
+            println!("--- Runtime ---");
+        }
+
+        match vm.run() {
+            Ok(()) => {
+                vm.debug_stack();
+                Ok(vm.format_value(&vm.result()))
+            }
+            Err(e) => {
+                vm.debug_stack();
+                Err(format!("Runtime error: {}", e))
+            }
+        }
+    }
+}
@@ -1,122 +1,59 @@
-mod compiler;
-mod debug;
-mod interpreter;
-mod lexer;
-mod parser;
-mod types;
-
-#[cfg(test)]
-mod tests;
-
-pub mod runtime {
-    use crate::compiler::Compiler;
-    use crate::interpreter::VirtualMachine;
-    use crate::lexer::Lexer;
-    use crate::parser::Parser;
-
-    pub fn compile_and_run(filename: &str) -> Result<String, String> {
-        compile_and_run_with_debug(filename, false)
-    }
-
-    pub fn compile_and_run_with_debug(filename: &str, debug: bool) -> Result<String, String> {
-        // Check if file ends with .n extension
-        if !filename.ends_with(".n") {
-            return Err("Error: File must have .n extension".to_string());
-        }
-
-        // Read the file
-        let source_code = match std::fs::read_to_string(filename) {
-            Ok(content) => content,
-            Err(err) => {
-                return Err(format!("Error reading file '{}': {}", filename, err));
-            }
-        };
-
-        if debug {
-            println!("--- Source Code ---\n{}", source_code);
-        }
+use n::runtime;
+use std::env;
+use std::process;
 
-        let mut lexer = Lexer::new(source_code);
-        let tokens = lexer.tokenize();
+fn main() {
+    let args: Vec<String> = env::args().collect();
 
-        if debug {
-            println!("--- Tokens ---");
-            for token in &tokens {
-                println!("{:?}", token);
+    if args.len() == 3 && args[1] == "doc" {
+        match runtime::document(&args[2]) {
+            Ok(listing) => print!("{}", listing),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
             }
         }
+        return;
+    }
 
-        let mut parser = Parser::new(tokens);
-        let ast = match parser.parse() {
-            Ok(ast) => ast,
-            Err(e) => return Err(format!("Parse error: {}", e)),
-        };
-
-        if debug {
-            println!("--- AST ---");
-            // Assuming AST implements Debug
-            println!("{:#?}", ast);
-        }
-
-        let mut compiler = Compiler::new();
-        let bytecode = match compiler.compile(&ast) {
-            Ok(bc) => bc,
-            Err(e) => return Err(format!("Compile error: {}", e)),
-        };
-
-        if debug {
-            println!("--- Bytecode ---\n");
-            if bytecode.functions.len() > 0 {
-                println!("--- Functions ---");
-                for function in bytecode.functions.iter() {
-                    println!("{}", function);
+    if args.len() == 3 && args[1] == "test" {
+        match runtime::run_tests(&args[2]) {
+            Ok(report) => {
+                print!("{}", report);
+                if !report.success() {
+                    process::exit(1);
                 }
             }
-            if bytecode.constants.len() > 0 {
-                println!("--- Constants ---");
-                for constant in bytecode.constants.iter() {
-                    println!("{}", constant);
-                }
-            }
-            println!("--- Instructions ---");
-            for instruction in bytecode.instructions.iter() {
-                println!("{}", instruction);
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
             }
         }
+        return;
+    }
 
-        let mut vm = VirtualMachine::new(bytecode, compiler);
-
-        if debug {
-            println!("--- Runtime ---");
-        }
-
-        match vm.run() {
-            Ok(()) => {
-                vm.debug_stack();
-                Ok("Successfully executed program".to_string())
-            }
+    if args.len() == 3 && args[1] == "--debug-json" {
+        match runtime::debug_json(&args[2]) {
+            Ok(json) => println!("{}", json),
             Err(e) => {
-                vm.debug_stack();
-                Err(format!("Runtime error: {}", e))
+                eprintln!("{}", e);
+                process::exit(1);
             }
         }
+        return;
     }
-}
-
-use std::env;
-use std::process;
-
-fn main() {
-    let args: Vec<String> = env::args().collect();
 
-    if args.len() != 2 {
-        eprintln!("Usage: {} <file.n>", args[0]);
+    if args.len() < 2 {
+        eprintln!(
+            "Usage: {} <file.n> [args...]\n       {} doc <file.n>\n       {} test <file.n>\n       {} --debug-json <file.n>",
+            args[0], args[0], args[0], args[0]
+        );
         process::exit(1);
     }
 
     let filename = &args[1];
 
-    match runtime::compile_and_run_with_debug(filename, true) {
+    match runtime::compile_and_run_with_args(filename, true, args[2..].to_vec()) {
         Ok(result) => {
             println!("=== EXECUTION ===");
             println!("{}", result);
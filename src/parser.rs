@@ -1,117 +1,613 @@
-use crate::types::{ast::*, token::Token};
+use crate::lexer::Lexer;
+use crate::types::constants::{Precedence, PrecedenceTable};
+use crate::types::diagnostic::{Diagnostic, Span};
+use crate::types::{
+    ast::*,
+    token::{Segment, Token},
+};
 
 pub struct Parser {
     tokens: Vec<Token>,
+    spans: Vec<Span>, // Parallel to `tokens`, empty when the source isn't known
     pos: usize,
+    depth: usize, // Expressions currently being parsed inside each other
+    max_depth: usize,
+    precedences: PrecedenceTable,
+    docs: Vec<String>, // `///` lines waiting for the declaration they document
+    docs_end: usize,   // Position just past the last `///` line
+    module_docs: Vec<String>, // `//!` lines seen so far
 }
 
+/// Deep enough for any handwritten code, shallow enough not to exhaust the native stack.
+const DEFAULT_MAX_DEPTH: usize = 256;
+
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, pos: 0 }
+    pub fn from_tokens(tokens: Vec<Token>) -> Self {
+        Self::with_spans(tokens, Vec::new())
+    }
+
+    pub fn with_spans(tokens: Vec<Token>, spans: Vec<Span>) -> Self {
+        Self {
+            tokens,
+            spans,
+            pos: 0,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            precedences: PrecedenceTable::default(),
+            docs: Vec::new(),
+            docs_end: 0,
+            module_docs: Vec::new(),
+        }
     }
 
-    pub fn parse(&mut self) -> Result<Program, String> {
+    /// Parses infix operators with the binding powers in `precedences`.
+    pub fn with_precedences(mut self, precedences: PrecedenceTable) -> Self {
+        self.precedences = precedences;
+        self
+    }
+
+    /// Limits how deeply expressions may nest, deeper input is a parse error.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn from_source(source: &str) -> Self {
+        let (tokens, spans) = Lexer::new(source.to_string()).tokenize_with_spans();
+        Self::with_spans(tokens, spans)
+    }
+
+    pub fn parse(&mut self) -> Result<Program, Diagnostic> {
         let mut statements = Vec::new();
+        let mut seen_non_import = false;
         while !self.is_at_end() {
             self.skip_newlines();
-            if !self.is_at_end() {
+            if self.is_at_end() {
+                break;
+            }
+            if matches!(self.current(), Token::Import) {
+                // Imports form the header of a file and may not follow other statements
+                if seen_non_import {
+                    return Err(self.error("Imports must appear before other statements"));
+                }
+                let line = self.current_line();
+                statements.push(self.import_statement(line)?);
+            } else if matches!(self.current(), Token::Export) {
+                seen_non_import = true;
+                statements.push(self.export_statement()?);
+            } else {
+                seen_non_import = true;
                 statements.push(self.statement()?);
             }
         }
-        Ok(Program { statements })
+        Ok(Program {
+            statements,
+            doc: join_docs(std::mem::take(&mut self.module_docs)),
+        })
     }
 
-    fn statement(&mut self) -> Result<Stmt, String> {
+    fn statement(&mut self) -> Result<Stmt, Diagnostic> {
         let line = self.current_line();
+        // Doc comments only attach to declarations right below them, anything else
+        // drops them
+        let doc = if self.docs_precede() {
+            join_docs(std::mem::take(&mut self.docs))
+        } else {
+            self.docs.clear();
+            None
+        };
         match self.current() {
-            Token::Let | Token::LetBang => self.let_statement(line),
-            Token::Func => self.func_statement(line),
+            Token::Let | Token::LetBang => self.let_statement(line, doc),
+            Token::Func => self.func_statement(line, doc),
+            Token::Import => Err(self.error("Imports are only allowed at the top of a file")),
+            Token::Export => Err(self.error("Only top-level declarations can be exported")),
             _ => Ok(Stmt::Expr(self.expression(1)?, line)),
         }
     }
 
-    fn let_statement(&mut self, line: usize) -> Result<Stmt, String> {
+    fn import_statement(&mut self, line: usize) -> Result<Stmt, Diagnostic> {
+        self.advance();
+        let mut modules = Vec::new();
+        loop {
+            match self.advance() {
+                Token::String(module) => modules.push(module),
+                t => {
+                    return Err(self.error(format!(
+                        "Expected module name string in import, found {:?}",
+                        t
+                    )));
+                }
+            }
+            if !matches!(self.current(), Token::Comma) {
+                break;
+            }
+            self.advance();
+        }
+        Ok(Stmt::Import { modules, line })
+    }
+
+    /// `export let` or `export func`, which makes the declaration visible to importers.
+    fn export_statement(&mut self) -> Result<Stmt, Diagnostic> {
+        self.advance();
+        if !matches!(self.current(), Token::Let | Token::LetBang | Token::Func) {
+            return Err(self.error(format!(
+                "Expected 'let' or 'func' after 'export', found {:?}",
+                self.current()
+            )));
+        }
+        let mut stmt = self.statement()?;
+        if let Stmt::Let { exported, .. } | Stmt::Func { exported, .. } = &mut stmt {
+            *exported = true;
+        }
+        Ok(stmt)
+    }
+
+    fn let_statement(&mut self, line: usize, doc: Option<String>) -> Result<Stmt, Diagnostic> {
         self.advance();
         let name = match self.advance() {
             Token::Identifier(n) => n,
             _ => {
-                return Err(format!(
-                    "Expected identifier at line {}",
-                    self.current_line()
-                ));
+                return Err(self.error("Expected identifier"));
             }
         };
+        let annotation = if matches!(self.current(), Token::Colon) {
+            self.advance();
+            Some(self.type_name()?)
+        } else {
+            None
+        };
         self.expect(Token::Assign)?;
         let value = self.expression(1)?;
-        Ok(Stmt::Let { name, value, line })
+        Ok(Stmt::Let {
+            name,
+            value,
+            line,
+            doc,
+            exported: false,
+            annotation,
+        })
     }
 
-    fn func_statement(&mut self, line: usize) -> Result<Stmt, String> {
+    /// The type named in an annotation, after its `:`.
+    fn type_name(&mut self) -> Result<String, Diagnostic> {
+        match self.current().clone() {
+            Token::Identifier(name) if TYPE_NAMES.contains(&name.as_str()) => {
+                self.advance();
+                Ok(name)
+            }
+            t => Err(self.error(format!(
+                "Expected a type ({}), found {:?}",
+                TYPE_NAMES.join(", "),
+                t
+            ))),
+        }
+    }
+
+    fn func_statement(&mut self, line: usize, doc: Option<String>) -> Result<Stmt, Diagnostic> {
         self.advance();
         let name = match self.advance() {
             Token::Identifier(n) => n,
             _ => {
-                return Err(format!(
-                    "Expected identifier at line {}",
-                    self.current_line()
-                ));
+                return Err(self.error("Expected identifier"));
             }
         };
         self.expect(Token::LeftParen)?;
         let mut params = Vec::new();
         while !matches!(self.current(), Token::RightParen) {
-            if let Token::Identifier(p) = self.advance() {
-                params.push(p);
+            match self.advance() {
+                Token::Identifier(p) if matches!(self.current(), Token::Colon) => {
+                    self.advance();
+                    params.push(Param::Typed(p, self.type_name()?));
+                }
+                Token::Identifier(p) => params.push(Param::Name(p)),
+                Token::LeftBrace => params.push(Param::Fields(self.field_names()?)),
+                _ => {}
             }
             if matches!(self.current(), Token::Comma) {
                 self.advance();
             }
         }
         self.expect(Token::RightParen)?;
+        let returns = if matches!(self.current(), Token::Arrow) {
+            self.advance();
+            Some(self.type_name()?)
+        } else {
+            None
+        };
+        let body = self.block()?;
+        Ok(Stmt::Func {
+            name,
+            params,
+            body,
+            line,
+            doc,
+            exported: false,
+            returns,
+        })
+    }
+
+    /// Parses the `x, y }` of a `{ x, y }` parameter, the brace is already consumed.
+    fn field_names(&mut self) -> Result<Vec<String>, Diagnostic> {
+        let mut fields = Vec::new();
+        loop {
+            match self.advance() {
+                Token::Identifier(field) => fields.push(field),
+                Token::RightBrace => break,
+                t => {
+                    return Err(
+                        self.error(format!("Expected field name in parameter, found {:?}", t))
+                    );
+                }
+            }
+            match self.advance() {
+                Token::Comma => {}
+                Token::RightBrace => break,
+                t => {
+                    return Err(
+                        self.error(format!("Expected ',' or '}}' in parameter, found {:?}", t))
+                    );
+                }
+            }
+        }
+        Ok(fields)
+    }
+
+    fn block(&mut self) -> Result<Vec<Stmt>, Diagnostic> {
+        let open = self.pos;
         self.expect(Token::LeftBrace)?;
         let mut body = Vec::new();
         while !matches!(self.current(), Token::RightBrace) {
             self.skip_newlines();
-            if !matches!(self.current(), Token::RightBrace) {
-                body.push(self.statement()?);
+            match self.current() {
+                Token::RightBrace => {}
+                Token::Eof => return Err(self.unclosed(open, '{')),
+                _ => body.push(self.statement()?),
             }
         }
         self.expect(Token::RightBrace)?;
-        Ok(Stmt::Func {
-            name,
-            params,
-            body,
-            line,
+        Ok(body)
+    }
+
+    fn match_expression(&mut self) -> Result<Expr, Diagnostic> {
+        let value = self.expression(1)?;
+        let open = self.pos;
+        self.expect(Token::LeftBrace)?;
+
+        let mut arms = Vec::new();
+        loop {
+            self.skip_newlines();
+            match self.current() {
+                Token::RightBrace => break,
+                Token::Eof => return Err(self.unclosed(open, '{')),
+                _ => {}
+            }
+            let line = self.current_line();
+            let pattern = self.pattern()?;
+            self.expect(Token::Arrow)?;
+            let body = self.arm_body()?;
+            arms.push(MatchArm {
+                pattern,
+                body,
+                line,
+                fallback: false,
+            });
+        }
+        self.expect(Token::RightBrace)?;
+
+        Ok(Expr::Match {
+            value: Box::new(value),
+            arms,
+        })
+    }
+
+    /// Parses the embedded expressions of an interpolated string, each with a
+    /// parser of its own over the tokens the lexer set aside for it.
+    fn interpolation(&mut self, segments: Vec<Segment>) -> Result<Expr, Diagnostic> {
+        let mut parts = Vec::new();
+        for segment in segments {
+            match segment {
+                Segment::Text(text) => parts.push(InterpPart::Literal(text)),
+                Segment::Code(tokens, spans) => {
+                    let mut parser = Parser::with_spans(tokens, spans)
+                        .with_precedences(self.precedences.clone())
+                        .with_max_depth(self.max_depth - self.depth);
+                    parser.skip_newlines();
+                    if matches!(parser.current(), Token::Eof) {
+                        return Err(parser.error("Expected expression in interpolation"));
+                    }
+                    let start = parser.pos;
+                    let expr = parser.expression(1)?;
+                    let span = match (parser.spans.get(start), parser.spans.get(parser.pos - 1)) {
+                        (Some(first), Some(last)) => first.to(last),
+                        _ => Span::default(),
+                    };
+                    parser.skip_newlines();
+                    if !matches!(parser.current(), Token::Eof) {
+                        return Err(parser.error(format!(
+                            "Unexpected {:?} in interpolation",
+                            parser.current()
+                        )));
+                    }
+                    parts.push(InterpPart::Expr(expr, span));
+                }
+            }
+        }
+        Ok(Expr::Interpolation { parts })
+    }
+
+    /// Parses `for name in iterable { ... }`. An array or map pattern in place
+    /// of the name, as in `for [key, value] in entries`, binds each element
+    /// through a match with that pattern as its only arm.
+    fn for_expression(&mut self) -> Result<Expr, Diagnostic> {
+        if let Token::Identifier(name) = self.current().clone() {
+            self.advance();
+            self.expect(Token::In)?;
+            let iterable = self.expression(1)?;
+            let body = self.block()?;
+            return Ok(Expr::For {
+                name,
+                iterable: Box::new(iterable),
+                body,
+            });
+        }
+
+        if !matches!(self.current(), Token::LeftBracket | Token::LeftBrace) {
+            let t = self.advance();
+            return Err(self.error(format!("Expected loop variable after 'for', found {:?}", t)));
+        }
+        let line = self.current_line();
+        let pattern = self.pattern()?;
+        self.expect(Token::In)?;
+        let iterable = self.expression(1)?;
+        let body = self.block()?;
+        let element = "$element".to_string();
+        Ok(Expr::For {
+            name: element.clone(),
+            iterable: Box::new(iterable),
+            body: vec![Stmt::Expr(
+                Expr::Match {
+                    value: Box::new(Expr::Identifier(element)),
+                    arms: vec![MatchArm {
+                        pattern,
+                        body,
+                        line,
+                        fallback: false,
+                    }],
+                },
+                line,
+            )],
+        })
+    }
+
+    /// Parses `yield value`. Kept out of `nud`, whose frame every level of
+    /// nesting pays for.
+    fn yield_expression(&mut self) -> Result<Expr, Diagnostic> {
+        Ok(Expr::Yield {
+            value: Box::new(self.expression(1)?),
+        })
+    }
+
+    /// Parses `if let pattern = expr { ... } else { ... }`, which is a match with
+    /// the pattern as its only arm and the else branch as a wildcard arm.
+    fn if_let_expression(&mut self) -> Result<Expr, Diagnostic> {
+        if !matches!(self.current(), Token::Let) {
+            return Err(self.error("Expected 'let' after 'if'"));
+        }
+        self.advance();
+        let pattern_line = self.current_line();
+        let pattern = self.pattern()?;
+        self.expect(Token::Assign)?;
+        let value = self.expression(1)?;
+        let then_body = self.block()?;
+
+        let line = self.current_line();
+        let else_body = if matches!(self.current(), Token::Else) {
+            self.advance();
+            if matches!(self.current(), Token::If) {
+                self.advance();
+                vec![Stmt::Expr(self.if_let_expression()?, line)]
+            } else {
+                self.block()?
+            }
+        } else {
+            Vec::new()
+        };
+
+        Ok(Expr::Match {
+            value: Box::new(value),
+            arms: vec![
+                MatchArm {
+                    pattern,
+                    body: then_body,
+                    line: pattern_line,
+                    fallback: false,
+                },
+                MatchArm {
+                    pattern: Pattern::Wildcard,
+                    body: else_body,
+                    line,
+                    fallback: true,
+                },
+            ],
         })
     }
 
-    fn expression(&mut self, min_prec: u8) -> Result<Expr, String> {
+    fn arm_body(&mut self) -> Result<Vec<Stmt>, Diagnostic> {
+        if matches!(self.current(), Token::LeftBrace) {
+            return self.block();
+        }
+        let line = self.current_line();
+        Ok(vec![Stmt::Expr(self.expression(1)?, line)])
+    }
+
+    /// A pattern, or several separated by `|` that match when any of them does.
+    fn pattern(&mut self) -> Result<Pattern, Diagnostic> {
+        let first = self.alternative()?;
+        if !matches!(self.current(), Token::Bar) {
+            return Ok(first);
+        }
+        let mut alternatives = vec![first];
+        while matches!(self.current(), Token::Bar) {
+            self.advance();
+            alternatives.push(self.alternative()?);
+        }
+        Ok(Pattern::Or(alternatives))
+    }
+
+    fn alternative(&mut self) -> Result<Pattern, Diagnostic> {
+        match self.advance() {
+            Token::Identifier(name) if name == "_" => Ok(Pattern::Wildcard),
+            Token::Identifier(name) => Ok(Pattern::Binding(name)),
+            Token::Number(n) => self.number_pattern(n),
+            Token::Minus => {
+                let n = self.negative_number()?;
+                self.number_pattern(n)
+            }
+            Token::String(s) => Ok(Pattern::Literal(Expr::String(s))),
+            Token::True => Ok(Pattern::Literal(Expr::Boolean(true))),
+            Token::False => Ok(Pattern::Literal(Expr::Boolean(false))),
+            Token::LeftBracket => self.array_pattern(),
+            Token::LeftBrace => self.object_pattern(),
+            t => Err(self.error(format!("Expected pattern, found {:?}", t))),
+        }
+    }
+
+    /// Parses the `x, y]` of a `[x, y]` pattern, the bracket is already consumed.
+    fn array_pattern(&mut self) -> Result<Pattern, Diagnostic> {
+        let mut elements = Vec::new();
+        while !matches!(self.current(), Token::RightBracket) {
+            elements.push(self.pattern()?);
+            match self.current() {
+                Token::Comma => {
+                    self.advance();
+                }
+                Token::RightBracket => {}
+                t => {
+                    return Err(self.error(format!(
+                        "Expected ',' or ']' in array pattern, found {:?}",
+                        t
+                    )));
+                }
+            }
+        }
+        self.advance();
+        Ok(Pattern::Array(elements))
+    }
+
+    /// Parses the `x, pos = p }` of a `{ x, pos = p }` pattern, the brace is already
+    /// consumed. A field without `= pattern` binds its value to its own name.
+    fn object_pattern(&mut self) -> Result<Pattern, Diagnostic> {
+        let mut fields = Vec::new();
+        while !matches!(self.current(), Token::RightBrace) {
+            let name = match self.advance() {
+                Token::Identifier(name) => name,
+                t => {
+                    return Err(self.error(format!(
+                        "Expected field name in object pattern, found {:?}",
+                        t
+                    )));
+                }
+            };
+            let pattern = if matches!(self.current(), Token::Assign) {
+                self.advance();
+                self.pattern()?
+            } else {
+                Pattern::Binding(name.clone())
+            };
+            fields.push((name, pattern));
+            match self.current() {
+                Token::Comma => {
+                    self.advance();
+                }
+                Token::RightBrace => {}
+                t => {
+                    return Err(self.error(format!(
+                        "Expected ',' or '}}' in object pattern, found {:?}",
+                        t
+                    )));
+                }
+            }
+        }
+        self.advance();
+        Ok(Pattern::Object(fields))
+    }
+
+    /// A number pattern, or a range pattern when `start` is followed by `..` or `..=`.
+    fn number_pattern(&mut self, start: f64) -> Result<Pattern, Diagnostic> {
+        let inclusive = match self.current() {
+            Token::DotDot => false,
+            Token::DotDotEqual => true,
+            _ => return Ok(Pattern::Literal(Expr::Number(start))),
+        };
+        self.advance();
+        let end = match self.advance() {
+            Token::Number(n) => n,
+            Token::Minus => self.negative_number()?,
+            t => {
+                return Err(self.error(format!(
+                    "Expected number to end range pattern, found {:?}",
+                    t
+                )));
+            }
+        };
+        Ok(Pattern::Range {
+            start,
+            end,
+            inclusive,
+        })
+    }
+
+    fn negative_number(&mut self) -> Result<f64, Diagnostic> {
+        match self.advance() {
+            Token::Number(n) => Ok(-n),
+            t => Err(self.error(format!(
+                "Expected number after '-' in pattern, found {:?}",
+                t
+            ))),
+        }
+    }
+
+    fn expression(&mut self, min_prec: u8) -> Result<Expr, Diagnostic> {
+        if self.depth >= self.max_depth {
+            return Err(self.error(format!(
+                "Expression nested more than {} levels deep",
+                self.max_depth
+            )));
+        }
+        self.depth += 1;
+        let result = self.nested_expression(min_prec);
+        self.depth -= 1;
+        result
+    }
+
+    fn nested_expression(&mut self, min_prec: u8) -> Result<Expr, Diagnostic> {
         let mut left = self.nud()?;
-        while self.precedence(false)? >= min_prec {
+        while self.precedence()? >= min_prec {
             left = self.led(left)?;
         }
         Ok(left)
     }
 
-    fn nud(&mut self) -> Result<Expr, String> {
+    fn nud(&mut self) -> Result<Expr, Diagnostic> {
         match self.advance() {
             Token::Identifier(s) => Ok(Expr::Identifier(s)),
             Token::Number(n) => Ok(Expr::Number(n)),
             Token::String(s) => Ok(Expr::String(s)),
+            Token::Interpolated(segments) => self.interpolation(segments),
             Token::LeftParen => {
                 let expr = self.expression(1)?;
                 self.expect(Token::RightParen)?;
                 Ok(expr)
             }
             Token::Minus => {
-                let right = self.expression(5)?;
+                let right = self.expression(Precedence::Unary.as_u8())?;
                 Ok(Expr::Unary {
                     op: UnaryOp::Neg,
                     right: Box::new(right),
                 })
             }
             Token::Not => {
-                let right = self.expression(5)?;
+                let right = self.expression(Precedence::Unary.as_u8())?;
                 Ok(Expr::Unary {
                     op: UnaryOp::Not,
                     right: Box::new(right),
@@ -140,10 +636,7 @@ impl Parser {
                         }
                         Token::RightBracket => break,
                         _ => {
-                            return Err(format!(
-                                "Expected ',' or ']' in array literal at line {}",
-                                self.current_line()
-                            ));
+                            return Err(self.error("Expected ',' or ']' in array literal"));
                         }
                     }
                 }
@@ -151,31 +644,83 @@ impl Parser {
                 self.expect(Token::RightBracket)?;
                 Ok(Expr::Array { elements })
             }
+            Token::LeftBrace => {
+                let open = self.pos - 1;
+                let mut entries = Vec::new();
+
+                // Parse map entries { key = expr, ... }
+                loop {
+                    self.skip_newlines();
+                    match self.current() {
+                        Token::RightBrace => break,
+                        Token::Eof => return Err(self.unclosed(open, '{')),
+                        _ => {}
+                    }
+
+                    let key = match self.advance() {
+                        Token::Identifier(name) | Token::String(name) => Expr::String(name),
+                        Token::Number(n) => Expr::Number(n),
+                        Token::True => Expr::Boolean(true),
+                        Token::False => Expr::Boolean(false),
+                        t => {
+                            return Err(self.error(format!("Expected map key, found {:?}", t)));
+                        }
+                    };
+                    self.expect(Token::Assign)?;
+                    entries.push((key, self.expression(1)?));
+
+                    self.skip_newlines();
+                    match self.current() {
+                        Token::Comma => {
+                            self.advance();
+                        }
+                        Token::RightBrace => break,
+                        Token::Eof => return Err(self.unclosed(open, '{')),
+                        _ => {
+                            return Err(self.error("Expected ',' or '}' in map literal"));
+                        }
+                    }
+                }
+
+                self.expect(Token::RightBrace)?;
+                Ok(Expr::Map { entries })
+            }
+            Token::Match => self.match_expression(),
+            Token::If => self.if_let_expression(),
+            Token::For => self.for_expression(),
+            Token::Yield => self.yield_expression(),
             Token::True => Ok(Expr::Boolean(true)),
             Token::False => Ok(Expr::Boolean(false)),
-            t => Err(format!(
-                "Unexpected token in nud: {:?} at line {}",
-                t,
-                self.current_line()
-            )),
+            Token::Error(message) => Err(self.error_at(self.pos - 1, message)),
+            t => Err(self.error(format!("Unexpected token in nud: {:?}", t))),
         }
     }
 
-    fn led(&mut self, left: Expr) -> Result<Expr, String> {
+    fn led(&mut self, left: Expr) -> Result<Expr, Diagnostic> {
         match self.current() {
             Token::Plus
             | Token::Minus
             | Token::Multiply
             | Token::Divide
+            | Token::Power
             | Token::Equal
             | Token::NotEqual
             | Token::Less
             | Token::Greater
             | Token::LessEqual
-            | Token::GreaterEqual => {
+            | Token::GreaterEqual
+            | Token::And
+            | Token::Or => {
                 let op = self.binary_op()?;
+                let precedence = self.precedence()?;
                 self.advance();
-                let right = self.expression(self.precedence(true)? + 1)?;
+                // `**` is right-associative, so its right operand may hold another `**`
+                let min_prec = if op == BinaryOp::Pow {
+                    precedence
+                } else {
+                    precedence + 1
+                };
+                let right = self.expression(min_prec)?;
                 Ok(Expr::Binary {
                     left: Box::new(left),
                     op,
@@ -197,19 +742,39 @@ impl Parser {
                     args,
                 })
             }
+            Token::LeftBracket => {
+                self.advance();
+                let index = self.expression(1)?;
+                self.expect(Token::RightBracket)?;
+                Ok(Expr::Index {
+                    object: Box::new(left),
+                    index: Box::new(index),
+                })
+            }
+            Token::Dot => {
+                self.advance();
+                match self.advance() {
+                    Token::Identifier(name) => Ok(Expr::Member {
+                        object: Box::new(left),
+                        name,
+                    }),
+                    t => Err(self.error(format!("Expected member name after '.', found {:?}", t))),
+                }
+            }
             Token::Pipeline => {
+                let precedence = self.precedence()?;
                 self.advance();
-                let right = self.expression(self.precedence(true)? + 1)?;
+                let right = self.expression(precedence + 1)?;
                 Ok(Expr::Pipeline {
                     left: Box::new(left),
                     right: Box::new(right),
                 })
             }
             Token::Update => {
+                let precedence = self.precedence()?;
                 self.advance();
                 // Make update right-associative: parse RHS with same precedence
-                println!("{:?}", self.current());
-                let right = self.expression(self.precedence(true)?)?;
+                let right = self.expression(precedence)?;
 
                 Ok(Expr::Update {
                     left: Box::new(left),
@@ -220,54 +785,36 @@ impl Parser {
         }
     }
 
-    fn binary_op(&self) -> Result<BinaryOp, String> {
+    fn binary_op(&self) -> Result<BinaryOp, Diagnostic> {
         match self.current() {
             Token::Plus => Ok(BinaryOp::Add),
             Token::Minus => Ok(BinaryOp::Sub),
             Token::Multiply => Ok(BinaryOp::Mul),
             Token::Divide => Ok(BinaryOp::Div),
+            Token::Power => Ok(BinaryOp::Pow),
             Token::Equal => Ok(BinaryOp::Eq),
             Token::NotEqual => Ok(BinaryOp::Ne),
             Token::Less => Ok(BinaryOp::Lt),
             Token::Greater => Ok(BinaryOp::Gt),
             Token::LessEqual => Ok(BinaryOp::Le),
             Token::GreaterEqual => Ok(BinaryOp::Ge),
-            _ => Err(format!(
-                "Not a binary operator: {:?} at line {}",
-                self.current(),
-                self.current_line()
-            )),
+            Token::And => Ok(BinaryOp::And),
+            Token::Or => Ok(BinaryOp::Or),
+            _ => Err(self.error(format!("Not a binary operator: {:?}", self.current()))),
         }
     }
 
-    fn precedence(&self, right_parse: bool) -> Result<u8, String> {
+    fn precedence(&self) -> Result<u8, Diagnostic> {
+        if let Some(precedence) = self.precedences.get(self.current()) {
+            return Ok(precedence.as_u8());
+        }
         match self.current() {
-            Token::Pipeline | Token::Update => Ok(1),
-            Token::Equal
-            | Token::NotEqual
-            | Token::Less
-            | Token::Greater
-            | Token::LessEqual
-            | Token::GreaterEqual => Ok(2),
-            Token::Plus | Token::Minus => Ok(3),
-            Token::Multiply | Token::Divide => Ok(4),
-            Token::LeftParen => Ok(5),
             Token::String(_)
             | Token::Number(_)
             | Token::Identifier(_)
             | Token::True
-            | Token::False
-            | Token::LeftBracket
-            | Token::LeftBrace => {
-                if right_parse {
-                    return Ok(1);
-                } else {
-                    return Err(format!(
-                        "Invalid hanging literal: {:?} at line {}",
-                        self.current(),
-                        self.current_line()
-                    ));
-                }
+            | Token::False => {
+                Err(self.error(format!("Invalid hanging literal: {:?}", self.current())))
             }
             _ => Ok(0),
         }
@@ -277,42 +824,83 @@ impl Parser {
         self.tokens.get(self.pos).unwrap_or(&Token::Eof)
     }
 
-    fn peek(&self) -> Option<&Token> {
-        self.tokens.get(self.pos + 1)
-    }
-
     fn advance(&mut self) -> Token {
         let token = self.current().clone();
-        if self.pos < self.tokens.len() - 1 {
+        // Stays on the last token, which may not exist for an empty token list
+        if self.pos + 1 < self.tokens.len() {
             self.pos += 1;
         }
         token
     }
 
-    fn expect(&mut self, expected: Token) -> Result<(), String> {
+    fn expect(&mut self, expected: Token) -> Result<(), Diagnostic> {
         if std::mem::discriminant(self.current()) != std::mem::discriminant(&expected) {
-            return Err(format!(
-                "Expected {:?}, found {:?} at line {}",
+            return Err(self.error(format!(
+                "Expected {:?}, found {:?}",
                 expected,
-                self.current(),
-                self.current_line()
-            ));
+                self.current()
+            )));
         }
         self.advance();
         Ok(())
     }
 
+    /// Skips blank lines, setting aside the doc comments on them.
     fn skip_newlines(&mut self) {
-        while matches!(self.current(), Token::Newline) {
+        loop {
+            match self.current().clone() {
+                Token::Newline => {}
+                Token::DocComment(text) => {
+                    // Lines separated by code, like one inside an expression, don't join
+                    if !self.docs_precede() {
+                        self.docs.clear();
+                    }
+                    self.docs.push(text);
+                    self.docs_end = self.pos + 1;
+                }
+                Token::ModuleDoc(text) => self.module_docs.push(text),
+                _ => break,
+            }
             self.advance();
         }
     }
 
+    /// Whether only newlines separate the `///` lines seen last from the current token.
+    fn docs_precede(&self) -> bool {
+        self.tokens[self.docs_end.min(self.pos)..self.pos]
+            .iter()
+            .all(|token| matches!(token, Token::Newline))
+    }
+
     fn is_at_end(&mut self) -> bool {
         self.skip_newlines();
         matches!(self.current(), Token::Eof)
     }
 
+    /// Builds an error located at the current token.
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        self.error_at(self.pos, message)
+    }
+
+    /// Error for a delimiter at token `open` that is still open at the end of
+    /// the file, spanning everything from the delimiter onwards.
+    fn unclosed(&self, open: usize, delimiter: char) -> Diagnostic {
+        let message = format!("Unclosed '{}'", delimiter);
+        match (self.spans.get(open), self.spans.get(self.pos)) {
+            (Some(start), Some(end)) => {
+                Diagnostic::error(message, start.line).with_span(start.to(end))
+            }
+            _ => Diagnostic::error(message, self.current_line()),
+        }
+    }
+
+    fn error_at(&self, pos: usize, message: impl Into<String>) -> Diagnostic {
+        match self.spans.get(pos) {
+            Some(span) => Diagnostic::error(message, span.line).with_span(*span),
+            None => Diagnostic::error(message, self.current_line()),
+        }
+    }
+
     fn current_line(&self) -> usize {
         let mut line = 1;
         for t in self.tokens.iter().take(self.pos) {
@@ -323,3 +911,7 @@ impl Parser {
         line
     }
 }
+
+fn join_docs(lines: Vec<String>) -> Option<String> {
+    (!lines.is_empty()).then(|| lines.join("\n"))
+}
@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+
+/// Supplies the source of imported modules, so hosts can keep them somewhere
+/// other than the filesystem, such as a database or a bundle.
+pub trait ModuleResolver {
+    /// The source of the module at `path`, or why it can't be loaded. An import
+    /// inside a module is relative to that module, the compiler joins the two
+    /// before asking, so `path` is always relative to the program's directory.
+    fn resolve(&self, path: &str) -> Result<String, String>;
+}
+
+/// Reads modules from files under `root`, adding the `.n` extension when the
+/// import leaves it out.
+pub struct FileResolver {
+    root: PathBuf,
+}
+
+impl FileResolver {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Default for FileResolver {
+    fn default() -> Self {
+        Self::new(".")
+    }
+}
+
+impl ModuleResolver for FileResolver {
+    fn resolve(&self, path: &str) -> Result<String, String> {
+        let mut file = self.root.join(path);
+        if file.extension().is_none() {
+            file.set_extension("n");
+        }
+        std::fs::read_to_string(&file)
+            .map_err(|err| format!("Cannot read module '{}': {}", path, err))
+    }
+}
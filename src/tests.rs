@@ -1,5 +1,21 @@
-use crate::runtime::compile_and_run;
+use crate::builtins;
+use crate::compiler::Compiler;
+use crate::interpreter::VirtualMachine;
+use crate::lexer::{LexedSource, Lexer, dedent, tokenize};
+use crate::parser::Parser;
+use crate::resolver::ModuleResolver;
+use crate::runtime::{compile_and_run, debug_json, run_tests, token_cache_hits, tokenize_cached};
+use crate::types::ast::{
+    BinaryOp, Expr, InterpPart, MatchArm, Param, Pattern, Program, Stmt, UnaryOp,
+};
+use crate::types::compiler::{ByteCode, CompileOptions, HeapObject, Instruction, MapKey, Value};
+use crate::types::constants::{Precedence, PrecedenceTable, TOKEN_CACHE_CAPACITY};
+use crate::types::diagnostic::{Diagnostic, Severity, Span};
+use crate::types::token::Token;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::path::Path;
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct TestResult {
@@ -29,110 +45,2860 @@ pub fn run_n_file(file_path: &str) -> TestResult {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+fn parse_source(source: &str) -> Result<Program, Diagnostic> {
+    Parser::from_source(source).parse()
+}
 
-    #[test]
-    fn test_basic_arithmetic() {
-        let result = run_n_file("tests/basic_arithmetic.n");
-        assert!(
-            result.passed,
-            "Basic arithmetic test failed: {}",
-            result.output
-        );
+struct MapResolver(HashMap<&'static str, &'static str>);
+
+impl ModuleResolver for MapResolver {
+    fn resolve(&self, path: &str) -> Result<String, String> {
+        self.0
+            .get(path)
+            .map(|source| source.to_string())
+            .ok_or_else(|| format!("No module named '{}'", path))
     }
+}
 
-    #[test]
-    fn test_comparison_operators() {
-        let result = run_n_file("tests/comparison_operators.n");
-        assert!(
-            result.passed,
-            "Comparison operators test failed: {}",
-            result.output
-        );
+fn run_source(source: &str) -> Result<VirtualMachine, String> {
+    run_source_with(source, |vm| vm)
+}
+
+/// Like `run_source`, with the VM passed through `configure` before it runs.
+fn run_source_with(
+    source: &str,
+    configure: impl FnOnce(VirtualMachine) -> VirtualMachine,
+) -> Result<VirtualMachine, String> {
+    let program = parse_source(source).map_err(|e| e.to_string())?;
+    let mut compiler = Compiler::new();
+    let bytecode = compiler.compile(&program).map_err(|e| e.to_string())?;
+    let mut vm = configure(VirtualMachine::new(bytecode, compiler));
+    vm.run()?;
+    Ok(vm)
+}
+
+#[test]
+fn test_basic_arithmetic() {
+    let result = run_n_file("tests/basic_arithmetic.n");
+    assert!(
+        result.passed,
+        "Basic arithmetic test failed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_comparison_operators() {
+    let result = run_n_file("tests/comparison_operators.n");
+    assert!(
+        result.passed,
+        "Comparison operators test failed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_string_operations() {
+    let result = run_n_file("tests/string_operations.n");
+    assert!(
+        result.exit_code != -1,
+        "String operations test crashed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_function_definitions() {
+    let result = run_n_file("tests/function_definitions.n");
+    assert!(
+        result.exit_code != -1,
+        "Function definitions test crashed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_complex_expressions() {
+    let result = run_n_file("tests/complex_expressions.n");
+    assert!(
+        result.passed,
+        "Complex expressions test failed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_heap_stress() {
+    let result = run_n_file("tests/heap_stress.n");
+    assert!(result.passed, "Heap stress test failed: {}", result.output);
+}
+
+#[test]
+fn test_edge_cases() {
+    let result = run_n_file("tests/edge_cases.n");
+    assert!(
+        result.exit_code != -1,
+        "Edge cases test crashed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_nested_functions() {
+    let result = run_n_file("tests/nested_functions.n");
+    assert!(
+        result.passed,
+        "Nested functions test failed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_error_cases() {
+    let result = run_n_file("tests/error_cases.n");
+    assert!(
+        !result.passed,
+        "Error cases test should have failed but passed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_division_by_zero_detection() {
+    let result = run_n_file("tests/error_cases.n");
+    println!("{:?}", result);
+    assert_eq!(result.name, "error_cases");
+    assert!(!result.passed, "Division by zero should cause failure");
+}
+
+#[test]
+fn test_single_expression_without_trailing_newline() {
+    // The file is just `42`, its last token runs straight into the end of input
+    let result = run_n_file("tests/single_expression.n");
+    assert!(
+        result.passed,
+        "Single expression test failed: {}",
+        result.output
+    );
+    assert_eq!(result.output, "42");
+
+    let vm = run_source("1 + 2").unwrap();
+    assert_eq!(vm.result(), Value::Number(3.0));
+}
+
+#[test]
+fn test_empty_program_gives_nil() {
+    for file in ["tests/empty.n", "tests/comments_only.n"] {
+        let result = run_n_file(file);
+        assert!(result.passed, "{} failed: {}", file, result.output);
+        assert_eq!(result.output, "nil");
     }
 
-    #[test]
-    fn test_string_operations() {
-        let result = run_n_file("tests/string_operations.n");
-        assert!(
-            result.exit_code != -1,
-            "String operations test crashed: {}",
-            result.output
-        );
+    assert!(parse_source("").unwrap().statements.is_empty());
+    assert!(
+        parse_source("\n// only a comment\n")
+            .unwrap()
+            .statements
+            .is_empty()
+    );
+    let empty = Parser::from_tokens(Vec::new()).parse().unwrap();
+    assert!(empty.statements.is_empty());
+
+    // Nothing to run but HALT, or not even that
+    let mut compiler = Compiler::new();
+    let bytecode = compiler.compile(&empty).unwrap();
+    assert_eq!(bytecode.instructions, vec![Instruction::Halt]);
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.result(), Value::Nil);
+    let bytecode = ByteCode {
+        constants: Vec::new(),
+        functions: Vec::new(),
+        instructions: Vec::new(),
+        instruction_lines: Vec::new(),
+        instruction_columns: Vec::new(),
+    };
+    let mut vm = VirtualMachine::new(bytecode, Compiler::new());
+    vm.run().unwrap();
+    assert_eq!(vm.result(), Value::Nil);
+}
+
+#[test]
+fn test_runner_reports_each_test() {
+    // The failing test comes first, the one after it still runs
+    let report = run_tests("tests/test_runner.n").unwrap();
+    assert_eq!(report.passed(), 1);
+    assert_eq!(report.failed(), 1);
+    assert!(!report.success());
+    assert_eq!(
+        report.to_string(),
+        "test test_double_is_wrong ... FAILED: [line 6] Assertion failed: expected 7, got 6\n\
+         test test_double ... ok\n\
+         \n\
+         1 passed, 1 failed\n"
+    );
+}
+
+#[test]
+fn test_debug_json_parses_back() {
+    let json = debug_json("tests/single_expression.n").unwrap();
+    let stages: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(stages["source"], "42");
+    assert_eq!(
+        stages["tokens"],
+        serde_json::json!([
+            { "token": { "Number": 42.0 }, "span": { "start": 0, "end": 2, "line": 1, "column": 1 } },
+            { "token": "Eof", "span": { "start": 2, "end": 2, "line": 1, "column": 3 } },
+        ])
+    );
+
+    // The AST and bytecode read back as what the parser and compiler produce
+    let program = parse_source("42").unwrap();
+    let ast: Program = serde_json::from_value(stages["ast"].clone()).unwrap();
+    assert_eq!(ast, program);
+    let bytecode: ByteCode = serde_json::from_value(stages["bytecode"].clone()).unwrap();
+    assert_eq!(bytecode, Compiler::new().compile(&program).unwrap());
+}
+
+#[test]
+fn test_array_operations() {
+    let result = run_n_file("tests/array_operations.n");
+    assert!(
+        result.passed,
+        "Array operations test failed: {}",
+        result.output
+    );
+}
+
+#[test]
+fn test_division_is_not_a_comment() {
+    let tokens = Lexer::new("10 / 2 + 1".to_string()).tokenize();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Number(10.0),
+            Token::Divide,
+            Token::Number(2.0),
+            Token::Plus,
+            Token::Number(1.0),
+            Token::Eof,
+        ]
+    );
+}
+
+#[test]
+fn test_comments_are_skipped() {
+    let tokens = Lexer::new("1 // line\n2 /* block */ / 3".to_string()).tokenize();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Number(1.0),
+            Token::Newline,
+            Token::Number(2.0),
+            Token::Divide,
+            Token::Number(3.0),
+            Token::Eof,
+        ]
+    );
+}
+
+#[test]
+fn test_crlf_matches_lf() {
+    let lf = "let a = 1\nlet b = \"x\ny\"\n\n/// c\nlet c = a + b\n";
+    let crlf = lf.replace('\n', "\r\n");
+    let lf_tokens = Lexer::new(lf.to_string()).tokenize();
+    let crlf_tokens = Lexer::new(crlf.clone()).tokenize();
+    assert_eq!(lf_tokens, crlf_tokens);
+    assert_eq!(
+        crlf_tokens
+            .iter()
+            .filter(|t| matches!(t, Token::Newline))
+            .count(),
+        5
+    );
+
+    // Every token starts on the same line and column either way
+    let position = |source: &str| -> Vec<(Token, usize, usize)> {
+        let (tokens, spans) = Lexer::new(source.to_string()).tokenize_with_spans();
+        tokens
+            .into_iter()
+            .zip(spans)
+            .map(|(token, span)| (token, span.line, span.column))
+            .collect()
+    };
+    let lf_positions = position(lf);
+    assert_eq!(lf_positions, position(&crlf));
+    let lines: Vec<usize> = lf_positions.iter().map(|(_, line, _)| *line).collect();
+    assert_eq!(
+        lines,
+        [
+            1, 1, 1, 1, 1, 2, 2, 2, 2, 3, 4, 5, 5, 6, 6, 6, 6, 6, 6, 6, 7
+        ]
+    );
+
+    // So do the lines runtime errors report
+    let source = "let a = 1\n\nlet b = a / \"x\"\n";
+    let lf_error = run_source(source).err().unwrap();
+    assert_eq!(
+        lf_error,
+        run_source(&source.replace('\n', "\r\n")).err().unwrap()
+    );
+    assert!(lf_error.starts_with("[line 3]"), "{}", lf_error);
+}
+
+#[test]
+fn test_unterminated_string_recovers_at_next_line() {
+    let tokens = Lexer::new("let a = \"oops\nlet b = 2".to_string()).tokenize();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Let,
+            Token::Identifier("a".to_string()),
+            Token::Assign,
+            Token::Error("Unterminated string".to_string()),
+            Token::Newline,
+            Token::Let,
+            Token::Identifier("b".to_string()),
+            Token::Assign,
+            Token::Number(2.0),
+            Token::Eof,
+        ]
+    );
+
+    // Also when an embedded expression is never closed
+    let tokens = Lexer::new("let a = $\"value {1 +\nlet b = 2\nb".to_string()).tokenize();
+    assert_eq!(
+        tokens,
+        vec![
+            Token::Let,
+            Token::Identifier("a".to_string()),
+            Token::Assign,
+            Token::Error("Unterminated string".to_string()),
+            Token::Newline,
+            Token::Let,
+            Token::Identifier("b".to_string()),
+            Token::Assign,
+            Token::Number(2.0),
+            Token::Newline,
+            Token::Identifier("b".to_string()),
+            Token::Eof,
+        ]
+    );
+}
+
+#[test]
+fn test_import_multiple_modules() {
+    let program = parse_source("import \"IO\", \"Math\"\nlet x = 1").unwrap();
+    match &program.statements[0] {
+        Stmt::Import { modules, .. } => assert_eq!(modules, &["IO", "Math"]),
+        other => panic!("Expected import statement, got {:?}", other),
     }
 
-    #[test]
-    fn test_function_definitions() {
-        let result = run_n_file("tests/function_definitions.n");
-        assert!(
-            result.exit_code != -1,
-            "Function definitions test crashed: {}",
-            result.output
-        );
+    let mut compiler = Compiler::new();
+    compiler.compile(&program).unwrap();
+    assert_eq!(compiler.imports, vec!["IO", "Math"]);
+}
+
+#[test]
+fn test_import_rejects_non_string_module() {
+    assert!(parse_source("import \"IO\", Math").is_err());
+    assert!(parse_source("let x = 1\nimport \"IO\"").is_err());
+}
+
+#[test]
+fn test_tokenize_matches_manual_loop() {
+    let source = "func add(a, b) {\n    a + b\n}\nadd(1, 2)";
+    let mut lexer = Lexer::new(source.to_string());
+    let mut manual = Vec::new();
+    loop {
+        let token = lexer.next_token();
+        let is_eof = matches!(token, Token::Eof);
+        manual.push(token);
+        if is_eof {
+            break;
+        }
     }
 
-    #[test]
-    fn test_complex_expressions() {
-        let result = run_n_file("tests/complex_expressions.n");
-        assert!(
-            result.passed,
-            "Complex expressions test failed: {}",
-            result.output
-        );
+    let tokens = tokenize(source);
+    assert_eq!(tokens, manual);
+    assert_eq!(tokens.iter().filter(|t| matches!(t, Token::Eof)).count(), 1);
+}
+
+#[test]
+fn test_parser_constructors_agree() {
+    let source = "func double(x) {\n    x * 2\n}\nlet y = double(4)";
+    let from_tokens = Parser::from_tokens(tokenize(source)).parse().unwrap();
+    let from_source = Parser::from_source(source).parse().unwrap();
+    assert_eq!(from_tokens, from_source);
+    assert_eq!(from_source.statements.len(), 2);
+}
+
+#[test]
+fn test_call_arguments_bind_in_order() {
+    let vm = run_source(
+        "func sub(a, b) {\n    a - b\n}\nlet direct = sub(10, 4)\nlet piped = 10 |> sub(4)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("direct"), Some(Value::Number(6.0)));
+    assert_eq!(vm.global("piped"), Some(Value::Number(6.0)));
+}
+
+#[test]
+fn test_partial_application() {
+    let vm = run_source(
+        "func add(a, b) {\n    a + b\n}\nlet add5 = add(5)\nlet result = add5(3)\nlet piped = 1 |> add5",
+    )
+    .unwrap();
+    assert_eq!(vm.global("result"), Some(Value::Number(8.0)));
+    assert_eq!(vm.global("piped"), Some(Value::Number(6.0)));
+
+    let error = run_source("func add(a, b) {\n    a + b\n}\nadd(1, 2, 3)")
+        .err()
+        .unwrap();
+    assert!(error.contains("expects 2 arguments, got 3"), "{}", error);
+}
+
+#[test]
+fn test_curry_builtin() {
+    let vm = run_source(
+        "func add3(a, b, c) {\n    (a * 100) + (b * 10) + c\n}\nlet curried = curry(add3, 3)\nlet one = curried(1)\nlet two = one(2)\nlet result = two(3)\nlet chained = curried(4)(5)(6)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("result"), Some(Value::Number(123.0)));
+    assert_eq!(vm.global("chained"), Some(Value::Number(456.0)));
+
+    let error = run_source("func add3(a, b, c) {\n    a + b + c\n}\ncurry(add3, 2)")
+        .err()
+        .unwrap();
+    assert!(error.contains("takes 3 arguments"), "{}", error);
+}
+
+#[test]
+fn test_sort_builtin() {
+    let vm = run_source(
+        "func descending(a, b) {\n    b - a\n}\nfunc same(a, b) {\n    0\n}\nlet numbers = sort([3, 1, 2, 10])\nlet words = sort([\"pear\", \"apple\", \"fig\"])\nlet reversed = sort([3, 1, 2, 10], descending)\nlet stable = sort([\"b\", \"a\", \"c\"], same)",
+    )
+    .unwrap();
+    let formatted = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(formatted("numbers"), "[1, 2, 3, 10]");
+    assert_eq!(formatted("words"), "[\"apple\", \"fig\", \"pear\"]");
+    assert_eq!(formatted("reversed"), "[10, 3, 2, 1]");
+    assert_eq!(formatted("stable"), "[\"b\", \"a\", \"c\"]");
+
+    let error = run_source("sort([1, \"a\"])").err().unwrap();
+    assert!(error.contains("without a comparator"), "{}", error);
+
+    // A comparator that isn't a total order still gives a permutation
+    let vm = run_source(
+        "import \"Math\"\nfunc noise(a, b) {\n    Math.sin(a * 12.9898 + b * 78.233 + 1)\n}\nlet shuffled = sort(range(0, 500), noise)\nlet restored = sort(shuffled) == range(0, 500)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("restored"), Some(Value::Boolean(true)));
+
+    let error = run_source("func words(a, b) {\n    \"less\"\n}\nsort([2, 1], words)")
+        .err()
+        .unwrap();
+    assert!(
+        error.ends_with("sort comparator must return a number, got string"),
+        "{}",
+        error
+    );
+    let error = run_source(
+        "func picky(a, b) {\n    assert(a != 3, \"saw 3\")\n    a - b\n}\nsort([1, 2, 3, 4], picky)",
+    )
+    .err()
+    .unwrap();
+    assert!(error.ends_with("Assertion failed: saw 3"), "{}", error);
+}
+
+#[test]
+fn test_zip_enumerate_and_range() {
+    let vm = run_source(
+        "let pairs = zip([1, 2, 3], [\"a\", \"b\"])\nlet indexed = enumerate([\"x\", \"y\"])\nlet numbers = range(2, 5)\nlet empty = range(3, 3)",
+    )
+    .unwrap();
+    let formatted = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(formatted("pairs"), "[[1, \"a\"], [2, \"b\"]]");
+    assert_eq!(formatted("indexed"), "[[0, \"x\"], [1, \"y\"]]");
+    assert_eq!(formatted("numbers"), "[2, 3, 4]");
+    assert_eq!(formatted("empty"), "[]");
+
+    let vm = run_source(
+        "let top = range(9007199254740990, 9007199254740992)\nlet halves = range(0.5, 3)",
+    )
+    .unwrap();
+    assert_eq!(
+        vm.format_value(&vm.global("top").unwrap()),
+        "[9007199254740990, 9007199254740991]"
+    );
+    assert_eq!(
+        vm.format_value(&vm.global("halves").unwrap()),
+        "[0.5, 1.5, 2.5]"
+    );
+    for (call, message) in [
+        (
+            "range(9007199254740992, 9007199254740994)",
+            "between -2^53 and 2^53, got 9007199254740994",
+        ),
+        ("range(0, 2 ** 1024)", "between -2^53 and 2^53, got inf"),
+        (
+            "range(0, 100000000)",
+            "range would have 100000000 elements, more than the limit of 16777216",
+        ),
+    ] {
+        let error = run_source(call).err().unwrap();
+        assert!(error.ends_with(message), "{}", error);
     }
+}
+
+#[test]
+fn test_flatten_and_flat_map() {
+    let vm = run_source(
+        "func repeat_self(n) {\n    range(0, n)\n}\nlet flat = flatten([[1, 2], [], [3], 4])\nlet expanded = flat_map([1, 2, 3], repeat_self)",
+    )
+    .unwrap();
+    let formatted = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(formatted("flat"), "[1, 2, 3, 4]");
+    assert_eq!(formatted("expanded"), "[0, 0, 1, 0, 1, 2]");
+}
+
+#[test]
+fn test_set_operations() {
+    let vm = run_source(
+        "import \"Set\"\nlet a = Set.new() |> Set.add(1) |> Set.add(2) |> Set.add(1)\nlet b = Set.new([2, 3, 3])\nlet has_two = Set.contains(a, 2)\nlet has_three = Set.contains(a, 3)\nlet both = Set.union(a, b)\nlet common = Set.intersection(a, b)",
+    )
+    .unwrap();
+    let formatted = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(formatted("a"), "Set { 1, 2 }");
+    assert_eq!(formatted("b"), "Set { 2, 3 }");
+    assert_eq!(vm.global("has_two"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("has_three"), Some(Value::Boolean(false)));
+    assert_eq!(formatted("both"), "Set { 1, 2, 3 }");
+    assert_eq!(formatted("common"), "Set { 2 }");
+
+    let vm = run_source(
+        "import \"Set\"\nlet same = Set.new([1, 2]) == Set.new([2, 1])\nlet has_array = Set.contains(Set.new([1]), [1])",
+    )
+    .unwrap();
+    assert_eq!(vm.global("same"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("has_array"), Some(Value::Boolean(false)));
+
+    let error = run_source("import \"Set\"\nlet s = Set.new([[1]])")
+        .err()
+        .unwrap();
+    assert!(
+        error.contains("Set elements must be strings, numbers or booleans, got array"),
+        "{}",
+        error
+    );
+
+    let error = run_source("let s = Set.new()").err().unwrap();
+    assert!(error.contains("must be imported"), "{}", error);
+}
+
+#[test]
+fn test_host_value_conversion() {
+    let mut vm = run_source(
+        "let n = 4\nlet s = \"hi\"\nlet b = true\nlet a = [1, [\"x\"]]\nfunc f(x) {\n    x\n}\nlet g = f",
+    )
+    .unwrap();
+    let global = |vm: &VirtualMachine, name: &str| vm.global(name).unwrap();
+
+    assert_eq!(vm.as_number(&global(&vm, "n")), Ok(4.0));
+    assert_eq!(vm.as_string(&global(&vm, "s")), Ok("hi".to_string()));
+    assert_eq!(vm.as_bool(&global(&vm, "b")), Ok(true));
+
+    let array = vm.as_array(&global(&vm, "a")).unwrap();
+    assert_eq!(array[0].as_number(), Some(1.0));
+    assert_eq!(array[1].as_array().unwrap()[0].as_string(), Some("x"));
+
+    let map = vm.alloc(HeapObject::Object(
+        [(MapKey::from("key"), HeapObject::Boolean(false))]
+            .into_iter()
+            .collect(),
+    ));
+    assert_eq!(
+        vm.as_map(&map).unwrap().get(&MapKey::from("key")),
+        Some(&HeapObject::Boolean(false))
+    );
+
+    assert_eq!(
+        vm.as_number(&global(&vm, "s")),
+        Err("Expected number, got string".to_string())
+    );
+    assert_eq!(
+        vm.as_array(&global(&vm, "n")),
+        Err("Expected array, got number".to_string())
+    );
+    assert!(vm.as_string(&global(&vm, "g")).is_err());
+}
+
+#[test]
+fn test_map_keys() {
+    let vm = run_source(
+        "let m = { 1 = \"a\", 2.5 = \"b\", true = \"yes\", name = \"n\" }\nlet one = { 1 = \"a\" }[1]\nlet half = m[2.5]\nlet flag = m[true]\nlet field = m.name\nlet zero = { 0 = \"z\" }[-0]",
+    )
+    .unwrap();
+    let global = |name: &str| vm.as_string(&vm.global(name).unwrap()).unwrap();
+
+    assert_eq!(global("one"), "a");
+    assert_eq!(global("half"), "b");
+    assert_eq!(global("flag"), "yes");
+    assert_eq!(global("field"), "n");
+    assert_eq!(global("zero"), "z");
+
+    assert!(
+        run_source("let m = { 1 = 1 }\nlet x = m[\"1\"]")
+            .err()
+            .unwrap()
+            .ends_with("Key '1' not found in object")
+    );
+    assert!(
+        run_source("let m = { a = 1 }\nlet x = m[[1]]")
+            .err()
+            .unwrap()
+            .ends_with("Map keys must be strings, numbers or booleans, got array")
+    );
+    assert_eq!(
+        MapKey::from_heap_object(&HeapObject::Number(f64::NAN)),
+        Err("NaN cannot be used as a map key".to_string())
+    );
+}
 
-    #[test]
-    fn test_heap_stress() {
-        let result = run_n_file("tests/heap_stress.n");
-        assert!(result.passed, "Heap stress test failed: {}", result.output);
+#[test]
+fn test_to_number_radix() {
+    let vm = run_source(
+        "let binary = to_number(\"1010\", 2)\nlet decimal = to_number(\"42\", 10)\nlet hex = to_number(\"ff\", 16)\nlet plain = to_number(\"2.5\")\nlet invalid = to_number(\"12\", 2)",
+    )
+    .unwrap();
+
+    assert_eq!(vm.global("binary"), Some(Value::Number(10.0)));
+    assert_eq!(vm.global("decimal"), Some(Value::Number(42.0)));
+    assert_eq!(vm.global("hex"), Some(Value::Number(255.0)));
+    assert_eq!(vm.global("plain"), Some(Value::Number(2.5)));
+    assert_eq!(vm.global("invalid"), Some(Value::Nil));
+
+    assert!(
+        run_source("let x = to_number(\"1\", 1)")
+            .err()
+            .unwrap()
+            .ends_with("to_number radix must be an integer from 2 to 36, got 1")
+    );
+}
+
+#[test]
+fn test_calling_non_function() {
+    assert_eq!(
+        run_source("let x = 5\nx(1)").err(),
+        Some("[line 2] value of type number is not callable".to_string())
+    );
+    assert_eq!(
+        run_source("let s = \"hi\"\nlet y = s(1, 2)").err(),
+        Some("[line 2] value of type string is not callable".to_string())
+    );
+}
+
+#[test]
+fn test_indexing_non_indexable_values() {
+    assert_eq!(
+        run_source("let x = (5)[0]").err(),
+        Some("[line 1] type number is not indexable".to_string())
+    );
+    assert_eq!(
+        run_source("let b = true\nlet x = b[\"key\"]").err(),
+        Some("[line 2] type boolean is not indexable".to_string())
+    );
+}
+
+#[test]
+fn test_power_precedence() {
+    let program = parse_source("-2 ** 2").unwrap();
+    assert_eq!(
+        program.statements,
+        vec![Stmt::Expr(
+            Expr::Unary {
+                op: UnaryOp::Neg,
+                right: Box::new(Expr::Binary {
+                    left: Box::new(Expr::Number(2.0)),
+                    op: BinaryOp::Pow,
+                    right: Box::new(Expr::Number(2.0)),
+                }),
+            },
+            1
+        )]
+    );
+
+    let vm = run_source(
+        "let a = -2 ** 2\nlet b = (-2) ** 2\nlet c = 2 ** 3 ** 2\nlet d = 2 * 3 ** 2 + 1",
+    )
+    .unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(-4.0)));
+    assert_eq!(vm.global("b"), Some(Value::Number(4.0)));
+    assert_eq!(vm.global("c"), Some(Value::Number(512.0)));
+    assert_eq!(vm.global("d"), Some(Value::Number(19.0)));
+}
+
+#[test]
+fn test_predicate_and_bang_identifiers() {
+    assert_eq!(
+        tokenize("empty? sort! let! a != b"),
+        vec![
+            Token::Identifier("empty?".to_string()),
+            Token::Identifier("sort!".to_string()),
+            Token::LetBang,
+            Token::Identifier("a".to_string()),
+            Token::NotEqual,
+            Token::Identifier("b".to_string()),
+            Token::Eof,
+        ]
+    );
+    assert_eq!(
+        tokenize("a!=b"),
+        vec![
+            Token::Identifier("a".to_string()),
+            Token::NotEqual,
+            Token::Identifier("b".to_string()),
+            Token::Eof,
+        ]
+    );
+}
+
+#[test]
+fn test_block_string_is_dedented() {
+    let source = "let text = \"\"\"\n        first\n          nested\n\n        last\n    \"\"\"";
+    assert_eq!(
+        tokenize(source)[3],
+        Token::String("first\n  nested\n\nlast".to_string())
+    );
+    assert_eq!(dedent("\n\tone\n\ttwo\n"), "one\ntwo");
+    assert_eq!(
+        tokenize("\"\"\"\n  open")[0],
+        Token::Error("Unterminated block string".to_string())
+    );
+}
+
+#[test]
+fn test_format_builtin() {
+    let vm = run_source(
+        "let a = 1\nlet sum = format(\"{} + {} = {}\", a, 2, a + 2)\nlet braces = format(\"{{{}}} {}\", \"x\", [1, 2])",
+    )
+    .unwrap();
+    assert_eq!(
+        vm.global("sum"),
+        Some(Value::String("1 + 2 = 3".to_string()))
+    );
+    assert_eq!(
+        vm.global("braces"),
+        Some(Value::String("{x} [1, 2]".to_string()))
+    );
+
+    assert!(
+        run_source("let s = format(\"{} and {}\", 1)")
+            .err()
+            .unwrap()
+            .ends_with("format string has 2 placeholders but got 1 arguments")
+    );
+}
+
+#[test]
+fn test_join_and_chars() {
+    let vm = run_source(
+        "let joined = join([1, 2.5, \"x\"], \", \")\nlet letters = chars(\"héllo→\")\nlet rejoined = join(letters, \"\")",
+    )
+    .unwrap();
+    assert_eq!(
+        vm.global("joined"),
+        Some(Value::String("1, 2.5, x".to_string()))
+    );
+    let letters = vm.as_array(&vm.global("letters").unwrap()).unwrap();
+    assert_eq!(letters.len(), 6);
+    assert_eq!(letters[1].as_string(), Some("é"));
+    assert_eq!(letters[5].as_string(), Some("→"));
+    assert_eq!(
+        vm.global("rejoined"),
+        Some(Value::String("héllo→".to_string()))
+    );
+}
+
+#[test]
+fn test_if_let_desugars_to_match() {
+    let program = parse_source("if let n = 5 { n } else { 0 }").unwrap();
+    assert_eq!(
+        program.statements,
+        vec![Stmt::Expr(
+            Expr::Match {
+                value: Box::new(Expr::Number(5.0)),
+                arms: vec![
+                    MatchArm {
+                        pattern: Pattern::Binding("n".to_string()),
+                        body: vec![Stmt::Expr(Expr::Identifier("n".to_string()), 1)],
+                        line: 1,
+                        fallback: false,
+                    },
+                    MatchArm {
+                        pattern: Pattern::Wildcard,
+                        body: vec![Stmt::Expr(Expr::Number(0.0), 1)],
+                        line: 1,
+                        fallback: true,
+                    },
+                ],
+            },
+            1
+        )]
+    );
+}
+
+#[test]
+fn test_match_and_if_let() {
+    let vm = run_source(
+        r#"let x = 3
+let word = match x {
+    1 -> "one"
+    3 -> "three"
+    _ -> "other"
+}
+let shout = match "hi" {
+    "no" -> 0
+    y -> {
+        let z = y + "!"
+        z
     }
+}
+func double(v) {
+    match v {
+        0 -> "zero"
+        n -> n * 2
+    }
+}
+let zero = double(0)
+let eight = double(4)
+let matched = if let 3 = x { "three" } else { "not three" }
+let unmatched = if let 2 = x { "two" } else { "not two" }
+let bound = if let n = x { n + 1 }
+let missing = if let 6 = x { "six" }"#,
+    )
+    .unwrap();
 
-    #[test]
-    fn test_edge_cases() {
-        let result = run_n_file("tests/edge_cases.n");
-        assert!(
-            result.exit_code != -1,
-            "Edge cases test crashed: {}",
-            result.output
-        );
+    let global = |name: &str| vm.global(name).unwrap();
+    assert_eq!(global("word"), Value::String("three".to_string()));
+    assert_eq!(global("shout"), Value::String("hi!".to_string()));
+    assert_eq!(global("zero"), Value::String("zero".to_string()));
+    assert_eq!(global("eight"), Value::Number(8.0));
+    assert_eq!(global("matched"), Value::String("three".to_string()));
+    assert_eq!(global("unmatched"), Value::String("not two".to_string()));
+    assert_eq!(global("bound"), Value::Number(4.0));
+    assert_eq!(global("missing"), Value::Nil);
+    // Pattern bindings do not leak out of their branch
+    assert_eq!(vm.global("n"), None);
+    assert_eq!(vm.global("y"), None);
+}
+
+#[test]
+fn test_memoize_builtin() {
+    let vm = run_source(
+        r#"let fib = memoize(slow_fib)
+func slow_fib(n) {
+    match n < 2 {
+        true -> n
+        _ -> fib(n - 1) + fib(n - 2)
+    }
+}
+let result = fib(20)
+let again = fib(20)"#,
+    )
+    .unwrap();
+
+    assert_eq!(vm.global("result"), Some(Value::Number(6765.0)));
+    assert_eq!(vm.global("again"), Some(Value::Number(6765.0)));
+    // Without the cache slow_fib(20) would run 21891 times
+    assert_eq!(vm.memoized_calls(&vm.global("fib").unwrap()), Some(21));
+}
+
+#[test]
+fn test_tail_calls_are_marked() {
+    let source = r#"func count(n, acc) {
+    match n {
+        0 -> acc
+        _ -> count(n - 1, acc + 1)
+    }
+}
+func sum(n) {
+    match n {
+        0 -> 0
+        _ -> n + sum(n - 1)
+    }
+}
+let counted = count(10000, 0)
+let summed = sum(100)"#;
+    let program = parse_source(source).unwrap();
+    let bytecode = Compiler::new().compile(&program).unwrap();
+
+    let count_of = |instruction: Instruction| {
+        bytecode
+            .instructions
+            .iter()
+            .filter(|i| **i == instruction)
+            .count()
+    };
+    // The recursive call in `count` is in tail position, the one in `sum` feeds `+`
+    assert_eq!(count_of(Instruction::TailCall(0, 2)), 1);
+    assert_eq!(count_of(Instruction::TailCall(1, 1)), 0);
+    // The remaining calls are the recursion in `sum` and the two top-level calls
+    assert_eq!(count_of(Instruction::Call(1, 1)), 2);
+    assert_eq!(count_of(Instruction::Call(0, 2)), 1);
+
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.global("counted"), Some(Value::Number(10000.0)));
+    assert_eq!(vm.global("summed"), Some(Value::Number(5050.0)));
+}
+
+#[test]
+fn test_diagnostics_carry_location() {
+    let error = parse_source("let x = 1\nlet y = [1 2]").unwrap_err();
+    assert_eq!(error.severity, Severity::Error);
+    assert_eq!(error.message, "Invalid hanging literal: Number(2.0)");
+    assert_eq!((error.line, error.column), (2, 12));
+    assert_eq!(
+        error.span,
+        Span {
+            start: 21,
+            end: 22,
+            line: 2,
+            column: 12
+        }
+    );
+    assert_eq!(
+        error.to_string(),
+        "Invalid hanging literal: Number(2.0) at line 2"
+    );
+
+    let error = parse_source("let s = \"open\nlet t = 1").unwrap_err();
+    assert_eq!(error.message, "Unterminated string");
+    assert_eq!((error.line, error.column, error.span.start), (1, 9, 8));
+
+    let program = parse_source("let a = 1\nlet b = Set.new()").unwrap();
+    let error = Compiler::new().compile(&program).unwrap_err();
+    assert_eq!(error.severity, Severity::Error);
+    assert_eq!(
+        error.message,
+        "Module 'Set' must be imported before use: import \"Set\""
+    );
+    assert_eq!(error.line, 2);
+}
+
+#[test]
+fn test_unclosed_brace_underlines_whole_range() {
+    let source = "let a = 1\nfunc f() {\n  1 + 2\n";
+    let error = parse_source(source).unwrap_err();
+    assert_eq!(error.message, "Unclosed '{'");
+    assert_eq!((error.line, error.column), (2, 10));
+    assert_eq!(
+        error.underline(source),
+        "2 | func f() {\n  |          ^\n3 |   1 + 2\n  | ^^^^^^^\n"
+    );
+
+    // A span inside one line only underlines its own characters
+    let error = parse_source("let x = \"open").unwrap_err();
+    assert_eq!(
+        error.underline("let x = \"open"),
+        "1 | let x = \"open\n  |         ^^^^^\n"
+    );
+}
+
+#[test]
+fn test_program_value_is_last_expression() {
+    let vm = run_source("let x = 2\nx + 1\nx * 10\nlet y = 3").unwrap();
+    assert_eq!(vm.result(), Value::Number(20.0));
+
+    let vm = run_source("let x = 2").unwrap();
+    assert_eq!(vm.result(), Value::Nil);
+}
+
+#[test]
+fn test_unreachable_match_arms_warn() {
+    let program =
+        parse_source("let x = 2\nmatch x {\n    1 -> 10\n    _ -> 20\n    2 -> 30\n    1 -> 40\n}")
+            .unwrap();
+    let mut compiler = Compiler::new();
+    compiler.compile(&program).unwrap();
+    let messages: Vec<String> = compiler.warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Unreachable match arm, an earlier arm matches every value at line 5",
+            "Unreachable match arm, an earlier arm matches every value at line 6",
+        ]
+    );
+    assert!(
+        compiler
+            .warnings
+            .iter()
+            .all(|w| w.severity == Severity::Warning)
+    );
+
+    let program = parse_source("match 1 {\n    1 -> 10\n    1 -> 20\n    x -> x\n}").unwrap();
+    let mut compiler = Compiler::new();
+    compiler.compile(&program).unwrap();
+    assert_eq!(compiler.warnings.len(), 1);
+    assert!(compiler.warnings[0].message.ends_with("same pattern"));
+}
+
+#[test]
+fn test_assert_eq_compares_deeply() {
+    let vm = run_source(
+        "let a = assert_eq([1, [2, 3]], [1, [2, 3]])\nlet b = assert_eq({ x = 1, y = \"s\" }, { y = \"s\", x = 1 })",
+    )
+    .unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Nil));
+    assert_eq!(vm.global("b"), Some(Value::Nil));
+
+    let error = run_source("assert_eq({ x = 1, y = \"1\" }, { x = 1, y = 1 })")
+        .err()
+        .unwrap();
+    assert!(
+        error.ends_with("Assertion failed: expected { x = 1, y = 1 }, got { x = 1, y = \"1\" }"),
+        "{}",
+        error
+    );
+    let error = run_source("assert_eq(1 + 1, 3)").err().unwrap();
+    assert!(
+        error.ends_with("Assertion failed: expected 3, got 2"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_prelude_needs_no_import() {
+    let vm = run_source(
+        "let n = len([1, 2, 3])\nlet s = len(\"héllo\")\nlet t = typeof({ a = 1 })\nlet ok = assert(n == 3)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("n"), Some(Value::Number(3.0)));
+    assert_eq!(vm.global("s"), Some(Value::Number(5.0)));
+    assert_eq!(vm.global("t"), Some(Value::String("object".to_string())));
+    assert_eq!(vm.global("ok"), Some(Value::Nil));
+
+    let error = run_source("assert(1 > 2, \"math broke\")").err().unwrap();
+    assert!(error.ends_with("Assertion failed: math broke"), "{}", error);
+    let error = run_source("len(5)").err().unwrap();
+    assert!(
+        error.ends_with("len expects a string, array, set or object, got number"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_shadowing_prelude_warns() {
+    let source = "func len(x) {\n    42\n}\nlet n = len([1])";
+    let program = parse_source(source).unwrap();
+    let mut compiler = Compiler::new();
+    compiler.compile(&program).unwrap();
+    assert_eq!(compiler.warnings.len(), 1);
+    assert_eq!(
+        compiler.warnings[0].to_string(),
+        "'len' shadows the prelude builtin of the same name at line 1"
+    );
+
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.global("n"), Some(Value::Number(42.0)));
+}
+
+#[test]
+fn test_float_precision() {
+    let vm = run_source_with("let pi = 3.14159\nlet xs = [pi, 2.5]", |vm| {
+        vm.with_float_precision(3)
+    })
+    .unwrap();
+    let pi = vm.global("pi").unwrap();
+    assert_eq!(vm.format_value(&pi), "3.14");
+    let xs = vm.global("xs").unwrap();
+    assert_eq!(vm.format_value(&xs), "[3.14, 2.5]");
+
+    let vm = run_source("let pi = 3.14159").unwrap();
+    assert_eq!(vm.format_value(&vm.global("pi").unwrap()), "3.14159");
+}
+
+#[test]
+fn test_number_formatting_is_stable() {
+    let vm = run_source(
+        "let sum = 0.1 + 0.2\nlet big = 1000000 * 1000000 * 1000000 * 1000000\nlet tiny = 0.000001 / 1000\nlet whole = 3.0\nlet negative_zero = -1 * 0\nlet xs = [1, 2.5]",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("sum"), "0.30000000000000004");
+    assert_eq!(format("big"), "1000000000000000000000000");
+    assert_eq!(format("tiny"), "0.0000000009999999999999999");
+    assert_eq!(format("whole"), "3");
+    assert_eq!(format("negative_zero"), "0");
+
+    let vm = run_source_with("let whole = 3\nlet xs = [1, 2.5]", |vm| {
+        vm.with_decimal_point()
+    })
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("whole"), "3.0");
+    assert_eq!(format("xs"), "[1.0, 2.5]");
+}
+
+#[test]
+fn test_match_range_patterns() {
+    let source = "func size(n) {\n    match n {\n        0..=9 -> \"digit\"\n        10..100 -> \"small\"\n        -5..0 -> \"negative\"\n        _ -> \"big\"\n    }\n}\nlet a = size(9)\nlet b = size(10)\nlet c = size(99.5)\nlet d = size(100)\nlet e = size(-3)\nlet f = size(-5.5)";
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.global("a"), Some(Value::String("digit".to_string())));
+    assert_eq!(vm.global("b"), Some(Value::String("small".to_string())));
+    assert_eq!(vm.global("c"), Some(Value::String("small".to_string())));
+    assert_eq!(vm.global("d"), Some(Value::String("big".to_string())));
+    assert_eq!(vm.global("e"), Some(Value::String("negative".to_string())));
+    assert_eq!(vm.global("f"), Some(Value::String("big".to_string())));
+
+    let vm = run_source(
+        "let a = match \"hi\" {\n    0..10 -> 1\n    \"hi\" -> 2\n    _ -> 3\n}\nlet b = match [5] {\n    [0..=9] -> 1\n    _ -> 2\n}\nlet c = match [\"x\"] {\n    [0..=9] -> 1\n    _ -> 2\n}",
+    )
+    .unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(2.0)));
+    assert_eq!(vm.global("b"), Some(Value::Number(1.0)));
+    assert_eq!(vm.global("c"), Some(Value::Number(2.0)));
+
+    assert_eq!(
+        tokenize("1..=2 3.5"),
+        vec![
+            Token::Number(1.0),
+            Token::DotDotEqual,
+            Token::Number(2.0),
+            Token::Number(3.5),
+            Token::Eof
+        ]
+    );
+}
+
+#[test]
+fn test_match_string_literals() {
+    let source = "func answer(s) {\n    match s {\n        \"yes\" -> true\n        \"no\" -> false\n        _ -> \"unsure\"\n    }\n}\nlet a = answer(\"yes\")\nlet b = answer(join([\"n\", \"o\"], \"\"))\nlet c = answer(\"yes!\")\nlet d = answer(1)";
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("b"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("c"), Some(Value::String("unsure".to_string())));
+    assert_eq!(vm.global("d"), Some(Value::String("unsure".to_string())));
+}
+
+#[test]
+fn test_copy_on_assign() {
+    let source = "let xs = [1, 2, 3]\nfunc grow(ys) {\n    ys <- [4]\n}\nfunc keep(ys) {\n    ys\n}\nlet grown = grow(xs)\nlet kept = keep(xs)\nlet alias = xs";
+    let mut vm = run_source_with(source, |vm| vm.with_copy_on_assign()).unwrap();
+    assert_eq!(
+        vm.format_value(&vm.global("grown").unwrap()),
+        "[1, 2, 3, 4]"
+    );
+
+    // Changing the caller's array leaves the copies bound and passed from it alone
+    let xs = vm.global("xs").unwrap();
+    vm.replace_object(&xs, HeapObject::Array(vec![HeapObject::Number(9.0)]))
+        .unwrap();
+    assert_eq!(vm.format_value(&xs), "[9]");
+    assert_eq!(vm.format_value(&vm.global("alias").unwrap()), "[1, 2, 3]");
+    assert_eq!(vm.format_value(&vm.global("kept").unwrap()), "[1, 2, 3]");
+
+    // Without the flag they all share the one array
+    let mut vm = run_source(source).unwrap();
+    let xs = vm.global("xs").unwrap();
+    vm.replace_object(&xs, HeapObject::Array(vec![HeapObject::Number(9.0)]))
+        .unwrap();
+    assert_eq!(vm.format_value(&vm.global("alias").unwrap()), "[9]");
+    assert_eq!(vm.format_value(&vm.global("kept").unwrap()), "[9]");
+}
+
+#[test]
+fn test_parser_depth_limit() {
+    let nested = |depth: usize| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+
+    let error = parse_source(&nested(1_000)).unwrap_err();
+    assert_eq!(error.message, "Expression nested more than 256 levels deep");
+    assert!(parse_source(&nested(100)).is_ok());
+
+    let mut parser = Parser::from_source(&nested(10)).with_max_depth(10);
+    assert!(parser.parse().is_err());
+    let mut parser = Parser::from_source(&nested(9)).with_max_depth(10);
+    assert!(parser.parse().is_ok());
+}
+
+#[test]
+fn test_disabled_keywords_lex_as_identifiers() {
+    let source =
+        "let match = 2\nfunc async(x) {\n    x * match\n}\nlet s = $\"{match}\"\nasync(match + 1)";
+    assert!(Parser::from_source(source).parse().is_err());
+
+    let (tokens, spans) = Lexer::new(source.to_string())
+        .without_keywords(&["match", "async"])
+        .tokenize_with_spans();
+    assert_eq!(tokens[1], Token::Identifier("match".to_string()));
+    let program = Parser::with_spans(tokens, spans).parse().unwrap();
+    let mut compiler = Compiler::new();
+    let bytecode = compiler.compile(&program).unwrap();
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.result(), Value::Number(6.0));
+    assert_eq!(vm.global("s"), Some(Value::String("2".to_string())));
+
+    // Edited sources keep the keywords disabled
+    let mut lexed = LexedSource::without_keywords("let a = 1", &["match"]);
+    lexed.edit(4..5, "match");
+    assert_eq!(lexed.tokens[1], Token::Identifier("match".to_string()));
+    assert_eq!(
+        lexed.tokens,
+        Lexer::new(lexed.source.clone())
+            .without_keywords(&["match"])
+            .tokenize()
+    );
+}
+
+#[test]
+fn test_incremental_relex_matches_full_lex() {
+    let source = "let a = 1\nlet b = \"two\"\n\nfunc f(x) {\n    x * 2\n}\nlet c = f(a)\n";
+    let edits: [(std::ops::Range<usize>, &str); 6] = [
+        (8..9, "42"),            // change a number
+        (14..17, ""),            // delete part of a line
+        (10..10, "let z = 0\n"), // insert a whole line
+        (20..40, "/*"),          // unterminated comment swallows the rest
+        (0..0, "\"\"\"\n  x\n"), // open a block string at the start
+        (39..39, "\n\n"),        // add blank lines at the end
+    ];
+    for (range, text) in edits {
+        let mut lexed = LexedSource::new(source);
+        lexed.edit(range.clone(), text);
+
+        let chars: Vec<char> = source.chars().collect();
+        let expected: String = chars[..range.start]
+            .iter()
+            .chain(text.chars().collect::<Vec<char>>().iter())
+            .chain(chars[range.end..].iter())
+            .collect();
+        let (tokens, spans) = Lexer::new(expected.clone()).tokenize_with_spans();
+        assert_eq!(lexed.source, expected);
+        assert_eq!(lexed.tokens, tokens, "edit {:?} {:?}", range, text);
+        assert_eq!(lexed.spans, spans, "edit {:?} {:?}", range, text);
     }
 
-    #[test]
-    fn test_nested_functions() {
-        let result = run_n_file("tests/nested_functions.n");
-        assert!(
-            result.passed,
-            "Nested functions test failed: {}",
-            result.output
+    // Edits apply on top of each other
+    let mut lexed = LexedSource::new(source);
+    lexed.edit(4..5, "alpha");
+    lexed.edit(0..0, "import \"Set\"\n");
+    let expected = format!(
+        "import \"Set\"\n{}",
+        source.replacen("let a", "let alpha", 1)
+    );
+    assert_eq!(lexed.tokens, tokenize(&expected));
+
+    // Lexing indexes characters directly, so a large file lexes and edits in linear time
+    let large = "let value = [1, \"two\", 3.5] // note\n".repeat(20_000);
+    let mut lexed = LexedSource::new(&large);
+    let end = large.chars().count();
+    lexed.edit(end - 1..end, "\nlet last = 1\n");
+    assert_eq!(lexed.tokens.len(), 11 * 20_000 + 6);
+    assert_eq!(lexed.tokens, tokenize(&lexed.source));
+}
+
+#[test]
+fn test_token_cache_reuses_unchanged_source() {
+    let hits = token_cache_hits();
+    let (first, _) = tokenize_cached("let cached = 1");
+    assert_eq!(token_cache_hits(), hits);
+    let (second, _) = tokenize_cached("let cached = 1");
+    assert_eq!(token_cache_hits(), hits + 1);
+    assert_eq!(first, second);
+
+    let (changed, _) = tokenize_cached("let cached = 2");
+    assert_eq!(token_cache_hits(), hits + 1);
+    assert_eq!(changed, tokenize("let cached = 2"));
+
+    compile_and_run("tests/basic_arithmetic.n").unwrap();
+    compile_and_run("tests/basic_arithmetic.n").unwrap();
+    assert_eq!(token_cache_hits(), hits + 2);
+
+    // Once full, the cache drops the source it lexed longest ago
+    for n in 0..TOKEN_CACHE_CAPACITY {
+        tokenize_cached(&format!("let filler = {}", n));
+    }
+    let hits = token_cache_hits();
+    tokenize_cached("let cached = 1");
+    assert_eq!(token_cache_hits(), hits);
+    tokenize_cached(&format!("let filler = {}", TOKEN_CACHE_CAPACITY - 1));
+    assert_eq!(token_cache_hits(), hits + 1);
+}
+
+#[test]
+fn test_compile_options_disable_optimizations() {
+    let source = "func countdown(n) {\n    match n {\n        0 -> 0\n        _ -> countdown(n - 1)\n    }\n}\nlet x = (2 + 3) * -4 ** 2\nlet y = 1 / 0 + 1";
+    let program = parse_source(source).unwrap();
+
+    let optimized = Compiler::new().compile(&program).unwrap();
+    let unoptimized = Compiler::with_options(CompileOptions::unoptimized())
+        .compile(&program)
+        .unwrap();
+    assert!(
+        unoptimized.instructions.len() > optimized.instructions.len(),
+        "{} <= {}",
+        unoptimized.instructions.len(),
+        optimized.instructions.len()
+    );
+    assert!(
+        optimized
+            .instructions
+            .contains(&Instruction::Push(Value::Number(-80.0)))
+    );
+    assert!(
+        optimized
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::TailCall(..)))
+    );
+    assert!(
+        !unoptimized
+            .instructions
+            .iter()
+            .any(|i| matches!(i, Instruction::TailCall(..)))
+    );
+
+    // Division by zero is left for the VM to report
+    let error = run_source("let y = 1 / 0 + 1").err().unwrap();
+    assert!(error.ends_with("Division by zero"), "{}", error);
+}
+
+#[test]
+fn test_precedence_table_changes_grouping() {
+    let tokens = tokenize("1 + 2 * 3");
+    let default = Parser::from_tokens(tokens.clone()).parse().unwrap();
+    let mut precedences = PrecedenceTable::default();
+    precedences.set(Token::Plus, Precedence::Power);
+    let regrouped = Parser::from_tokens(tokens)
+        .with_precedences(precedences)
+        .parse()
+        .unwrap();
+
+    let number = |n: f64| Box::new(Expr::Number(n));
+    let binary = |left, op, right| Expr::Binary { left, op, right };
+    assert_eq!(
+        default.statements[0],
+        Stmt::Expr(
+            binary(
+                number(1.0),
+                BinaryOp::Add,
+                Box::new(binary(number(2.0), BinaryOp::Mul, number(3.0)))
+            ),
+            1
+        )
+    );
+    assert_eq!(
+        regrouped.statements[0],
+        Stmt::Expr(
+            binary(
+                Box::new(binary(number(1.0), BinaryOp::Add, number(2.0))),
+                BinaryOp::Mul,
+                number(3.0)
+            ),
+            1
+        )
+    );
+}
+
+#[test]
+fn test_doc_comments_attach_to_declarations() {
+    let source = "//! Geometry helpers\n\n/// Area of a square.\n/// `side` is a number.\nfunc area(side) {\n    /// Not attached to an expression\n    side * side\n}\n// plain comment\n//// also plain\nlet unit = 1\n/// The default size\nlet size = area(unit)";
+    let program = parse_source(source).unwrap();
+    assert_eq!(program.doc.as_deref(), Some("Geometry helpers"));
+
+    let docs: Vec<Option<&str>> = program
+        .statements
+        .iter()
+        .map(|stmt| match stmt {
+            Stmt::Func { doc, .. } | Stmt::Let { doc, .. } => doc.as_deref(),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        docs,
+        vec![
+            Some("Area of a square.\n`side` is a number."),
+            None,
+            Some("The default size"),
+        ]
+    );
+    let Stmt::Func { body, .. } = &program.statements[0] else {
+        panic!("expected a function");
+    };
+    assert!(matches!(body[0], Stmt::Expr(..)));
+
+    // `///` after code, or inside an expression, documents nothing
+    assert_eq!(
+        tokenize("x /// not a doc"),
+        vec![Token::Identifier("x".to_string()), Token::Eof]
+    );
+    let program = parse_source(
+        "let a = 1 /// trailing\nlet b = match a {\n    /// inside\n    _ -> 2\n}\nfunc c() {\n    3\n}\n    /// indented\nlet d = 4",
+    )
+    .unwrap();
+    let docs: Vec<Option<&str>> = program
+        .statements
+        .iter()
+        .map(|stmt| match stmt {
+            Stmt::Func { doc, .. } | Stmt::Let { doc, .. } => doc.as_deref(),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(docs, vec![None, None, None, Some("indented")]);
+}
+
+#[test]
+fn test_doc_listing() {
+    let program = parse_source(
+        "//! Geometry helpers\nimport \"Set\"\n/// Area of a square.\nfunc area(side) {\n    side * side\n}\nlet unit = 1\narea(unit)",
+    )
+    .unwrap();
+    assert_eq!(
+        crate::doc::render(&program),
+        "Geometry helpers\n\n### func area(side)\n\nArea of a square.\n\n### let unit\n"
+    );
+}
+
+#[test]
+fn test_duplicate_function_is_an_error() {
+    let program = parse_source("func foo() {\n    1\n}\n\nfunc foo(x) {\n    x\n}").unwrap();
+    let error = Compiler::new().compile(&program).unwrap_err();
+    assert_eq!(error.severity, Severity::Error);
+    assert_eq!(
+        error.to_string(),
+        "Function 'foo' is already defined at line 5"
+    );
+
+    let program = parse_source(
+        "func outer() {\n    func foo() {\n        1\n    }\n    func foo() {\n        2\n    }\n    foo()\n}",
+    )
+    .unwrap();
+    let error = Compiler::new().compile(&program).unwrap_err();
+    assert_eq!(error.line, 5);
+}
+
+#[test]
+fn test_nested_functions_are_local_helpers() {
+    let vm = run_source(
+        "func outer(a) {\n    func helper(x) {\n        x + a\n    }\n    func twice(x) {\n        helper(helper(x))\n    }\n    [helper(1), twice(1), 5 |> helper]\n}\nfunc adder(a) {\n    func helper(x) {\n        x + a\n    }\n    helper\n}\nfunc foo() {\n    func foo() {\n        1\n    }\n    foo() + 1\n}\nlet results = outer(10)\nlet add_two = adder(2)\nlet added = add_two(3)\nlet nested = foo()",
+    )
+    .unwrap();
+    assert_eq!(
+        vm.format_value(&vm.global("results").unwrap()),
+        "[11, 21, 15]"
+    );
+    assert_eq!(vm.global("added"), Some(Value::Number(5.0)));
+    assert_eq!(vm.global("nested"), Some(Value::Number(2.0)));
+
+    let program = parse_source(
+        "func outer(a) {\n    func helper(x) {\n        x + a\n    }\n    helper(1)\n}\nhelper(1)",
+    )
+    .unwrap();
+    let error = Compiler::new().compile(&program).unwrap_err();
+    assert_eq!(error.to_string(), "Undefined function 'helper' at line 7");
+}
+
+#[test]
+fn test_function_body_values() {
+    let vm = run_source(
+        "func empty() {\n}\nfunc expression(x) {\n    let y = x + 1\n    y * 2\n}\nfunc trailing_let(x) {\n    let y = x + 1\n}\nfunc trailing_func() {\n    func inner() {\n        1\n    }\n}\nlet a = empty()\nlet b = expression(2)\nlet c = trailing_let(2)\nlet d = trailing_func()",
+    )
+    .unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Nil));
+    assert_eq!(vm.global("b"), Some(Value::Number(6.0)));
+    assert_eq!(vm.global("c"), Some(Value::Nil));
+    assert_eq!(vm.global("d"), Some(Value::Nil));
+}
+
+#[test]
+fn test_array_contains_and_index_of() {
+    let vm = run_source(
+        "let xs = [1, \"two\", { x = 1, y = 2 }, [3]]\nlet has_two = contains(xs, \"two\")\nlet has_map = contains(xs, { y = 2, x = 1 })\nlet has_five = contains(xs, 5)\nlet map_at = index_of(xs, { x = 1, y = 2 })\nlet list_at = index_of(xs, [3])\nlet missing = index_of(xs, { x = 1 })",
+    )
+    .unwrap();
+    assert_eq!(vm.global("has_two"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("has_map"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("has_five"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("map_at"), Some(Value::Number(2.0)));
+    assert_eq!(vm.global("list_at"), Some(Value::Number(3.0)));
+    assert_eq!(vm.global("missing"), Some(Value::Number(-1.0)));
+}
+
+#[test]
+fn test_map_merge_and_remove() {
+    let vm = run_source(
+        "import \"Map\"\nlet a = { name = \"Alice\", age = 30 }\nlet b = { age = 31, 1 = true }\nlet merged = Map.merge(a, b)\nlet removed = Map.remove(a, \"age\")\nlet unchanged = Map.remove(a, \"missing\")",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("merged"), "{ name = \"Alice\", age = 31, 1 = true }");
+    assert_eq!(format("removed"), "{ name = \"Alice\" }");
+    assert_eq!(format("unchanged"), "{ name = \"Alice\", age = 30 }");
+    assert_eq!(format("a"), "{ name = \"Alice\", age = 30 }");
+    assert_eq!(format("b"), "{ age = 31, 1 = true }");
+}
+
+#[test]
+fn test_map_entries_in_for_loops() {
+    let vm = run_source(
+        "import \"Map\"\nfunc add(total, n) {\n    total + n\n}\nlet prices = { pear = 3, apple = 2, 10 = 5 }\nlet entries = Map.entries(prices)\nlet keys = for [key, _] in entries {\n    key\n}\nlet total = reduce(for [_, price] in Map.entries(prices) {\n    price\n}, add, 0)\nlet empty = Map.entries({})",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(
+        format("entries"),
+        "[[\"pear\", 3], [\"apple\", 2], [10, 5]]"
+    );
+    assert_eq!(format("keys"), "[\"pear\", \"apple\", 10]");
+    assert_eq!(vm.global("total"), Some(Value::Number(10.0)));
+    assert_eq!(format("empty"), "[]");
+
+    let error = run_source("for [a, b] in [[1, 2], [3]] {\n    a + b\n}")
+        .err()
+        .unwrap();
+    assert!(error.ends_with("No arm matched value [3]"), "{}", error);
+
+    // A missing `in` is reported as such, whatever the loop variable
+    let error = parse_source("for x foo {\n    x\n}").unwrap_err();
+    assert_eq!(error.message, "Expected In, found Identifier(\"foo\")");
+    let error = parse_source("for [x] foo {\n    x\n}").unwrap_err();
+    assert_eq!(error.message, "Expected In, found Identifier(\"foo\")");
+    let error = parse_source("for 1 in xs {\n    1\n}").unwrap_err();
+    assert!(
+        error.message.starts_with("Expected loop variable"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_generators_and_for_loops() {
+    let source = "func count(limit) {\n    for i in range(0, limit) {\n        yield i + 1\n    }\n}\nfunc sum(xs, i) {\n    match i == len(xs) {\n        true -> 0\n        _ -> xs[i] + sum(xs, i + 1)\n    }\n}\nlet firsts = for n in count(4) {\n    n\n}\nlet total = sum(firsts, 0)\nlet squares = for x in [1, 2, 3] {\n    x * x\n}\nlet nothing = for x in count(0) {\n    x\n}";
+    let vm = run_source(source).unwrap();
+    assert_eq!(
+        vm.format_value(&vm.global("firsts").unwrap()),
+        "[1, 2, 3, 4]"
+    );
+    assert_eq!(vm.global("total"), Some(Value::Number(10.0)));
+    assert_eq!(vm.format_value(&vm.global("squares").unwrap()), "[1, 4, 9]");
+    assert_eq!(vm.format_value(&vm.global("nothing").unwrap()), "[]");
+}
+
+#[test]
+fn test_suspended_generators_survive_collection() {
+    // Collections run while `keep` is suspended, the array it holds must outlive them
+    let source = "func keep() {\n    let kept = [\"a\", \"b\", \"c\"]\n    yield 0\n    yield kept\n}\nfunc churn(n) {\n    match n {\n        0 -> 0\n        _ -> churn(n - 1) + len([n, n, n, n, n, n, n, n])\n    }\n}\nlet seen = for v in keep() {\n    match v {\n        0 -> churn(300)\n        _ -> 0\n    }\n    v\n}";
+    let vm = run_source(source).unwrap();
+    assert_eq!(
+        vm.format_value(&vm.global("seen").unwrap()),
+        "[0, [\"a\", \"b\", \"c\"]]"
+    );
+}
+
+#[test]
+fn test_generators_resume_lazily() {
+    // Each resume runs up to the next yield, keeping the operands in progress
+    let source = "func pairs() {\n    let a = 10\n    let pending = [a, yield 1, yield 2]\n    yield len(pending)\n}\nlet gen = pairs()\nlet values = for v in gen {\n    v\n}\nlet again = for v in gen {\n    v\n}";
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.format_value(&vm.global("values").unwrap()), "[1, 2, 3]");
+    assert_eq!(vm.format_value(&vm.global("again").unwrap()), "[]");
+    assert_eq!(vm.type_name(&vm.global("gen").unwrap()), "generator");
+
+    let error = run_source("for x in 5 {\n    x\n}").err().unwrap();
+    assert!(error.ends_with("type number is not iterable"), "{}", error);
+    let program = parse_source("yield 1").unwrap();
+    let error = Compiler::new().compile(&program).unwrap_err();
+    assert_eq!(error.message, "yield is only allowed inside a function");
+}
+
+#[test]
+fn test_destructured_parameters() {
+    let program =
+        parse_source("func dist(scale, { x, y }) {\n    scale * (x * x + y * y)\n}").unwrap();
+    let Stmt::Func { params, .. } = &program.statements[0] else {
+        panic!("expected a function");
+    };
+    assert_eq!(
+        params,
+        &vec![
+            Param::Name("scale".to_string()),
+            Param::Fields(vec!["x".to_string(), "y".to_string()]),
+        ]
+    );
+    assert_eq!(
+        crate::doc::render(&program),
+        "### func dist(scale, { x, y })\n"
+    );
+
+    let source = "func dist(scale, { x, y }) {\n    scale * (x * x + y * y)\n}\nlet d = dist(2, { y = 4, x = 3, z = 0 })\nlet partial = dist(1)\nlet curried = partial({ x = 1, y = 1 })";
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.global("d"), Some(Value::Number(50.0)));
+    assert_eq!(vm.global("curried"), Some(Value::Number(2.0)));
+
+    let error = run_source("func dist({ x, y }) {\n    x + y\n}\ndist({ x = 1 })")
+        .err()
+        .unwrap();
+    assert!(error.contains("Key 'y' not found"), "{}", error);
+    let error = run_source("func dist({ x, y }) {\n    x + y\n}\ndist(5)")
+        .err()
+        .unwrap();
+    assert!(error.contains("line 1"), "{}", error);
+    assert!(parse_source("func dist({ x, 1 }) {\n    x\n}").is_err());
+}
+
+#[test]
+fn test_print_hook_captures_output() {
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let captured = Rc::clone(&lines);
+    run_source_with(
+        "let name = \"meow\"\nprint(\"hello\", name)\nprint([1, 2], 3.5)",
+        |mut vm| {
+            vm.on_print(Box::new(move |line| {
+                captured.borrow_mut().push(line.to_string())
+            }));
+            vm
+        },
+    )
+    .unwrap();
+    assert_eq!(*lines.borrow(), vec!["hello meow", "[1, 2] 3.5"]);
+}
+
+#[test]
+fn test_interpolation_parts() {
+    let program = parse_source("$\"a {x+1} b\"").unwrap();
+    assert_eq!(
+        program.statements,
+        vec![Stmt::Expr(
+            Expr::Interpolation {
+                parts: vec![
+                    InterpPart::Literal("a ".to_string()),
+                    InterpPart::Expr(
+                        Expr::Binary {
+                            left: Box::new(Expr::Identifier("x".to_string())),
+                            op: BinaryOp::Add,
+                            right: Box::new(Expr::Number(1.0)),
+                        },
+                        Span {
+                            start: 5,
+                            end: 8,
+                            line: 1,
+                            column: 6
+                        }
+                    ),
+                    InterpPart::Literal(" b".to_string()),
+                ],
+            },
+            1
+        )]
+    );
+
+    let vm = run_source(
+        "let name = \"Alice\"\nlet items = [1, 2]\nlet greeting = $\"Hi {name}, {len(items) * 2} {{items}} {format(\"<{}>\", items[0])}\"",
+    )
+    .unwrap();
+    assert_eq!(
+        vm.global("greeting"),
+        Some(Value::String("Hi Alice, 4 {items} <1>".to_string()))
+    );
+
+    // Errors inside an interpolation point at the embedded tokens
+    let error = parse_source("let x = 1\nlet s = $\"value: {x +} done\"").unwrap_err();
+    assert_eq!((error.line, error.column), (2, 22));
+    let error = parse_source("$\"a {} b\"").unwrap_err();
+    assert_eq!(error.message, "Expected expression in interpolation");
+}
+
+#[test]
+fn test_interpolation_errors_point_into_the_string() {
+    let error = run_source("let x = 1\nlet s = $\"value: {x - \"a\"}\"")
+        .err()
+        .unwrap();
+    assert_eq!(
+        error,
+        "[line 2, column 19] Cannot apply '-' to number and string"
+    );
+
+    // Code outside the string still only knows its line
+    let error = run_source("let x = 1\nlet s = $\"{x}\" + (x - \"a\")")
+        .err()
+        .unwrap();
+    assert_eq!(error, "[line 2] Cannot apply '-' to number and string");
+}
+
+#[test]
+fn test_interpolation_balances_braces() {
+    let program = parse_source("$\"{ {a=1}[\"a\"] }\"").unwrap();
+    let Stmt::Expr(Expr::Interpolation { parts }, _) = &program.statements[0] else {
+        panic!("expected an interpolation");
+    };
+    assert_eq!(
+        parts,
+        &vec![InterpPart::Expr(
+            Expr::Index {
+                object: Box::new(Expr::Map {
+                    entries: vec![(Expr::String("a".to_string()), Expr::Number(1.0))],
+                }),
+                index: Box::new(Expr::String("a".to_string())),
+            },
+            Span {
+                start: 4,
+                end: 14,
+                line: 1,
+                column: 5
+            }
+        )]
+    );
+
+    let vm = run_source(
+        "let point = { x = 1, y = 2 }\nlet s = $\"<{ {a = point.x}[\"a\"] }> {\"}\"} {$\"[{point.y + 1} {\"}{\"}]\"} {\"\"\"{\"\"\"}\"",
+    )
+    .unwrap();
+    assert_eq!(
+        vm.global("s"),
+        Some(Value::String("<1> } [3 }{] {".to_string()))
+    );
+}
+
+#[test]
+fn test_math_clamp_sign_and_hypot() {
+    let vm = run_source(
+        "import \"Math\"\nlet high = Math.clamp(15, 0, 10)\nlet low = Math.clamp(-2, 0, 10)\nlet inside = Math.clamp(2.5, 0, 10)\nlet point = Math.clamp(7, 3, 3)\nlet signs = [Math.sign(-3), Math.sign(0), Math.sign(-0), Math.sign(0.5)]\nlet h = Math.hypot(3, 4)\nlet big = Math.hypot(10 ** 200, 10 ** 200) > 10 ** 200",
+    )
+    .unwrap();
+    assert_eq!(vm.global("high"), Some(Value::Number(10.0)));
+    assert_eq!(vm.global("low"), Some(Value::Number(0.0)));
+    assert_eq!(vm.global("inside"), Some(Value::Number(2.5)));
+    assert_eq!(vm.global("point"), Some(Value::Number(3.0)));
+    assert_eq!(
+        vm.format_value(&vm.global("signs").unwrap()),
+        "[-1, 0, 0, 1]"
+    );
+    assert_eq!(vm.global("h"), Some(Value::Number(5.0)));
+    assert_eq!(vm.global("big"), Some(Value::Boolean(true)));
+
+    let error = run_source("import \"Math\"\nMath.clamp(1, 10, 0)")
+        .err()
+        .unwrap();
+    assert!(
+        error.contains("Math.clamp lower bound 10 is greater than upper bound 0"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_math_trigonometry_and_logarithms() {
+    let vm = run_source(
+        "import \"Math\"\nlet sin0 = Math.sin(0)\nlet cos0 = Math.cos(0)\nlet loge = Math.log(Math.e)\nlet quarter = Math.atan2(1, 1) * 4\nlet half = Math.asin(1) * 2 == Math.pi\nlet bits = Math.log2(8) + Math.log10(1000)\nlet roundtrip = Math.exp(Math.log(5))\nlet outside = [Math.log(-1), Math.acos(2)]",
+    )
+    .unwrap();
+    assert_eq!(vm.global("sin0"), Some(Value::Number(0.0)));
+    assert_eq!(vm.global("cos0"), Some(Value::Number(1.0)));
+    assert_eq!(vm.global("loge"), Some(Value::Number(1.0)));
+    assert_eq!(
+        vm.global("quarter"),
+        Some(Value::Number(std::f64::consts::PI))
+    );
+    assert_eq!(vm.global("half"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("bits"), Some(Value::Number(6.0)));
+    let Some(Value::Number(roundtrip)) = vm.global("roundtrip") else {
+        panic!("expected a number");
+    };
+    assert!((roundtrip - 5.0).abs() < 1e-12);
+    assert_eq!(
+        vm.format_value(&vm.global("outside").unwrap()),
+        "[NaN, NaN]"
+    );
+
+    let error = run_source("Math.pi").err().unwrap();
+    assert!(
+        error.contains("Module 'Math' must be imported"),
+        "{}",
+        error
+    );
+    let error = run_source("import \"Math\"\nMath.sin").err().unwrap();
+    assert!(
+        error.contains("Module function 'Math.sin' must be called"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_io_lines_and_append() {
+    let path = std::env::temp_dir().join(format!("n_io_test_{}.txt", std::process::id()));
+    let path = path.to_string_lossy().replace('\\', "/");
+    std::fs::write(&path, "first\r\nsecond\n").unwrap();
+
+    let source = format!(
+        "import \"IO\"\nlet before = IO.lines(\"{0}\")\nIO.append(\"{0}\", \"third\n\")\nIO.append(\"{0}\", \"fourth\")\nlet after = IO.lines(\"{0}\")",
+        path
+    );
+    let result = run_source(&source);
+    let contents = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    let vm = result.unwrap();
+    assert_eq!(
+        vm.format_value(&vm.global("before").unwrap()),
+        "[\"first\", \"second\"]"
+    );
+    assert_eq!(
+        vm.format_value(&vm.global("after").unwrap()),
+        "[\"first\", \"second\", \"third\", \"fourth\"]"
+    );
+    assert_eq!(contents.unwrap(), "first\r\nsecond\nthird\nfourth");
+
+    let error = run_source(&format!("import \"IO\"\nIO.lines(\"{}.missing\")", path))
+        .err()
+        .unwrap();
+    assert!(error.contains("Cannot read"), "{}", error);
+}
+
+#[test]
+fn test_readline_from_input_source() {
+    let vm = run_source_with(
+        "import \"IO\"\nlet first = IO.readline()\nlet second = IO.readline()\nlet done = IO.readline()",
+        |mut vm| {
+            vm.set_input(Box::new(std::io::Cursor::new("hello\r\nworld\n")));
+            vm
+        },
+    )
+    .unwrap();
+    assert_eq!(vm.global("first"), Some(Value::String("hello".to_string())));
+    assert_eq!(
+        vm.global("second"),
+        Some(Value::String("world".to_string()))
+    );
+    assert_eq!(vm.global("done"), Some(Value::Nil));
+}
+
+#[test]
+fn test_env_module_with_injected_source() {
+    let vm = run_source_with(
+        "import \"Env\"\nlet set = Env.get(\"N_GREETING\")\nlet unset = Env.get(\"N_MISSING\")\nlet args = Env.args()",
+        |mut vm| {
+            vm.set_env(HashMap::from([(
+                "N_GREETING".to_string(),
+                "hello".to_string(),
+            )]));
+            vm.set_args(vec!["-v".to_string(), "input.txt".to_string()]);
+            vm
+        },
+    )
+    .unwrap();
+    assert_eq!(vm.global("set"), Some(Value::String("hello".to_string())));
+    assert_eq!(vm.global("unset"), Some(Value::Nil));
+    assert_eq!(
+        vm.format_value(&vm.global("args").unwrap()),
+        "[\"-v\", \"input.txt\"]"
+    );
+}
+
+#[test]
+fn test_stack_underflow_names_the_instruction() {
+    let bytecode = ByteCode {
+        constants: Vec::new(),
+        functions: Vec::new(),
+        instructions: vec![
+            Instruction::Push(Value::Number(1.0)),
+            Instruction::Pop,
+            Instruction::Push(Value::Number(2.0)),
+            Instruction::Add,
+            Instruction::Halt,
+        ],
+        instruction_lines: vec![1, 1, 3, 3, 3],
+        instruction_columns: vec![0; 5],
+    };
+    let mut vm = VirtualMachine::new(bytecode, Compiler::new());
+    assert_eq!(
+        vm.run().unwrap_err(),
+        "[line 3] Stack underflow at instruction 3 (ADD)"
+    );
+}
+
+#[test]
+fn test_dangling_heap_pointer_is_described() {
+    let vm = run_source("let xs = [1, 2]").unwrap();
+    let dangling = Value::HeapPointer(99);
+    assert_eq!(
+        vm.to_host(&dangling).unwrap_err(),
+        "Invalid heap pointer 99 during conversion to a host value (heap holds 1 objects)"
+    );
+    assert_eq!(
+        vm.array_elements(&dangling, "join").unwrap_err(),
+        "Invalid heap pointer 99 during join (heap holds 1 objects)"
+    );
+}
+
+#[test]
+fn test_run_compiled_program_twice() {
+    let program = parse_source(
+        "func square(x) {\n    x * x\n}\nlet xs = [square(2), square(3)]\n$\"{xs} {square(4)}\"",
+    )
+    .unwrap();
+    let bytecode = Compiler::new().compile(&program).unwrap();
+
+    for _ in 0..2 {
+        let vm = VirtualMachine::run_program(bytecode.clone()).unwrap();
+        assert_eq!(vm.format_value(&vm.result()), "[4, 9] 16");
+    }
+}
+
+#[test]
+fn test_reset_vm_runs_another_program() {
+    let compile = |source: &str| {
+        let program = parse_source(source).unwrap();
+        let mut compiler = Compiler::new();
+        let bytecode = compiler.compile(&program).unwrap();
+        (bytecode, compiler)
+    };
+
+    let (bytecode, compiler) = compile("let secret = [1, 2, 3]\nprint(secret)\nlen(secret)");
+    let mut vm = VirtualMachine::new(bytecode, compiler).with_float_precision(3);
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let captured = Rc::clone(&lines);
+    vm.on_print(Box::new(move |line| {
+        captured.borrow_mut().push(line.to_string())
+    }));
+    vm.run().unwrap();
+    assert_eq!(vm.result(), Value::Number(3.0));
+
+    let (bytecode, compiler) = compile("let other = 2 / 3\nprint(other)\nlet count = 0\ncount");
+    vm.load(bytecode, compiler);
+    assert_eq!(vm.result(), Value::Nil);
+    assert_eq!(vm.global("secret"), None);
+    vm.run().unwrap();
+    assert_eq!(vm.result(), Value::Number(0.0));
+    assert_eq!(vm.global("secret"), None);
+    assert_eq!(vm.format_value(&vm.global("other").unwrap()), "0.667");
+
+    // Running the same program again starts over
+    vm.reset();
+    vm.run().unwrap();
+    assert_eq!(*lines.borrow(), vec!["[1, 2, 3]", "0.667", "0.667"]);
+}
+
+#[test]
+fn test_instruction_limit_stops_infinite_loop() {
+    let run = |source: &str, limit: usize| {
+        run_source_with(source, |vm| vm.with_instruction_limit(limit)).map(|vm| vm.result())
+    };
+
+    let forever = "func forever(n) {\n    forever(n + 1)\n}\nforever(0)";
+    assert_eq!(
+        run(forever, 10_000).unwrap_err(),
+        "[line 2] Instruction limit of 10000 reached"
+    );
+    assert_eq!(run("1 + 2", 10_000), Ok(Value::Number(3.0)));
+}
+
+#[test]
+fn test_heap_limit_aborts_when_exceeded() {
+    let run = |source: &str| {
+        run_source_with(source, |vm| vm.with_heap_limit(500))
+            .map(|vm| vm.format_value(&vm.result()))
+    };
+
+    // Garbage is collected before the limit applies, so only live objects count
+    let churn = "func churn(n) {\n    match n {\n        0 -> 0\n        _ -> churn(n - 1 + len(range(0, 40)) - 40)\n    }\n}\nlet small = [1, 2, 3]\nlet kept = [small, small]\nchurn(20) + len(kept)";
+    assert_eq!(run(churn), Ok("2".to_string()));
+
+    let hoard = "let a = range(0, 40)\nlet b = range(0, 40)\nlet c = range(0, 40)\nlen(a) + len(b) + len(c)";
+    let error = run(hoard).unwrap_err();
+    assert!(
+        error.contains("Out of memory: the heap holds")
+            && error.ends_with("more than the limit of 500"),
+        "{}",
+        error
+    );
+
+    // The heap is checked every few instructions run, whatever their positions,
+    // so a loop too short to reach a checked position is still stopped
+    let grow = "func g(xs, n) {\n    g(xs <- [n], n + 1)\n}\ng([], 0)";
+    let error = run_source_with(grow, |vm| {
+        vm.with_heap_limit(500).with_instruction_limit(100_000)
+    })
+    .err()
+    .unwrap();
+    assert!(error.contains("Out of memory"), "{}", error);
+}
+
+#[test]
+fn test_canonical_disassembly() {
+    let program = parse_source(
+        "func fact(n) {\n    match n {\n        0 -> 1\n        _ -> n * fact(n - 1)\n    }\n}\nlet name = \"x\"\nprint(fact(5), name)",
+    )
+    .unwrap();
+    let bytecode = Compiler::new().compile(&program).unwrap();
+    let expected = [
+        "constants:",
+        "  [2] \"x\"",
+        "  [0] 0",
+        "  [1] 1",
+        "  [3] 5",
+        "code:",
+        "  JUMP L2",
+        "F0(n):",
+        "  LOAD_ARG 1",
+        "  LOAD_VAR 1 0",
+        "  STORE_VAR 1 1",
+        "  LOAD_VAR 1 1",
+        "  LOAD_CONST 0",
+        "  EQUAL",
+        "  JUMP_IF_FALSE L0",
+        "  LOAD_CONST 1",
+        "  JUMP L1",
+        "L0:",
+        "  LOAD_VAR 1 0",
+        "  LOAD_VAR 1 0",
+        "  LOAD_CONST 1",
+        "  SUB",
+        "  CALL F0 1",
+        "  MUL",
+        "  JUMP L1",
+        "  LOAD_VAR 1 1",
+        "  NO_MATCH",
+        "L1:",
+        "  RETURN",
+        "L2:",
+        "  LOAD_CONST \"x\"",
+        "  STORE_VAR 0 0",
+        "  LOAD_CONST 5",
+        "  CALL F0 1",
+        "  LOAD_VAR 0 0",
+        "  CALL_GLOBAL print 2",
+        "  HALT",
+    ];
+    assert_eq!(bytecode.canonical(), expected.join("\n") + "\n");
+
+    // Unrelated constants earlier in the file shift indices and offsets, not the listing
+    let shifted = parse_source("let unused = \"padding\"").unwrap();
+    let mut statements = shifted.statements;
+    statements.extend(program.statements);
+    let shifted = Compiler::new()
+        .compile(&Program {
+            statements,
+            doc: None,
+        })
+        .unwrap()
+        .canonical();
+    assert!(shifted.contains(&expected[6..30].join("\n")), "{}", shifted);
+}
+
+#[test]
+fn test_modules_share_a_constant_pool() {
+    let first = parse_source("let greeting = \"shared\"\nlet a = \"only a\"").unwrap();
+    let first = Compiler::new().compile(&first).unwrap();
+
+    let second = parse_source("let message = \"shared\"\nlet b = \"only b\"").unwrap();
+    let mut compiler = Compiler::new().with_constants(first.constants.clone());
+    let second = compiler.compile(&second).unwrap();
+    let shared = Value::String("shared".to_string());
+    assert_eq!(
+        second.constants,
+        vec![
+            shared.clone(),
+            Value::String("only a".to_string()),
+            Value::String("only b".to_string()),
+        ]
+    );
+    assert_eq!(first.instructions[0], Instruction::LoadConst(0));
+    assert_eq!(second.instructions[0], Instruction::LoadConst(0));
+
+    let mut vm = VirtualMachine::new(second, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.global("message"), Some(shared));
+    assert_eq!(vm.global("b"), Some(Value::String("only b".to_string())));
+}
+
+#[test]
+fn test_in_memory_module_resolver() {
+    let resolver = || {
+        MapResolver(HashMap::from([
+            (
+                "geometry",
+                "import \"units\", \"Math\"\nfunc area(side) {\n    side * side * scale\n}\nfunc diagonal(side) {\n    Math.hypot(side, side)\n}",
+            ),
+            ("units", "import \"geometry\"\nlet scale = 2"),
+        ]))
+    };
+
+    let program =
+        parse_source("import \"geometry\"\nlet a = area(3)\nlet d = diagonal(3)").unwrap();
+    let mut compiler = Compiler::new().with_resolver(resolver());
+    let bytecode = compiler.compile(&program).unwrap();
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(18.0)));
+    assert_eq!(vm.global("d"), Some(Value::Number(3f64.hypot(3.0))));
+
+    let program = parse_source("\nimport \"shapes\"").unwrap();
+    let error = Compiler::new()
+        .with_resolver(resolver())
+        .compile(&program)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "No module named 'shapes' at line 2");
+
+    // Imports inside a module are relative to the module's own directory
+    let nested = MapResolver(HashMap::from([
+        (
+            "lib/shapes",
+            "import \"units\", \"../config\"\nlet side = unit * size",
+        ),
+        ("lib/units", "let unit = 2"),
+        ("config", "let size = 5"),
+        ("units", "let unit = 100"),
+    ]));
+    let program = parse_source("import \"lib/shapes\"\nlet s = side").unwrap();
+    let mut compiler = Compiler::new().with_resolver(nested);
+    let bytecode = compiler.compile(&program).unwrap();
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.global("s"), Some(Value::Number(10.0)));
+}
+
+#[test]
+fn test_circular_lets_are_an_error() {
+    let compile = |source: &str| Compiler::new().compile(&parse_source(source).unwrap());
+
+    let error = compile("let a = b\nlet b = a").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Circular definition: a -> b -> a at line 1"
+    );
+    let error =
+        compile("let x = 1\nlet a = [x, c]\nlet b = { k = a }\nlet c = len(b)").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Circular definition: a -> c -> b -> a at line 2"
+    );
+    let error = compile("let a = a + 1").unwrap_err();
+    assert_eq!(error.to_string(), "Circular definition: a -> a at line 1");
+
+    // Forward references to functions, and names bound inside the value, are fine
+    assert!(compile("let a = twice(2)\nfunc twice(x) {\n    x * 2\n}").is_ok());
+    assert!(compile("let b = match 1 {\n    b -> b\n}\nlet c = for c in [b] {\n    c\n}").is_ok());
+}
+
+#[test]
+fn test_logical_operators_short_circuit() {
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let captured = Rc::clone(&lines);
+    run_source_with(
+        "func loud(x) {\n    print(\"evaluated\")\n    x\n}\nprint(false && loud(true))\nprint(true || loud(false))\nprint(true && loud(false))\nprint(false || loud(true))\nprint(1 < 2 && 3 < 2 || 2 < 3)",
+        |mut vm| {
+            vm.on_print(Box::new(move |line| {
+                captured.borrow_mut().push(line.to_string())
+            }));
+            vm
+        },
+    )
+    .unwrap();
+    assert_eq!(
+        *lines.borrow(),
+        vec![
+            "false",
+            "true",
+            "evaluated",
+            "false",
+            "evaluated",
+            "true",
+            "true"
+        ]
+    );
+}
+
+#[test]
+fn test_array_unique_and_group_by() {
+    let vm = run_source(
+        "let deduped = unique([3, 1, 3, { a = 1 }, 2, 1, { a = 1 }, [2]])\nfunc parity(n) {\n    match n < 2 {\n        true -> match n == 0 {\n            true -> \"even\"\n            _ -> \"odd\"\n        }\n        _ -> parity(n - 2)\n    }\n}\nlet groups = group_by([1, 2, 3, 4, 5], parity)\nlet empty = group_by([], parity)",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("deduped"), "[3, 1, { a = 1 }, 2, [2]]");
+    assert_eq!(format("groups"), "{ odd = [1, 3, 5], even = [2, 4] }");
+    assert_eq!(format("empty"), "{  }");
+}
+
+#[test]
+fn test_reduce_reduce_right_and_scan() {
+    let vm = run_source(
+        "func digits(acc, x) {\n    acc * 10 + x\n}\nlet left = reduce([1, 2, 3], digits, 0)\nlet right = reduce_right([1, 2, 3], digits, 0)\nlet steps = scan([1, 2, 3], digits, 0)\nlet none = scan([], digits, 0)\nlet initial = reduce_right([], digits, 7)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("left"), Some(Value::Number(123.0)));
+    assert_eq!(vm.global("right"), Some(Value::Number(321.0)));
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("steps"), "[1, 12, 123]");
+    assert_eq!(format("none"), "[]");
+    assert_eq!(vm.global("initial"), Some(Value::Number(7.0)));
+}
+
+#[test]
+fn test_find_any_and_all_stop_early() {
+    // Each wrapper counts the calls that reach the predicate
+    let vm = run_source(
+        "func big(x) {\n    x > 2\n}\nlet find_big = memoize(big)\nlet any_big = memoize(big)\nlet all_big = memoize(big)\nlet found = find([1, 3, 4, 5], find_big)\nlet missing = find([1, 2], big)\nlet some = any([1, 2, 3, 4], any_big)\nlet none = any([], big)\nlet every = all([3, 1, 4, 5], all_big)\nlet vacuous = all([], big)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("found"), Some(Value::Number(3.0)));
+    assert_eq!(vm.global("missing"), Some(Value::Nil));
+    assert_eq!(vm.global("some"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("none"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("every"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("vacuous"), Some(Value::Boolean(true)));
+    let calls = |name: &str| vm.memoized_calls(&vm.global(name).unwrap());
+    assert_eq!(calls("find_big"), Some(2));
+    assert_eq!(calls("any_big"), Some(3));
+    assert_eq!(calls("all_big"), Some(2));
+
+    let error = run_source("func half(x) {\n    x / 2\n}\nany([1], half)")
+        .err()
+        .unwrap();
+    assert!(error.ends_with("Expected boolean, got number"), "{}", error);
+}
+
+#[test]
+fn test_list_helpers_are_prelude_builtins() {
+    for name in [
+        "sort",
+        "zip",
+        "enumerate",
+        "range",
+        "flatten",
+        "flat_map",
+        "find",
+        "any",
+        "all",
+        "contains",
+        "index_of",
+        "unique",
+        "group_by",
+        "fill",
+        "repeat",
+        "slice",
+        "chunk",
+        "windows",
+        "reduce",
+        "reduce_right",
+        "scan",
+        "join",
+    ] {
+        assert!(builtins::is_prelude(name), "{} isn't in the prelude", name);
+    }
+    assert!(!builtins::is_module("Array"));
+
+    let vm = run_source(
+        "func wanted(x) {\n    contains([2, 3], x)\n}\nlet found = find([1, 2, 3], wanted)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("found"), Some(Value::Number(2.0)));
+}
+
+#[test]
+fn test_equality_across_types() {
+    let vm = run_source(
+        "func double(x) {\n    x * 2\n}\nfunc triple(x) {\n    x * 3\n}\nfunc add(a, b) {\n    a + b\n}\nlet f = double\nlet none = to_number(\"x\")\nlet same_function = f == double\nlet other_function = double == triple\nlet function_number = double != 2\nlet same_partial = add(1) == add(1)\nlet other_partial = add(1) == add(2)\nlet nil_nil = none == to_number(\"y\")\nlet nil_zero = none == 0\nlet nil_false = none != false\nlet number_string = 1 == \"1\"\nlet string_bool = \"true\" != true\nlet lists = [1, [2]] == [1, [2]]\nlet maps = { a = 1, b = 2 } == { b = 2, a = 1 }\nlet list_map = [] == {}\nlet numbers = 2 != 3",
+    )
+    .unwrap();
+    for (name, expected) in [
+        ("same_function", true),
+        ("other_function", false),
+        ("function_number", true),
+        ("same_partial", true),
+        ("other_partial", false),
+        ("nil_nil", true),
+        ("nil_zero", false),
+        ("nil_false", true),
+        ("number_string", false),
+        ("string_bool", true),
+        ("lists", true),
+        ("maps", true),
+        ("list_map", false),
+        ("numbers", true),
+    ] {
+        assert_eq!(vm.global(name), Some(Value::Boolean(expected)), "{}", name);
+    }
+}
+
+#[test]
+fn test_ordering_collections_is_an_error() {
+    let error = run_source("let a = [1] < [2]").err().unwrap();
+    assert_eq!(
+        error,
+        "[line 1] Cannot order array and array - only numbers can be compared with <, >, <= and >="
+    );
+    let error = run_source("let a = 1\nlet b = { x = 1 } >= a")
+        .err()
+        .unwrap();
+    assert!(
+        error.contains("Cannot order object and number"),
+        "{}",
+        error
+    );
+
+    let vm = run_source("let le = 2 <= 2\nlet ge = 1 >= 2\nlet lt = -1 < 0").unwrap();
+    assert_eq!(vm.global("le"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("ge"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("lt"), Some(Value::Boolean(true)));
+
+    // Every ordering comparison with NaN is false
+    let vm = run_source(
+        "import \"Number\"\nlet nan = Number.parse(\"NaN\")\nlet le = nan <= 1\nlet ge = nan >= 1\nlet lt = nan < 1\nlet gt = 1 > nan\nlet in_range = match nan {\n    0..=9 -> true\n    _ -> false\n}",
+    )
+    .unwrap();
+    for name in ["le", "ge", "lt", "gt", "in_range"] {
+        assert_eq!(vm.global(name), Some(Value::Boolean(false)), "{}", name);
+    }
+}
+
+#[test]
+fn test_modules_only_share_exported_names() {
+    let resolver = || {
+        MapResolver(HashMap::from([
+            (
+                "shapes",
+                "export func area(side) {\n    side * scale(side)\n}\nfunc scale(x) {\n    x\n}\nexport let unit = 1\nlet secret = 2",
+            ),
+            ("plain", "func helper(x) {\n    x + 1\n}"),
+        ]))
+    };
+    let compile = |source: &str| {
+        Compiler::new()
+            .with_resolver(resolver())
+            .compile(&parse_source(source).unwrap())
+    };
+
+    let program = parse_source("import \"shapes\"\nlet a = area(3) + unit").unwrap();
+    let mut compiler = Compiler::new().with_resolver(resolver());
+    let bytecode = compiler.compile(&program).unwrap();
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(10.0)));
+
+    let error = compile("import \"shapes\"\nlet a = 1\nlet b = scale(a)").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "'scale' is not exported by module 'shapes' at line 3"
+    );
+    let error = compile("import \"shapes\"\nfunc f() {\n    secret\n}").unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "'secret' is not exported by module 'shapes' at line 2"
+    );
+    // Local bindings may reuse private names, and modules without exports share everything
+    assert!(compile("import \"shapes\"\nfunc f(secret) {\n    secret\n}").is_ok());
+
+    // The importer's own declarations don't clash with the module's private ones
+    let program = parse_source(
+        "import \"shapes\"\nfunc scale(x) {\n    x * 100\n}\nlet secret = 7\nlet a = area(3)\nlet b = scale(2) + secret",
+    )
+    .unwrap();
+    let mut compiler = Compiler::new().with_resolver(resolver());
+    let bytecode = compiler.compile(&program).unwrap();
+    let mut vm = VirtualMachine::new(bytecode, compiler);
+    vm.run().unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(9.0)));
+    assert_eq!(vm.global("b"), Some(Value::Number(207.0)));
+    assert!(compile("import \"plain\"\nlet b = helper(1)").is_ok());
+
+    let error = parse_source("export 1").unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Expected 'let' or 'func' after 'export'")
+    );
+    let error = parse_source("func f() {\n    export let x = 1\n}").unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("Only top-level declarations can be exported")
+    );
+}
+
+#[test]
+fn test_match_evaluates_scrutinee_once() {
+    let calls = Rc::new(RefCell::new(0));
+    let counter = Rc::clone(&calls);
+    let vm = run_source_with(
+        "func expensive() {\n    print(\"called\")\n    7\n}\nlet result = match expensive() {\n    1 -> \"one\"\n    2 -> \"two\"\n    3..5 -> \"few\"\n    \"seven\" -> \"text\"\n    6..=9 -> \"several\"\n    _ -> \"many\"\n}\nlet bound = match expensive() {\n    0 -> 0\n    n -> n + 1\n}",
+        |mut vm| {
+            vm.on_print(Box::new(move |_| *counter.borrow_mut() += 1));
+            vm
+        },
+    )
+    .unwrap();
+    assert_eq!(*calls.borrow(), 2);
+    assert_eq!(
+        vm.global("result"),
+        Some(Value::String("several".to_string()))
+    );
+    assert_eq!(vm.global("bound"), Some(Value::Number(8.0)));
+}
+
+#[test]
+fn test_match_without_matching_arm_errors() {
+    let source =
+        "func name(n) {\n    match n {\n        1 -> \"one\"\n        2 -> \"two\"\n    }\n}\n";
+    let vm = run_source(&format!("{}let two = name(2)", source)).unwrap();
+    assert_eq!(vm.global("two"), Some(Value::String("two".to_string())));
+
+    let error = run_source(&format!("{}name(\"dog\")", source))
+        .err()
+        .unwrap();
+    assert_eq!(error, "[line 2] No arm matched value \"dog\"");
+    let error = run_source(&format!("{}name([1, 3])", source))
+        .err()
+        .unwrap();
+    assert!(error.ends_with("No arm matched value [1, 3]"), "{}", error);
+}
+
+#[test]
+fn test_array_length_patterns() {
+    let vm = run_source(
+        "func describe(xs) {\n    match xs {\n        [] -> \"empty\"\n        [x] -> $\"one {x}\"\n        [x, y] -> $\"two {x + y}\"\n        [0, _, [z]] -> $\"nested {z}\"\n        _ -> \"many\"\n    }\n}\nlet a = describe([])\nlet b = describe([5])\nlet c = describe([1, 2])\nlet d = describe([0, 1, [2]])\nlet e = describe([1, 2, 3])\nlet f = describe(\"x\")\nlet g = if let [first, second] = [7, 8] {\n    first * second\n} else {\n    0\n}",
+    )
+    .unwrap();
+    for (name, expected) in [
+        ("a", "empty"),
+        ("b", "one 5"),
+        ("c", "two 3"),
+        ("d", "nested 2"),
+        ("e", "many"),
+        ("f", "many"),
+    ] {
+        assert_eq!(
+            vm.global(name),
+            Some(Value::String(expected.to_string())),
+            "{}",
+            name
         );
     }
+    assert_eq!(vm.global("g"), Some(Value::Number(56.0)));
+}
+
+#[test]
+fn test_nested_patterns() {
+    let program = parse_source("match p {\n    { pos = { x, y } } -> x\n}").unwrap();
+    let Stmt::Expr(Expr::Match { arms, .. }, _) = &program.statements[0] else {
+        panic!("expected a match, got {:?}", program.statements[0]);
+    };
+    assert_eq!(
+        arms[0].pattern,
+        Pattern::Object(vec![(
+            "pos".to_string(),
+            Pattern::Object(vec![
+                ("x".to_string(), Pattern::Binding("x".to_string())),
+                ("y".to_string(), Pattern::Binding("y".to_string())),
+            ])
+        )])
+    );
 
-    #[test]
-    fn test_error_cases() {
-        let result = run_n_file("tests/error_cases.n");
-        assert!(
-            !result.passed,
-            "Error cases test should have failed but passed: {}",
-            result.output
+    let vm = run_source(
+        "func first_name(people) {\n    match people {\n        [{ name }, _] -> name\n        _ -> \"nobody\"\n    }\n}\nfunc depth(node) {\n    match node {\n        { child = { child = { child = { value = 0 } } } } -> \"zero\"\n        { child = { child = { child = { value } } } } -> value\n        { child = [] } -> \"leaf\"\n        _ -> \"shallow\"\n    }\n}\nlet a = first_name([{ name = \"Ada\", age = 36 }, { name = \"Bo\" }])\nlet b = first_name([{ age = 1 }, 2])\nlet c = first_name([\"Ada\", 2])\nlet d = depth({ child = { child = { child = { value = 42 } } } })\nlet e = depth({ child = { child = { child = { value = 0 } } } })\nlet f = depth({ child = { child = 1 } })\nlet g = depth({ child = [] })",
+    )
+    .unwrap();
+    let string = |s: &str| Some(Value::String(s.to_string()));
+    assert_eq!(vm.global("a"), string("Ada"));
+    assert_eq!(vm.global("b"), string("nobody"));
+    assert_eq!(vm.global("c"), string("nobody"));
+    assert_eq!(vm.global("d"), Some(Value::Number(42.0)));
+    assert_eq!(vm.global("e"), string("zero"));
+    assert_eq!(vm.global("f"), string("shallow"));
+    assert_eq!(vm.global("g"), string("leaf"));
+}
+
+#[test]
+fn test_or_patterns_bind_the_same_names() {
+    let program = parse_source("match p {\n    1 | 2 -> 0\n}").unwrap();
+    let Stmt::Expr(Expr::Match { arms, .. }, _) = &program.statements[0] else {
+        panic!("expected a match, got {:?}", program.statements[0]);
+    };
+    assert_eq!(
+        arms[0].pattern,
+        Pattern::Or(vec![
+            Pattern::Literal(Expr::Number(1.0)),
+            Pattern::Literal(Expr::Number(2.0)),
+        ])
+    );
+
+    let vm = run_source(
+        "func kind(x) {\n    match x {\n        1 | 2 -> \"small\"\n        [a, 0] | [0, a] | { a } -> a\n        _ -> \"other\"\n    }\n}\nlet a = kind(2)\nlet b = kind([5, 0])\nlet c = kind([0, 7])\nlet d = kind({ a = 9 })\nlet e = kind([1, 1])",
+    )
+    .unwrap();
+    assert_eq!(vm.global("a"), Some(Value::String("small".to_string())));
+    assert_eq!(vm.global("b"), Some(Value::Number(5.0)));
+    assert_eq!(vm.global("c"), Some(Value::Number(7.0)));
+    assert_eq!(vm.global("d"), Some(Value::Number(9.0)));
+    assert_eq!(vm.global("e"), Some(Value::String("other".to_string())));
+
+    for source in [
+        "let r = match [1, 2] {\n    [a, 0] | [0, b] -> 1\n    _ -> 0\n}",
+        "let r = match [1, 2] {\n    [a, 0] | [0, _] -> 1\n    _ -> 0\n}",
+    ] {
+        let program = parse_source(source).unwrap();
+        let error = Compiler::new().compile(&program).unwrap_err();
+        assert_eq!(
+            error.message,
+            "Every alternative of a '|' pattern must bind the same names, 'a' is only bound by some"
         );
     }
+}
+
+#[test]
+fn test_strictness_turns_warnings_into_errors() {
+    let source = "func f(x) {\n    let unused = x + 1\n    let _kept = 2\n    match x {\n        n -> 1\n    }\n}\nlet r = f(1)";
+    let program = parse_source(source).unwrap();
 
-    #[test]
-    fn test_division_by_zero_detection() {
-        let result = run_n_file("tests/error_cases.n");
-        println!("{:?}", result);
-        assert!(!result.passed, "Division by zero should cause failure");
+    let mut compiler = Compiler::new();
+    compiler.compile(&program).unwrap();
+    let messages: Vec<String> = compiler.warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "Unused binding 'unused' at line 2",
+            "Unused binding 'n' at line 5"
+        ]
+    );
+
+    let options = CompileOptions {
+        unused_bindings: Severity::Error,
+        ..CompileOptions::default()
+    };
+    let error = Compiler::with_options(options)
+        .compile(&program)
+        .unwrap_err();
+    assert_eq!(error.severity, Severity::Error);
+    assert_eq!(error.to_string(), "Unused binding 'unused' at line 2");
+
+    // Each diagnostic is configured on its own
+    let shadowing = parse_source("func len(x) {\n    x\n}").unwrap();
+    let options = CompileOptions {
+        unused_bindings: Severity::Error,
+        ..CompileOptions::default()
+    };
+    assert!(Compiler::with_options(options).compile(&shadowing).is_ok());
+    let error = Compiler::with_options(CompileOptions::strict())
+        .compile(&shadowing)
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "'len' shadows the prelude builtin of the same name at line 1"
+    );
+
+    // The `else` of an `if let` whose pattern always matches isn't an unreachable arm
+    let if_let = parse_source(
+        "func f(v) {
+    if let x = v { x + 1 } else { 0 }
+}
+func g(v) {
+    if let _ = v { 1 }
+}
+let r = f(1) + g(2)",
+    )
+    .unwrap();
+    let mut compiler = Compiler::with_options(CompileOptions::strict());
+    compiler.compile(&if_let).unwrap();
+    assert!(compiler.warnings.is_empty(), "{:?}", compiler.warnings);
+}
+
+#[test]
+fn test_array_fill_and_repeat() {
+    let vm = run_source(
+        "let zeros = fill(3, 0)\nlet grid = fill(2, [1, 2])\nlet none = fill(0, \"x\")\nlet twice = repeat([1, 2], 2)\nlet empty = repeat([1], 0)",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("zeros"), "[0, 0, 0]");
+    assert_eq!(format("grid"), "[[1, 2], [1, 2]]");
+    assert_eq!(format("none"), "[]");
+    assert_eq!(format("twice"), "[1, 2, 1, 2]");
+    assert_eq!(format("empty"), "[]");
+
+    let error = run_source("fill(-1, 0)").err().unwrap();
+    assert!(
+        error.ends_with("fill count must be a non-negative integer, got -1"),
+        "{}",
+        error
+    );
+    let error = run_source("repeat([1], -2)").err().unwrap();
+    assert!(
+        error.ends_with("repeat count must be a non-negative integer, got -2"),
+        "{}",
+        error
+    );
+    assert!(run_source("fill(1.5, 0)").is_err());
+
+    // Huge counts are an error rather than an allocation failure or an endless loop
+    let error = run_source("fill(10 ** 300, 0)").err().unwrap();
+    assert!(
+        error.contains("fill would create") && error.ends_with("the limit of 16777216"),
+        "{}",
+        error
+    );
+    let error = run_source("repeat([1, 2, 3], 10000000)").err().unwrap();
+    assert!(
+        error.ends_with("repeat would create 30000000 elements, more than the limit of 16777216"),
+        "{}",
+        error
+    );
+    let vm = run_source("let nothing = repeat([], 10 ** 18)").unwrap();
+    assert_eq!(vm.format_value(&vm.global("nothing").unwrap()), "[]");
+}
+
+#[test]
+fn test_array_slice_clamps_and_counts_from_the_end() {
+    let vm = run_source(
+        "let xs = [1, 2, 3, 4, 5]\nlet middle = slice(xs, 1, 3)\nlet rest = slice(xs, 2)\nlet last_two = slice(xs, -2)\nlet trimmed = slice(xs, 1, -1)\nlet clamped = slice(xs, -10, 10)\nlet backwards = slice(xs, 3, 1)\nlet past_end = slice(xs, 7, 9)\nlet same = slice(xs, 0, len(xs)) == xs\nlet first = slice(xs, 0, 1) == [xs[0]]",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("middle"), "[2, 3]");
+    assert_eq!(format("rest"), "[3, 4, 5]");
+    assert_eq!(format("last_two"), "[4, 5]");
+    assert_eq!(format("trimmed"), "[2, 3, 4]");
+    assert_eq!(format("clamped"), "[1, 2, 3, 4, 5]");
+    assert_eq!(format("backwards"), "[]");
+    assert_eq!(format("past_end"), "[]");
+    assert_eq!(vm.global("same"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("first"), Some(Value::Boolean(true)));
+
+    let error = run_source("slice([1, 2], 0.5)").err().unwrap();
+    assert!(
+        error.ends_with("slice bounds must be integers, got 0.5"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_array_chunk_and_windows() {
+    let vm = run_source(
+        "let even = chunk([1, 2, 3, 4, 5, 6], 2)\nlet remainder = chunk([1, 2, 3, 4, 5], 2)\nlet whole = chunk([1, 2], 5)\nlet pairs = windows([1, 2, 3, 4], 2)\nlet triples = windows([1, 2, 3, 4], 3)\nlet too_short = windows([1, 2], 3)",
+    )
+    .unwrap();
+    let format = |name: &str| vm.format_value(&vm.global(name).unwrap());
+    assert_eq!(format("even"), "[[1, 2], [3, 4], [5, 6]]");
+    assert_eq!(format("remainder"), "[[1, 2], [3, 4], [5]]");
+    assert_eq!(format("whole"), "[[1, 2]]");
+    assert_eq!(format("pairs"), "[[1, 2], [2, 3], [3, 4]]");
+    assert_eq!(format("triples"), "[[1, 2, 3], [2, 3, 4]]");
+    assert_eq!(format("too_short"), "[]");
+
+    for (call, message) in [
+        (
+            "chunk([1], 0)",
+            "chunk size must be a positive integer, got 0",
+        ),
+        (
+            "windows([1], -1)",
+            "windows size must be a positive integer, got -1",
+        ),
+        (
+            "chunk([1], 1.5)",
+            "chunk size must be a positive integer, got 1.5",
+        ),
+    ] {
+        let error = run_source(call).err().unwrap();
+        assert!(error.ends_with(message), "{}", error);
     }
+}
 
-    #[test]
-    fn test_array_operations() {
-        let result = run_n_file("tests/array_operations.n");
-        assert!(
-            result.passed,
-            "Array operations test failed: {}",
-            result.output
-        );
+#[test]
+fn test_constant_field_access_compiles_to_get_field() {
+    let source = "let p = { x = 1, y = 2 }\nlet a = p.x\nlet b = p[\"x\"]\nlet key = \"y\"\nlet c = p[key]\nlet xs = [p]\nlet d = xs[0].y";
+    let program = parse_source(source).unwrap();
+    let mut compiler = Compiler::new();
+    let bytecode = compiler.compile(&program).unwrap();
+
+    let x = bytecode
+        .constants
+        .iter()
+        .position(|c| *c == Value::String("x".to_string()))
+        .unwrap();
+    let fields: Vec<usize> = bytecode
+        .instructions
+        .iter()
+        .filter_map(|instruction| match instruction {
+            Instruction::GetField(id) => Some(*id),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields[..2], [x, x]);
+    // Only the dynamic key and the array index still go through INDEX
+    let indexes = bytecode
+        .instructions
+        .iter()
+        .filter(|instruction| **instruction == Instruction::Index)
+        .count();
+    assert_eq!(indexes, 2);
+    assert!(bytecode.canonical().contains("GET_FIELD \"x\""));
+
+    let vm = run_source(source).unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(1.0)));
+    assert_eq!(vm.global("b"), Some(Value::Number(1.0)));
+    assert_eq!(vm.global("c"), Some(Value::Number(2.0)));
+    assert_eq!(vm.global("d"), Some(Value::Number(2.0)));
+
+    let error = run_source("let p = { x = 1 }\np.z").err().unwrap();
+    assert!(error.ends_with("Key 'z' not found in object"), "{}", error);
+    let error = run_source("let xs = [1]\nxs.z").err().unwrap();
+    assert!(
+        error.ends_with("Array index must be a number, got string"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_number_module() {
+    let vm = run_source(
+        "import \"Number\"\nlet whole = Number.is_int(2.0)\nlet half = Number.is_int(2.5)\nlet negative = Number.is_int(-3)\nlet parsed = Number.parse(\" 4.25 \")\nlet invalid = Number.parse(\"four\")\nlet nan = Number.parse(\"NaN\")\nlet is_nan = Number.is_nan(nan)\nlet not_nan = Number.is_nan(parsed)\nlet nan_int = Number.is_int(nan)",
+    )
+    .unwrap();
+    assert_eq!(vm.global("whole"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("half"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("negative"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("parsed"), Some(Value::Number(4.25)));
+    assert_eq!(vm.global("invalid"), Some(Value::Nil));
+    assert_eq!(vm.global("is_nan"), Some(Value::Boolean(true)));
+    assert_eq!(vm.global("not_nan"), Some(Value::Boolean(false)));
+    assert_eq!(vm.global("nan_int"), Some(Value::Boolean(false)));
+
+    let error = run_source("import \"Number\"\nNumber.parse(4)")
+        .err()
+        .unwrap();
+    assert!(error.contains("string"), "{}", error);
+    assert!(run_source("Number.is_int(1)").is_err());
+}
+
+#[test]
+fn test_arithmetic_type_errors_name_operator_and_types() {
+    for (source, expected) in [
+        ("\"a\" - 1", "Cannot apply '-' to string and number"),
+        ("true * 2", "Cannot apply '*' to boolean and number"),
+        ("1 + \"a\"", "Cannot apply '+' to number and string"),
+        ("[1] / 2", "Cannot apply '/' to array and number"),
+        ("2 ** { a = 1 }", "Cannot apply '**' to number and object"),
+        (
+            "let f = to_number(\"x\")\nf - f",
+            "Cannot apply '-' to nil and nil",
+        ),
+    ] {
+        let error = run_source(source).err().unwrap();
+        assert!(error.ends_with(expected), "{}: {}", source, error);
     }
 }
+
+#[test]
+fn test_let_type_annotations() {
+    let program = parse_source("let x: number = 5").unwrap();
+    let Stmt::Let { annotation, .. } = &program.statements[0] else {
+        panic!("expected a let, got {:?}", program.statements[0]);
+    };
+    assert_eq!(annotation.as_deref(), Some("number"));
+
+    let vm = run_source(
+        "let n: number = 5\nlet s: string = \"meow\"\nlet b: bool = 1 < 2\nlet xs: array = [1]\nlet m: map = { a = 1 }\nfunc twice(x) {\n    x * 2\n}\nlet f: function = twice\nlet none: nil = to_number(\"x\")",
+    )
+    .unwrap();
+    assert_eq!(vm.global("n"), Some(Value::Number(5.0)));
+
+    let error = run_source("let x = 1\nlet name: string = x + 1")
+        .err()
+        .unwrap();
+    assert_eq!(error, "[line 2] Expected 'name' to be string, got number");
+    let error = run_source("func f() {\n    let m: map = [1]\n    m\n}\nf()")
+        .err()
+        .unwrap();
+    assert!(
+        error.ends_with("Expected 'm' to be map, got array"),
+        "{}",
+        error
+    );
+
+    let error = parse_source("let x: integer = 1").unwrap_err();
+    assert!(
+        error.to_string().contains("Expected a type (number, string, bool, array, map, function, nil), found Identifier(\"integer\")"),
+        "{}",
+        error
+    );
+}
+
+#[test]
+fn test_return_type_annotations() {
+    let program = parse_source("func f(x) -> number {\n    x\n}").unwrap();
+    let Stmt::Func { returns, .. } = &program.statements[0] else {
+        panic!("expected a func, got {:?}", program.statements[0]);
+    };
+    assert_eq!(returns.as_deref(), Some("number"));
+    let program = parse_source("func g(x) {\n    x\n}").unwrap();
+    assert!(matches!(
+        &program.statements[0],
+        Stmt::Func { returns: None, .. }
+    ));
+
+    let source = "func half(x) -> number {\n    x / 2\n}\nfunc name(x) -> string {\n    match x {\n        1 -> \"one\"\n        _ -> x\n    }\n}\nfunc count(n) -> number {\n    match n {\n        0 -> 0\n        _ -> count(n - 1)\n    }\n}\nfunc any(x) {\n    x\n}";
+    let vm = run_source(&format!(
+        "{}\nlet a = half(5)\nlet b = name(1)\nlet c = count(3)\nlet d = any(\"free\")",
+        source
+    ))
+    .unwrap();
+    assert_eq!(vm.global("a"), Some(Value::Number(2.5)));
+    assert_eq!(vm.global("b"), Some(Value::String("one".to_string())));
+    assert_eq!(vm.global("c"), Some(Value::Number(0.0)));
+
+    let error = run_source(&format!("{}\nname(2)", source)).err().unwrap();
+    assert_eq!(
+        error,
+        "[line 4] Expected the result of 'name' to be string, got number"
+    );
+}
+
+#[test]
+fn test_parameter_type_annotations() {
+    let program = parse_source("func add(a: number, b) {\n    a + b\n}").unwrap();
+    let Stmt::Func { params, .. } = &program.statements[0] else {
+        panic!("expected a func, got {:?}", program.statements[0]);
+    };
+    assert_eq!(
+        *params,
+        vec![
+            Param::Typed("a".to_string(), "number".to_string()),
+            Param::Name("b".to_string())
+        ]
+    );
+
+    let source = "func add(a: number, b: number) {\n    a + b\n}\nfunc greet(name: string, times) {\n    name\n}";
+    let vm = run_source(&format!(
+        "{}\nlet n = add(1, 2)\nlet g = greet(\"hi\", [1])",
+        source
+    ))
+    .unwrap();
+    assert_eq!(vm.global("n"), Some(Value::Number(3.0)));
+    assert_eq!(vm.global("g"), Some(Value::String("hi".to_string())));
+
+    let error = run_source(&format!("{}\nadd(1, \"2\")", source))
+        .err()
+        .unwrap();
+    assert_eq!(
+        error,
+        "[line 1] Expected parameter 'b' of 'add' to be number, got string, in the call at line 7"
+    );
+    let error = run_source(&format!(
+        "{}\nlet plus_one = add(1)\nplus_one(true)",
+        source
+    ))
+    .err()
+    .unwrap();
+    assert!(
+        error.ends_with(
+            "Expected parameter 'b' of 'add' to be number, got boolean, in the call at line 8"
+        ),
+        "{}",
+        error
+    );
+}
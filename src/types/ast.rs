@@ -1,4 +1,7 @@
-#[derive(Debug, Clone)]
+use crate::types::diagnostic::Span;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Expr {
     Identifier(String),
     Number(f64),
@@ -21,6 +24,10 @@ pub enum Expr {
         func: Box<Expr>,
         args: Vec<Expr>,
     },
+    Member {
+        object: Box<Expr>,
+        name: String,
+    },
     Pipeline {
         left: Box<Expr>,
         right: Box<Expr>,
@@ -28,45 +35,152 @@ pub enum Expr {
     Array {
         elements: Vec<Expr>,
     },
+    Map {
+        entries: Vec<(Expr, Expr)>,
+    },
+    Index {
+        object: Box<Expr>,
+        index: Box<Expr>,
+    },
+    Match {
+        value: Box<Expr>,
+        arms: Vec<MatchArm>,
+    },
+    For {
+        name: String,
+        iterable: Box<Expr>,
+        body: Vec<Stmt>,
+    },
+    Yield {
+        value: Box<Expr>,
+    },
+    Interpolation {
+        parts: Vec<InterpPart>,
+    },
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum InterpPart {
+    Literal(String),
+    Expr(Expr, Span), // Where the embedded expression sits in the source
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Param {
+    Name(String),
+    Typed(String, String), // `a: number`, the argument must have the type
+    Fields(Vec<String>),   // `{ x, y }`, binds the fields of the object passed in
+}
+
+impl Param {
+    /// The name of the parameter's argument slot, for `{ x, y }` how it reads in source.
+    pub fn name(&self) -> String {
+        match self {
+            Param::Name(name) | Param::Typed(name, _) => name.clone(),
+            Param::Fields(fields) => format!("{{ {} }}", fields.join(", ")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub body: Vec<Stmt>,
+    pub line: usize,    // Where the pattern starts, for diagnostics about the arm
+    pub fallback: bool, // The `else` of an `if let`, never reported as unreachable
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Pattern {
+    Wildcard,
+    Literal(Expr),
+    Binding(String),
+    Range {
+        start: f64,
+        end: f64,
+        inclusive: bool, // `..=` includes `end`, `..` stops before it
+    },
+    Array(Vec<Pattern>), // `[x, y]`, matches arrays of exactly this length
+    Object(Vec<(String, Pattern)>), // `{ x, pos = p }`, matches objects holding these fields
+    Or(Vec<Pattern>),    // `1 | 2`, matches when any alternative does
+}
+
+impl Pattern {
+    /// The names the pattern binds when it matches, in source order.
+    pub fn bindings(&self) -> Vec<String> {
+        match self {
+            // Every alternative binds the same names, the compiler checks
+            Pattern::Or(alternatives) => alternatives
+                .first()
+                .map(Pattern::bindings)
+                .unwrap_or_default(),
+            Pattern::Binding(name) => vec![name.clone()],
+            Pattern::Array(elements) => elements.iter().flat_map(Pattern::bindings).collect(),
+            Pattern::Object(fields) => fields
+                .iter()
+                .flat_map(|(_, pattern)| pattern.bindings())
+                .collect(),
+            Pattern::Wildcard | Pattern::Literal(_) | Pattern::Range { .. } => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum UnaryOp {
     Neg, // Unary minus
     Not, // Logical not
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Pow,
     Eq,
     Ne,
     Lt,
     Gt,
     Le,
     Ge,
+    And,
+    Or,
 }
 
-#[derive(Debug, Clone)]
+/// Type names that annotations may use.
+pub const TYPE_NAMES: [&str; 7] = [
+    "number", "string", "bool", "array", "map", "function", "nil",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Stmt {
     Let {
         name: String,
         value: Expr,
         line: usize,
+        doc: Option<String>, // `///` comment lines right above the declaration
+        exported: bool,      // Declared with `export`, so importers may use it
+        annotation: Option<String>, // `let x: number`, checked when the value is bound
     },
     Func {
         name: String,
-        params: Vec<String>,
+        params: Vec<Param>,
         body: Vec<Stmt>,
         line: usize,
+        doc: Option<String>,
+        exported: bool,
+        returns: Option<String>, // `-> number`, checked when the function returns
+    },
+    Import {
+        modules: Vec<String>,
+        line: usize,
     },
     Expr(Expr, usize),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Program {
     pub statements: Vec<Stmt>,
+    pub doc: Option<String>, // `//!` comment lines anywhere in the file
 }
@@ -1,14 +1,22 @@
+use crate::types::diagnostic::Severity;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 #[repr(u8)]
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Instruction {
     StoreVar(usize, usize) = 0x01,
     LoadVar(usize, usize) = 0x02,
     LoadArg(usize) = 0x03,
-    Call(usize) = 0x04,
+    Call(usize, usize) = 0x04, // Function index, argument count
     Return = 0x05,
     LoadConst(usize) = 0x06,
+    LoadFunction(usize) = 0x07,
+    CallValue(usize) = 0x08, // Call the function on top of the stack with N arguments
+    CallGlobal(usize, usize) = 0x09, // Builtin index, argument count
+    TailCall(usize, usize) = 0x0A, // Call that reuses the current frame
+    MakeGenerator = 0x0B,    // Suspend a fresh call as a generator and return it
+    Yield = 0x0C,            // Suspend the running generator, handing back a value
     Add = 0x10,
     Sub = 0x11,
     Div = 0x12,
@@ -19,9 +27,18 @@ pub enum Instruction {
     Not = 0x17,
     CreateArray(usize) = 0x18, // Create array with N elements from stack
     ConcatArray = 0x19,        // Pop two arrays, concatenate, push result
+    CreateMap(usize) = 0x1A,   // Create map from N key/value pairs on the stack
+    Index = 0x1B,              // Pop index and collection, push the element
+    Pow = 0x1C,
+    GetField(usize) = 0x1D, // Look up the field named by string constant N in the map on top of the stack
+    LessEqual = 0x1E,
+    GreaterEqual = 0x1F,
     Jump(usize) = 0x20,
     JumpIfFalse(usize) = 0x21,
     JumpIfTrue(usize) = 0x22,
+    IterNext(usize) = 0x23, // Pop position and iterable, push the next element or jump when done
+    CheckType(String, String) = 0x24, // Expected type, what is checked; errors unless the top of the stack has the type
+    NoMatch = 0x25, // Pop the scrutinee of a match that no arm matched and fail naming it
     Pop = 0x30,
     Push(Value) = 0x31,
     Dup = 0x32,
@@ -35,12 +52,22 @@ pub enum VarOutput {
     GotOuterScope { index: usize, depth: usize },
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Value {
     Number(f64),
     String(String),
     Boolean(bool),
-    Function { params: Vec<String>, offset: usize },
+    Nil,
+    Function {
+        params: Vec<String>,
+        offset: usize,
+    },
+    Partial {
+        function: Box<Value>,
+        args: Vec<Value>,
+    }, // Function with leading arguments bound
+    Memoized(usize),  // Index of the cache behind a `memoize` wrapper
+    Generator(usize), // Index of a suspended generator call
     HeapPointer(usize),
 }
 
@@ -50,7 +77,9 @@ impl Value {
             Value::Number(_) => "number",
             Value::String(_) => "string",
             Value::Boolean(_) => "boolean",
-            Value::Function { .. } => "function",
+            Value::Nil => "nil",
+            Value::Function { .. } | Value::Partial { .. } | Value::Memoized(_) => "function",
+            Value::Generator(_) => "generator",
             Value::HeapPointer(_) => "heap pointer",
         }
     }
@@ -58,12 +87,7 @@ impl Value {
     pub fn type_name<'a>(&'a self, heap: &'a [HeapObject]) -> &'static str {
         match self {
             Value::HeapPointer(idx) => match heap.get(*idx) {
-                Some(HeapObject::String(_)) => "string",
-                Some(HeapObject::Number(_)) => "number",
-                Some(HeapObject::Boolean(_)) => "boolean",
-                Some(HeapObject::Null) => "null",
-                Some(HeapObject::Array(_)) => "array",
-                Some(HeapObject::Object(_)) => "object",
+                Some(object) => object.type_name(),
                 None => "unknown",
             },
             _ => self.type_name_stack(),
@@ -71,6 +95,55 @@ impl Value {
     }
 }
 
+impl HeapObject {
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            HeapObject::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            HeapObject::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            HeapObject::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[HeapObject]> {
+        match self {
+            HeapObject::Array(elements) => Some(elements),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&ObjectMap> {
+        match self {
+            HeapObject::Object(map) => Some(map),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            HeapObject::String(_) => "string",
+            HeapObject::Number(_) => "number",
+            HeapObject::Boolean(_) => "boolean",
+            HeapObject::Null => "nil",
+            HeapObject::Array(_) => "array",
+            HeapObject::Set(_) => "set",
+            HeapObject::Object(_) => "object",
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum HeapObject {
     String(String),
@@ -78,13 +151,186 @@ pub enum HeapObject {
     Boolean(bool),
     Null,
     Array(Vec<HeapObject>),
-    Object(HashMap<String, HeapObject>),
+    Set(ObjectMap), // Unique elements in insertion order, each keyed by its `MapKey`
+    Object(ObjectMap),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Canonical form of a map key. Numbers are keyed by their bit pattern so that
+/// lookups are exact and hashing is deterministic, which rules out NaN.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    String(String),
+    Number(u64),
+    Boolean(bool),
+}
+
+impl MapKey {
+    pub fn from_heap_object(object: &HeapObject) -> Result<MapKey, String> {
+        match object {
+            HeapObject::String(s) => Ok(MapKey::String(s.clone())),
+            HeapObject::Number(n) if n.is_nan() => {
+                Err("NaN cannot be used as a map key".to_string())
+            }
+            // -0 and 0 compare equal so they must share a key
+            HeapObject::Number(n) if *n == 0.0 => Ok(MapKey::Number(0.0_f64.to_bits())),
+            HeapObject::Number(n) => Ok(MapKey::Number(n.to_bits())),
+            HeapObject::Boolean(b) => Ok(MapKey::Boolean(*b)),
+            other => Err(format!(
+                "Map keys must be strings, numbers or booleans, got {}",
+                other.type_name()
+            )),
+        }
+    }
+
+    /// The value this key was made from, with -0 read back as 0.
+    pub fn to_heap_object(&self) -> HeapObject {
+        match self {
+            MapKey::String(s) => HeapObject::String(s.clone()),
+            MapKey::Number(bits) => HeapObject::Number(f64::from_bits(*bits)),
+            MapKey::Boolean(b) => HeapObject::Boolean(*b),
+        }
+    }
+}
+
+impl From<&str> for MapKey {
+    fn from(key: &str) -> Self {
+        MapKey::String(key.to_string())
+    }
+}
+
+/// Map entries kept in insertion order, with a hash index so lookups don't
+/// scan them.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectMap {
+    entries: Vec<(MapKey, HeapObject)>,
+    index: HashMap<MapKey, usize>, // Position of each key in `entries`
+}
+
+impl ObjectMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&HeapObject> {
+        self.index.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    /// Inserts `value`, replacing an existing entry for `key` in place.
+    pub fn insert(&mut self, key: MapKey, value: HeapObject) {
+        match self.index.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<HeapObject> {
+        let position = self.index.remove(key)?;
+        for i in self.index.values_mut() {
+            if *i > position {
+                *i -= 1;
+            }
+        }
+        Some(self.entries.remove(position).1)
+    }
+
+    pub fn contains_key(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// The entry at `position` in insertion order.
+    pub fn get_index(&self, position: usize) -> Option<&(MapKey, HeapObject)> {
+        self.entries.get(position)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (MapKey, HeapObject)> {
+        self.entries.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &HeapObject> {
+        self.entries.iter().map(|(_, value)| value)
+    }
+}
+
+impl PartialEq for ObjectMap {
+    // Maps are equal when they hold the same entries, whatever their order
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl FromIterator<(MapKey, HeapObject)> for ObjectMap {
+    fn from_iter<I: IntoIterator<Item = (MapKey, HeapObject)>>(iter: I) -> Self {
+        let mut map = ObjectMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
+
+/// Optimization passes the compiler runs, all of them are on by default, and
+/// how it reports code that compiles but is probably a mistake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompileOptions {
+    pub constant_folding: bool, // Evaluate arithmetic on number literals while compiling
+    pub tail_calls: bool,       // Emit TAIL_CALL for calls in tail position
+    pub unused_bindings: Severity, // Local `let`s and pattern names that are never read
+    pub shadowing: Severity,    // Definitions hiding a prelude builtin
+    pub unreachable_arms: Severity, // Match arms that can never be chosen
+}
+
+impl Default for CompileOptions {
+    fn default() -> Self {
+        Self {
+            constant_folding: true,
+            tail_calls: true,
+            unused_bindings: Severity::Warning,
+            shadowing: Severity::Warning,
+            unreachable_arms: Severity::Warning,
+        }
+    }
+}
+
+impl CompileOptions {
+    /// Every pass off, so the bytecode follows the source one to one.
+    pub fn unoptimized() -> Self {
+        Self {
+            constant_folding: false,
+            tail_calls: false,
+            ..Self::default()
+        }
+    }
+
+    /// Every optimization on, and every check an error instead of a warning.
+    pub fn strict() -> Self {
+        Self {
+            unused_bindings: Severity::Error,
+            shadowing: Severity::Error,
+            unreachable_arms: Severity::Error,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ByteCode {
     pub constants: Vec<Value>,
     pub functions: Vec<Value>,
     pub instructions: Vec<Instruction>,
     pub instruction_lines: Vec<usize>,
+    pub instruction_columns: Vec<usize>, // 0 where only the line is known
 }
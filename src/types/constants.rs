@@ -1,3 +1,6 @@
+use crate::types::token::Token;
+use std::mem::discriminant;
+
 pub const UNDERFLOW_ERROR: &str = "Stack underflow";
 pub const INVALID_HEAP_POINTER_ERROR: &str = "Invalid heap pointer";
 
@@ -17,15 +20,25 @@ pub const HEAP_SCORE_OTHER_OBJECT: usize = 32;
 // String Processing
 pub const MAX_STRING_LENGTH: usize = 1024;
 
+// Collection Building
+pub const MAX_COLLECTION_LENGTH: usize = 1 << 24; // Most elements a builtin like `range` will create
+
+// Token Caching
+pub const TOKEN_CACHE_CAPACITY: usize = 64; // Sources whose tokens are kept, the oldest goes first
+
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Precedence {
     Lowest = 0,
     Pipeline = 1,
-    Comparison = 2,
-    Term = 3,   // Addition/Subtraction
-    Factor = 4, // Multiplication/Division
-    Unary = 5,  // Unary operators and parentheses
+    Or = 2,
+    And = 3,
+    Comparison = 4,
+    Term = 5,   // Addition/Subtraction
+    Factor = 6, // Multiplication/Division
+    Unary = 7,  // Operand of unary minus and not
+    Power = 8,  // Right-associative, binds tighter than unary minus
+    Call = 9,   // Calls, indexing and member access
 }
 
 impl Precedence {
@@ -33,3 +46,59 @@ impl Precedence {
         self as u8
     }
 }
+
+/// Binding power of every infix token, the parser consults it instead of a fixed
+/// `match` so operators can be added or regrouped in one place.
+#[derive(Debug, Clone)]
+pub struct PrecedenceTable {
+    entries: Vec<(Token, Precedence)>,
+}
+
+impl Default for PrecedenceTable {
+    fn default() -> Self {
+        let mut table = Self {
+            entries: Vec::new(),
+        };
+        for token in [Token::Pipeline, Token::Update] {
+            table.set(token, Precedence::Pipeline);
+        }
+        table.set(Token::Or, Precedence::Or);
+        table.set(Token::And, Precedence::And);
+        for token in [
+            Token::Equal,
+            Token::NotEqual,
+            Token::Less,
+            Token::Greater,
+            Token::LessEqual,
+            Token::GreaterEqual,
+        ] {
+            table.set(token, Precedence::Comparison);
+        }
+        table.set(Token::Plus, Precedence::Term);
+        table.set(Token::Minus, Precedence::Term);
+        table.set(Token::Multiply, Precedence::Factor);
+        table.set(Token::Divide, Precedence::Factor);
+        table.set(Token::Power, Precedence::Power);
+        for token in [Token::LeftParen, Token::LeftBracket, Token::Dot] {
+            table.set(token, Precedence::Call);
+        }
+        table
+    }
+}
+
+impl PrecedenceTable {
+    /// Tokens are compared by kind, so `Token::Number(0.0)` stands for every number.
+    pub fn get(&self, token: &Token) -> Option<Precedence> {
+        self.entries
+            .iter()
+            .find(|(entry, _)| discriminant(entry) == discriminant(token))
+            .map(|(_, precedence)| *precedence)
+    }
+
+    /// Registers `token` as an infix operator, replacing any earlier precedence.
+    pub fn set(&mut self, token: Token, precedence: Precedence) {
+        self.entries
+            .retain(|(entry, _)| discriminant(entry) != discriminant(&token));
+        self.entries.push((token, precedence));
+    }
+}
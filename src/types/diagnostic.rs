@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Range of source characters, `end` exclusive, with the line and column
+/// (both starting at 1) where it begins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    /// Span from the start of `self` to the end of `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            end: other.end.max(self.end),
+            ..*self
+        }
+    }
+}
+
+/// An error or warning about the source. `column` is 0 and `span` empty when
+/// only the line is known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, line: usize) -> Self {
+        Self::new(Severity::Error, message, line)
+    }
+
+    pub fn warning(message: impl Into<String>, line: usize) -> Self {
+        Self::new(Severity::Warning, message, line)
+    }
+
+    fn new(severity: Severity, message: impl Into<String>, line: usize) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            line,
+            column: 0,
+            span: Span::default(),
+        }
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.line = span.line;
+        self.column = span.column;
+        self.span = span;
+        self
+    }
+
+    /// Renders the source lines covered by the span with carets under the
+    /// spanned characters, continuing the underline on every line a multi-line
+    /// span crosses. Empty when there is no span.
+    pub fn underline(&self, source: &str) -> String {
+        if self.span == Span::default() {
+            return String::new();
+        }
+        // An empty span, such as one at the end of the file, still gets a caret
+        let end = self.span.end.max(self.span.start + 1);
+        let width = source.split('\n').count().to_string().len();
+
+        let mut output = String::new();
+        let mut line_start = 0;
+        for (index, raw_line) in source.split('\n').enumerate() {
+            let line = raw_line.strip_suffix('\r').unwrap_or(raw_line);
+            let line_end = line_start + line.chars().count();
+            if line_start >= end {
+                break;
+            }
+            // The newline ending a line counts as part of it
+            if line_end >= self.span.start {
+                let from = self.span.start.max(line_start) - line_start;
+                let to = end.min(line_end).max(from + 1) - line_start;
+                output.push_str(&format!("{:>width$} | {}\n", index + 1, line));
+                output.push_str(&format!(
+                    "{:>width$} | {}{}\n",
+                    "",
+                    " ".repeat(from),
+                    "^".repeat(to - from)
+                ));
+            }
+            line_start += raw_line.chars().count() + 1;
+        }
+        output
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at line {}", self.message, self.line)
+    }
+}
@@ -1,5 +1,6 @@
 pub mod ast;
 pub mod compiler;
 pub mod constants;
+pub mod diagnostic;
 pub mod token;
 pub mod traits;
@@ -1,8 +1,12 @@
-#[derive(Debug, Clone, PartialEq)]
+use crate::types::diagnostic::Span;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Token {
     // Literals
     Identifier(String),
     String(String),
+    Interpolated(Vec<Segment>), // `$"..."`
     Number(f64),
     True,
     False,
@@ -14,17 +18,22 @@ pub enum Token {
     Fn,
     Match,
     Import,
+    Export,
     Enum,
     If,
     Else,
     Return,
     Async,
     Await,
+    For,
+    In,
+    Yield,
 
     // Operators
     Plus,
     Minus,
     Multiply,
+    Power,
     Divide,
     Modulo,
     Equal,
@@ -38,7 +47,9 @@ pub enum Token {
     Or,
     Not,
     Pipeline,    // |>
+    Bar,         // |, between the alternatives of a pattern
     Update,      // <-
+    Colon,       // :
     DoubleColon, // ::
 
     // Delimiters
@@ -50,11 +61,24 @@ pub enum Token {
     RightBracket,
     Comma,
     Dot,
-    Arrow,    // ->
-    FatArrow, // =>
-    Hash,     // #
+    DotDot,      // ..
+    DotDotEqual, // ..=
+    Arrow,       // ->
+    FatArrow,    // =>
+    Hash,        // #
 
     // Misc
+    DocComment(String), // `///` line, text after the slashes
+    ModuleDoc(String),  // `//!` line, text after the slashes
+    Error(String),
     Newline,
     Eof,
 }
+
+/// A piece of an interpolated string, either literal text or the tokens of an
+/// embedded `{...}` expression, ending in `Eof` and located in the whole source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Segment {
+    Text(String),
+    Code(Vec<Token>, Vec<Span>),
+}